@@ -0,0 +1,65 @@
+/*
+Reads a generated CSV input with `input::read_records` and with the serde
+deserialization it replaced, and prints the records per second of each.
+Run with `cargo bench`; ROWS sets the number of rows.
+*/
+use csv::{ReaderBuilder, Trim};
+use std::{
+    env,
+    fs::{self, File},
+    io::{BufWriter, Write},
+    time::Instant,
+};
+
+use transaction_processor::input::{self, InputFormat, InputRecord, RawInputRecord};
+
+fn generate(filename: &str, rows: u32) {
+    let mut out = BufWriter::new(File::create(filename).unwrap());
+    writeln!(out, "type,client,tx,amount").unwrap();
+    for tx in 1..=rows {
+        let client = tx % 1000;
+        match tx % 4 {
+            0 => writeln!(out, "withdrawal, {}, {}, {}.25", client, tx, tx % 100),
+            _ => writeln!(out, "deposit, {}, {}, {}.5", client, tx, tx % 1000),
+        }
+        .unwrap();
+    }
+    out.flush().unwrap();
+}
+
+fn records_per_sec(name: &str, rows: u32, read: impl FnOnce() -> usize) -> f64 {
+    let started = Instant::now();
+    let records = read();
+    let per_sec = f64::from(rows) / started.elapsed().as_secs_f64();
+    assert_eq!(records, rows as usize);
+    println!("{:<12} {:>12.0} records/s", name, per_sec);
+    per_sec
+}
+
+fn main() {
+    let rows = env::var("ROWS").map_or(1_000_000, |r| r.parse().unwrap());
+    let filename = env::temp_dir().join("transaction_processor_bench.csv");
+    let filename = filename.to_str().unwrap();
+    generate(filename, rows);
+
+    let serde = records_per_sec("serde", rows, || {
+        let reader = ReaderBuilder::new()
+            .trim(Trim::All)
+            .flexible(true)
+            .from_path(filename)
+            .unwrap();
+        reader
+            .into_deserialize::<RawInputRecord>()
+            .filter_map(|r| InputRecord::try_from(r.ok()?).ok())
+            .count()
+    });
+    let byte_record = records_per_sec("ByteRecord", rows, || {
+        input::read_records(filename, InputFormat::Csv)
+            .unwrap()
+            .filter(|(_, r)| r.is_ok())
+            .count()
+    });
+    println!("{:.2}x", byte_record / serde);
+
+    fs::remove_file(filename).unwrap();
+}
@@ -0,0 +1,95 @@
+use std::{fmt, ops::Deref};
+
+// the longest amount kept inline, so an Amount is no larger than 40 bytes
+const INLINE: usize = 38;
+
+/*
+The amount of a record as it was written, before it is parsed into Money when
+the record is applied. Amounts are short, so they are kept inline and reading
+a record doesn't allocate for its amount; longer text, which is usually not an
+amount at all, is kept on the heap.
+*/
+#[derive(Clone, Eq, PartialEq)]
+pub struct Amount(Repr);
+
+#[derive(Clone, Eq, PartialEq)]
+enum Repr {
+    Inline { len: u8, bytes: [u8; INLINE] },
+    Heap(Box<str>),
+}
+
+impl Amount {
+    pub fn as_str(&self) -> &str {
+        match &self.0 {
+            // only ever copied from a str, see From<&str>
+            Repr::Inline { len, bytes } => std::str::from_utf8(&bytes[..*len as usize]).unwrap(),
+            Repr::Heap(s) => s,
+        }
+    }
+}
+
+impl From<&str> for Amount {
+    fn from(s: &str) -> Self {
+        if s.len() > INLINE {
+            return Self(Repr::Heap(s.into()));
+        }
+        let mut bytes = [0; INLINE];
+        bytes[..s.len()].copy_from_slice(s.as_bytes());
+        Self(Repr::Inline {
+            len: s.len() as u8,
+            bytes,
+        })
+    }
+}
+
+impl From<String> for Amount {
+    fn from(s: String) -> Self {
+        if s.len() > INLINE {
+            return Self(Repr::Heap(s.into_boxed_str()));
+        }
+        Self::from(s.as_str())
+    }
+}
+
+impl Deref for Amount {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl fmt::Debug for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Amount, Repr, INLINE};
+
+    #[test]
+    fn inline() {
+        let amount = Amount::from("1.5");
+        assert!(matches!(amount.0, Repr::Inline { .. }));
+        assert_eq!(amount.as_str(), "1.5");
+        assert_eq!(Amount::from("1.5".to_string()), amount);
+        assert_eq!(Amount::from("").as_str(), "");
+
+        let long = "1".repeat(INLINE + 1);
+        let amount = Amount::from(long.as_str());
+        assert!(matches!(amount.0, Repr::Heap(_)));
+        assert_eq!(&*amount, long);
+        assert_eq!(Amount::from(long.clone()), amount);
+        assert_eq!(Amount::from("1".repeat(INLINE)).len(), INLINE);
+
+        assert!(std::mem::size_of::<Option<Amount>>() <= 40);
+    }
+}
@@ -1,25 +1,413 @@
-use std::env;
+use std::{collections::BTreeMap, env};
+
+use crate::{
+    cents::{Precision, Rounding, DEFAULT_SCALE, MAX_SCALE},
+    client::{ClientId, LockScope},
+    input::InputFormat,
+    lenient::DecimalSeparator,
+    output::OutputFormat,
+    record_type::RecordTypes,
+};
+
+/*
+The usage text, with the scale limits of this build.
+*/
+pub fn usage() -> String {
+    format!(
+        "\
+Usage: transaction_processor [COMMAND] [OPTIONS] [INPUT_FILENAME...]
+
+Commands:
+  process    Apply the input and write account balances (default)
+  validate   Dry run, write the records that would be rejected and why
+  report     Apply the input and write the JSON run report
+  statement  Apply the input and write every record of one client (needs --client)
+  serve      Apply records from stdin and answer with one JSON line per record
+  snapshot   Apply the input and write the engine state as JSON
+
+INPUT_FILENAME may be - to read from stdin, or a pattern like 'feeds/*.csv'.
+Several inputs are read one after the other. serve always reads stdin.
+
+Options:
+  --input-format csv|json|ndjson          Input format (default csv)
+  --merge-by COLUMN                       Merge the inputs into one stream in order of a
+                                          sequence number or timestamp column
+  --output FILENAME                       Write output to a file instead of stdout
+  --output-format csv|json|ndjson|table   Account balance format (default csv)
+  --report FILENAME                       Also write the JSON run report
+  --rejects FILENAME                      Also write rejected records as CSV
+  --review-queue FILENAME                 Also write records that broke a fraud rule as CSV
+  --load-state FILENAME                   Start from a saved engine state
+  --save-state FILENAME                   Save the engine state when done
+  --opening-balances FILENAME             Start from the accounts file of an earlier run
+  --client ID                             Client for the statement command
+  --tenant NAME                           Tenant of the client for the statement command
+  --strict                                Stop at the first rejected record
+  --lock-scope client|account             What a chargeback locks (default client)
+  --tenant-lock-scope TENANT=client|account
+                                          The lock scope of one tenant, may be repeated
+  --sub-accounts                          Write a row per sub-account and a client roll-up
+  --pending N                             Let disputes, resolves and chargebacks wait up to
+                                          N records for a transaction that didn't arrive yet
+  --policy FILENAME                       Business rules from a TOML policy file
+  --script FILENAME                       Custom rules from a Rhai script with hooks
+  --type-alias ALIAS=TYPE                 Read the record type ALIAS as TYPE, e.g.
+                                          withdraw=withdrawal, may be repeated
+  --checkpoint FILENAME                   Save a checkpoint of a long run to a file
+  --checkpoint-every N                    Records between checkpoints (default 1000000)
+  --resume                                Continue from the checkpoint of an interrupted run
+  --scale N                               Decimal places of amounts, 0 to {MAX_SCALE} (default {DEFAULT_SCALE})
+  --rounding reject|half-even|half-up|truncate
+                                          Amounts with more decimal places than the
+                                          scale are rejected (default) or rounded
+  --min-decimals N                        Decimal places always written (default the scale)
+  --lenient-amounts dot|comma             Also accept amounts like $1,000.50, +1, 1e3 or
+                                          1.234,56, with the given decimal separator
+
+Exit codes:
+  0  success, every record was applied
+  1  processing failed (unreadable input, failed to write output)
+  2  invalid command line
+  3  some records were rejected, the rest was applied and written
+  4  strict mode stopped at a rejected record, nothing was written"
+    )
+}
+
+pub const DEFAULT_CHECKPOINT_EVERY: u64 = 1_000_000;
+
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    Process,
+    Validate,
+    Report,
+    Statement,
+    Serve,
+    Snapshot,
+}
+
+impl Command {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "process" => Some(Self::Process),
+            "validate" => Some(Self::Validate),
+            "report" => Some(Self::Report),
+            "statement" => Some(Self::Statement),
+            "serve" => Some(Self::Serve),
+            "snapshot" => Some(Self::Snapshot),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    pub inputs: Vec<String>,
+    pub input_format: InputFormat,
+    pub merge_by: Option<String>,
+    pub output: Option<String>,
+    pub output_format: OutputFormat,
+    pub report: Option<String>,
+    pub rejects: Option<String>,
+    pub review_queue: Option<String>,
+    pub load_state: Option<String>,
+    pub save_state: Option<String>,
+    pub opening_balances: Option<String>,
+    pub client: Option<ClientId>,
+    pub tenant: Option<String>,
+    pub strict: bool,
+    pub policy: Option<String>,
+    pub script: Option<String>,
+    pub precision: Precision,
+    pub record_types: RecordTypes,
+    pub lenient: Option<DecimalSeparator>,
+    pub lock_scope: LockScope,
+    pub tenant_lock_scopes: BTreeMap<String, LockScope>,
+    pub sub_accounts: bool,
+    pub pending: u64,
+    pub checkpoint: Option<String>,
+    pub checkpoint_every: u64,
+    pub resume: bool,
+}
 
 /*
 Processes command line args.
-Returns input filename or an error
+Returns the parsed args or a usage error
 */
-pub fn process_args() -> Result<String, &'static str> {
+pub fn process_args() -> Result<Args, &'static str> {
     process_args_impl(env::args().collect())
 }
 
-fn process_args_impl(mut args: Vec<String>) -> Result<String, &'static str> {
-    if args.len() == 2 {
-        // filename
-        Ok(args.remove(1))
-    } else {
-        Err("Usage: cargo run -- INPUT_FILENAME")
+fn process_args_impl(args: Vec<String>) -> Result<Args, &'static str> {
+    // skip the program name
+    let mut args = args.into_iter().skip(1).peekable();
+
+    // without a command the args are for `process`
+    let command = match args.peek().and_then(|a| Command::from_name(a)) {
+        Some(command) => {
+            args.next();
+            command
+        }
+        None => Command::Process,
+    };
+
+    let mut inputs = Vec::new();
+    let mut input_format = None;
+    let mut merge_by = None;
+    let mut output = None;
+    let mut output_format = None;
+    let mut report = None;
+    let mut rejects = None;
+    let mut review_queue = None;
+    let mut load_state = None;
+    let mut save_state = None;
+    let mut opening_balances = None;
+    let mut client = None;
+    let mut tenant = None;
+    let mut strict = false;
+    let mut policy = None;
+    let mut script = None;
+    let mut scale = None;
+    let mut rounding = None;
+    let mut min_decimals = None;
+    let mut lenient = None;
+    let mut lock_scope = None;
+    let mut tenant_lock_scopes = BTreeMap::new();
+    let mut record_types = RecordTypes::default();
+    let mut sub_accounts = false;
+    let mut pending = None;
+    let mut checkpoint = None;
+    let mut checkpoint_every = None;
+    let mut resume = false;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--input-format" => set_once(&mut input_format, value(&mut args)?.parse()?)?,
+            "--merge-by" => set_once(&mut merge_by, value(&mut args)?)?,
+            "--output" => set_once(&mut output, value(&mut args)?)?,
+            "--output-format" => set_once(&mut output_format, value(&mut args)?.parse()?)?,
+            "--report" => set_once(&mut report, value(&mut args)?)?,
+            "--rejects" => set_once(&mut rejects, value(&mut args)?)?,
+            "--review-queue" => set_once(&mut review_queue, value(&mut args)?)?,
+            "--load-state" => set_once(&mut load_state, value(&mut args)?)?,
+            "--save-state" => set_once(&mut save_state, value(&mut args)?)?,
+            "--opening-balances" => set_once(&mut opening_balances, value(&mut args)?)?,
+            "--client" => {
+                let id = value(&mut args)?
+                    .parse::<ClientId>()
+                    .map_err(|_| "Invalid client id")?;
+                set_once(&mut client, id)?;
+            }
+            "--tenant" => set_once(&mut tenant, value(&mut args)?)?,
+            "--policy" => set_once(&mut policy, value(&mut args)?)?,
+            "--script" => set_once(&mut script, value(&mut args)?)?,
+            "--scale" => {
+                let n = value(&mut args)?
+                    .parse::<u32>()
+                    .map_err(|_| "Invalid scale")?;
+                set_once(&mut scale, n)?;
+            }
+            "--rounding" => set_once(&mut rounding, value(&mut args)?.parse::<Rounding>()?)?,
+            "--min-decimals" => {
+                let n = value(&mut args)?
+                    .parse::<u32>()
+                    .map_err(|_| "Invalid minimum decimals")?;
+                set_once(&mut min_decimals, n)?;
+            }
+            "--lenient-amounts" => set_once(&mut lenient, value(&mut args)?.parse()?)?,
+            "--lock-scope" => set_once(&mut lock_scope, value(&mut args)?.parse()?)?,
+            "--tenant-lock-scope" => {
+                let value = value(&mut args)?;
+                let (name, scope) = value
+                    .split_once('=')
+                    .ok_or("Expected TENANT=SCOPE for --tenant-lock-scope")?;
+                if tenant_lock_scopes
+                    .insert(name.to_string(), scope.parse()?)
+                    .is_some()
+                {
+                    return Err("Lock scope given more than once for a tenant");
+                }
+            }
+            "--type-alias" => {
+                let value = value(&mut args)?;
+                let (alias, name) = value
+                    .split_once('=')
+                    .ok_or("Expected ALIAS=TYPE for --type-alias")?;
+                record_types.alias(alias, name)?;
+            }
+            "--sub-accounts" => sub_accounts = true,
+            "--pending" => {
+                let n = value(&mut args)?
+                    .parse::<u64>()
+                    .map_err(|_| "Invalid number of pending records")?;
+                set_once(&mut pending, n)?;
+            }
+            "--checkpoint" => set_once(&mut checkpoint, value(&mut args)?)?,
+            "--checkpoint-every" => {
+                let n = value(&mut args)?
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&n| n > 0)
+                    .ok_or("Invalid number of records between checkpoints")?;
+                set_once(&mut checkpoint_every, n)?;
+            }
+            "--resume" => resume = true,
+            "--strict" => strict = true,
+            // a lone - is stdin, not an option
+            _ if arg.starts_with("--") => return Err("Unknown option"),
+            _ => inputs.push(arg),
+        }
+    }
+
+    match (&command, client) {
+        (Command::Statement, None) => return Err("Missing --client"),
+        (Command::Statement, Some(_)) | (_, None) => {}
+        (_, Some(_)) => return Err("--client is only supported by statement"),
+    }
+    if policy.is_some() && (lock_scope.is_some() || !tenant_lock_scopes.is_empty()) {
+        return Err("Set the lock scope in the policy file instead of with --lock-scope");
+    }
+    if tenant.is_some() && command != Command::Statement {
+        return Err("--tenant is only supported by statement");
+    }
+
+    if checkpoint.is_none() && (resume || checkpoint_every.is_some()) {
+        return Err("--resume and --checkpoint-every need --checkpoint");
+    }
+    if checkpoint.is_some()
+        && !matches!(
+            command,
+            Command::Process | Command::Report | Command::Snapshot
+        )
+    {
+        return Err("--checkpoint is only supported by process, report and snapshot");
+    }
+    if load_state.is_some() && opening_balances.is_some() {
+        return Err("Start from either --load-state or --opening-balances");
+    }
+    if resume && (load_state.is_some() || opening_balances.is_some()) {
+        return Err("--resume starts from the state in the checkpoint, not --load-state");
     }
+
+    if command == Command::Validate && (save_state.is_some() || rejects.is_some()) {
+        return Err("validate writes its rejects to the output and never saves state");
+    }
+
+    if command == Command::Serve {
+        if !inputs.is_empty() {
+            return Err("serve reads from stdin and takes no input filename");
+        }
+        if merge_by.is_some() {
+            return Err("--merge-by is not supported by serve");
+        }
+        inputs.push("-".to_string());
+    } else if inputs.is_empty() {
+        return Err("Missing input filename");
+    }
+    if inputs.iter().filter(|i| *i == "-").count() > 1 {
+        return Err("stdin can only be read once");
+    }
+
+    let scale = scale.unwrap_or(DEFAULT_SCALE);
+    let precision = Precision::new(
+        scale,
+        rounding.unwrap_or(Rounding::Reject),
+        min_decimals.unwrap_or(scale),
+    )?;
+
+    Ok(Args {
+        command,
+        inputs,
+        input_format: input_format.unwrap_or(InputFormat::Csv),
+        merge_by,
+        output,
+        output_format: output_format.unwrap_or(OutputFormat::Csv),
+        report,
+        rejects,
+        review_queue,
+        load_state,
+        save_state,
+        opening_balances,
+        client,
+        tenant,
+        strict,
+        policy,
+        script,
+        precision,
+        record_types,
+        lenient,
+        lock_scope: lock_scope.unwrap_or_default(),
+        tenant_lock_scopes,
+        sub_accounts,
+        pending: pending.unwrap_or(0),
+        checkpoint,
+        checkpoint_every: checkpoint_every.unwrap_or(DEFAULT_CHECKPOINT_EVERY),
+        resume,
+    })
+}
+
+fn value(args: &mut impl Iterator<Item = String>) -> Result<String, &'static str> {
+    args.next().ok_or("Missing value for option")
+}
+
+fn set_once<T>(option: &mut Option<T>, value: T) -> Result<(), &'static str> {
+    if option.is_some() {
+        return Err("Option given more than once");
+    }
+    *option = Some(value);
+    Ok(())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::process_args_impl;
+    use std::collections::BTreeMap;
+
+    use super::{process_args_impl, usage, Args, Command, DEFAULT_CHECKPOINT_EVERY};
+    use crate::{
+        cents::{Precision, Rounding, MAX_SCALE},
+        client::{client_id, LockScope},
+        input::InputFormat,
+        lenient::DecimalSeparator,
+        output::OutputFormat,
+        record_type::RecordTypes,
+    };
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|a| a.to_string()).collect()
+    }
+
+    // args for just an input filename
+    fn defaults() -> Args {
+        Args {
+            command: Command::Process,
+            inputs: vec!["filename".to_string()],
+            input_format: InputFormat::Csv,
+            merge_by: None,
+            output: None,
+            output_format: OutputFormat::Csv,
+            report: None,
+            rejects: None,
+            review_queue: None,
+            load_state: None,
+            save_state: None,
+            opening_balances: None,
+            client: None,
+            tenant: None,
+            strict: false,
+            policy: None,
+            script: None,
+            precision: Precision::default(),
+            record_types: RecordTypes::default(),
+            lenient: None,
+            lock_scope: LockScope::Client,
+            tenant_lock_scopes: BTreeMap::new(),
+            sub_accounts: false,
+            pending: 0,
+            checkpoint: None,
+            checkpoint_every: DEFAULT_CHECKPOINT_EVERY,
+            resume: false,
+        }
+    }
 
     #[test]
     fn too_few_args() {
@@ -28,6 +416,9 @@ mod tests {
 
         // 1 arg
         assert!(process_args_impl(vec!["1".to_string()]).is_err());
+
+        // command without input
+        assert!(process_args_impl(args(&["program", "process"])).is_err());
     }
 
     #[test]
@@ -35,15 +426,454 @@ mod tests {
         // 2 args
         assert_eq!(
             process_args_impl(vec!["program".to_string(), "filename".to_string()]),
-            Ok("filename".to_string()),
+            Ok(defaults()),
         );
     }
 
     #[test]
-    fn too_many_args() {
-        // 3 args
+    fn several_inputs() {
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "a.csv",
+                "feeds/*.csv",
+                "--merge-by",
+                "seq",
+            ])),
+            Ok(Args {
+                inputs: vec!["a.csv".to_string(), "feeds/*.csv".to_string()],
+                merge_by: Some("seq".to_string()),
+                ..defaults()
+            }),
+        );
+
+        // stdin only once
+        assert!(process_args_impl(args(&["program", "-", "a.csv", "-"])).is_err());
+
+        // serve only reads stdin
+        assert!(process_args_impl(args(&["program", "serve", "--merge-by", "seq"])).is_err());
+    }
+
+    #[test]
+    fn commands() {
+        for (name, command) in [
+            ("process", Command::Process),
+            ("validate", Command::Validate),
+            ("report", Command::Report),
+            ("snapshot", Command::Snapshot),
+        ] {
+            assert_eq!(
+                process_args_impl(args(&["program", name, "filename"])),
+                Ok(Args {
+                    command,
+                    ..defaults()
+                }),
+            );
+        }
+
+        assert_eq!(
+            process_args_impl(args(&["program", "statement", "filename", "--client", "7"])),
+            Ok(Args {
+                command: Command::Statement,
+                client: Some(client_id(7)),
+                ..defaults()
+            }),
+        );
+
+        assert_eq!(
+            process_args_impl(args(&["program", "serve"])),
+            Ok(Args {
+                command: Command::Serve,
+                inputs: vec!["-".to_string()],
+                ..defaults()
+            }),
+        );
+    }
+
+    #[test]
+    fn command_errors() {
+        // statement needs a client
+        assert!(process_args_impl(args(&["program", "statement", "filename"])).is_err());
         assert!(
-            process_args_impl(vec!["1".to_string(), "2".to_string(), "3".to_string(),]).is_err()
+            process_args_impl(args(&["program", "statement", "filename", "--client", ""])).is_err()
+        );
+
+        // only statement takes a client
+        assert!(process_args_impl(args(&["program", "filename", "--client", "7"])).is_err());
+
+        // serve reads stdin
+        assert!(process_args_impl(args(&["program", "serve", "filename"])).is_err());
+
+        // validate never saves anything
+        assert!(process_args_impl(args(&[
+            "program",
+            "validate",
+            "filename",
+            "--save-state",
+            "state.json"
+        ]))
+        .is_err());
+        // the policy file has the lock scopes
+        assert!(process_args_impl(args(&[
+            "program",
+            "filename",
+            "--policy",
+            "policy.toml",
+            "--lock-scope",
+            "account",
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn options() {
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "--report",
+                "report.json",
+                "--rejects",
+                "rejects.csv",
+                "--review-queue",
+                "review.csv",
+                "--load-state",
+                "in.json",
+                "filename",
+                "--save-state",
+                "out.json",
+                "--strict",
+                "--policy",
+                "policy.toml",
+                "--script",
+                "rules.rhai",
+            ])),
+            Ok(Args {
+                report: Some("report.json".to_string()),
+                rejects: Some("rejects.csv".to_string()),
+                review_queue: Some("review.csv".to_string()),
+                load_state: Some("in.json".to_string()),
+                save_state: Some("out.json".to_string()),
+                strict: true,
+                policy: Some("policy.toml".to_string()),
+                script: Some("rules.rhai".to_string()),
+                ..defaults()
+            }),
         );
+
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "filename",
+                "--opening-balances",
+                "accounts.csv"
+            ])),
+            Ok(Args {
+                opening_balances: Some("accounts.csv".to_string()),
+                ..defaults()
+            }),
+        );
+        // one starting point only
+        assert!(process_args_impl(args(&[
+            "program",
+            "filename",
+            "--opening-balances",
+            "accounts.csv",
+            "--load-state",
+            "state.json",
+        ]))
+        .is_err());
+
+        // missing value
+        assert!(process_args_impl(args(&["program", "filename", "--report"])).is_err());
+
+        // repeated option
+        assert!(process_args_impl(args(&[
+            "program", "filename", "--report", "a", "--report", "b",
+        ]))
+        .is_err());
+
+        // unknown option
+        assert!(process_args_impl(args(&["program", "filename", "--verbose"])).is_err());
+    }
+
+    #[test]
+    fn stdin() {
+        assert_eq!(
+            process_args_impl(args(&["program", "-"])),
+            Ok(Args {
+                inputs: vec!["-".to_string()],
+                ..defaults()
+            }),
+        );
+    }
+
+    #[test]
+    fn checkpoint() {
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "filename",
+                "--checkpoint",
+                "run.checkpoint",
+                "--checkpoint-every",
+                "500",
+                "--resume",
+            ])),
+            Ok(Args {
+                checkpoint: Some("run.checkpoint".to_string()),
+                checkpoint_every: 500,
+                resume: true,
+                ..defaults()
+            }),
+        );
+
+        for invalid in [
+            &["program", "filename", "--resume"][..],
+            &["program", "filename", "--checkpoint-every", "10"],
+            &[
+                "program",
+                "filename",
+                "--checkpoint",
+                "c",
+                "--checkpoint-every",
+                "0",
+            ],
+            &["program", "validate", "filename", "--checkpoint", "c"],
+            &[
+                "program",
+                "filename",
+                "--checkpoint",
+                "c",
+                "--resume",
+                "--load-state",
+                "s",
+            ],
+        ] {
+            assert!(process_args_impl(args(invalid)).is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn input_format() {
+        assert_eq!(
+            process_args_impl(args(&["program", "filename", "--input-format", "ndjson"])),
+            Ok(Args {
+                input_format: InputFormat::Ndjson,
+                ..defaults()
+            }),
+        );
+
+        assert!(
+            process_args_impl(args(&["program", "filename", "--input-format", "xml"])).is_err()
+        );
+    }
+
+    #[test]
+    fn output() {
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "filename",
+                "--output",
+                "accounts.json",
+                "--output-format",
+                "json",
+            ])),
+            Ok(Args {
+                output: Some("accounts.json".to_string()),
+                output_format: OutputFormat::Json,
+                ..defaults()
+            }),
+        );
+
+        assert!(
+            process_args_impl(args(&["program", "filename", "--output-format", "xml"])).is_err()
+        );
+    }
+
+    #[test]
+    fn precision() {
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "filename",
+                "--scale",
+                "8",
+                "--rounding",
+                "half-even",
+            ])),
+            Ok(Args {
+                precision: Precision::new(8, Rounding::HalfEven, 8).unwrap(),
+                ..defaults()
+            }),
+        );
+
+        assert_eq!(
+            process_args_impl(args(&["program", "filename", "--min-decimals", "2"])),
+            Ok(Args {
+                precision: Precision::new(4, Rounding::Reject, 2).unwrap(),
+                ..defaults()
+            }),
+        );
+
+        assert!(process_args_impl(args(&["program", "filename", "--scale", "39"])).is_err());
+
+        // the usage names the largest scale of this build, which is accepted
+        assert!(usage().contains(&format!("0 to {} ", MAX_SCALE)));
+        let max = MAX_SCALE.to_string();
+        assert!(process_args_impl(args(&["program", "filename", "--scale", &max])).is_ok());
+        let over = (MAX_SCALE + 1).to_string();
+        assert!(process_args_impl(args(&["program", "filename", "--scale", &over])).is_err());
+        assert!(process_args_impl(args(&["program", "filename", "--rounding", "up"])).is_err());
+        assert!(process_args_impl(args(&[
+            "program",
+            "filename",
+            "--scale",
+            "2",
+            "--min-decimals",
+            "4",
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn lenient() {
+        assert_eq!(
+            process_args_impl(args(&["program", "filename", "--lenient-amounts", "comma"])),
+            Ok(Args {
+                lenient: Some(DecimalSeparator::Comma),
+                ..defaults()
+            }),
+        );
+
+        assert!(
+            process_args_impl(args(&["program", "filename", "--lenient-amounts", "auto"])).is_err()
+        );
+    }
+
+    #[test]
+    fn sub_accounts() {
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "filename",
+                "--lock-scope",
+                "account",
+                "--sub-accounts",
+            ])),
+            Ok(Args {
+                lock_scope: LockScope::Account,
+                sub_accounts: true,
+                ..defaults()
+            }),
+        );
+
+        assert!(
+            process_args_impl(args(&["program", "filename", "--lock-scope", "tenant"])).is_err()
+        );
+    }
+
+    #[test]
+    fn pending() {
+        assert_eq!(
+            process_args_impl(args(&["program", "filename", "--pending", "1000"])),
+            Ok(Args {
+                pending: 1000,
+                ..defaults()
+            }),
+        );
+
+        assert!(process_args_impl(args(&["program", "filename", "--pending", "-1"])).is_err());
+    }
+
+    #[test]
+    fn tenants() {
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "statement",
+                "filename",
+                "--client",
+                "7",
+                "--tenant",
+                "acme",
+                "--tenant-lock-scope",
+                "acme=account",
+                "--tenant-lock-scope",
+                "globex=client",
+            ])),
+            Ok(Args {
+                command: Command::Statement,
+                client: Some(client_id(7)),
+                tenant: Some("acme".to_string()),
+                tenant_lock_scopes: [
+                    ("acme".to_string(), LockScope::Account),
+                    ("globex".to_string(), LockScope::Client),
+                ]
+                .into(),
+                ..defaults()
+            }),
+        );
+
+        // only statement takes a tenant
+        assert!(process_args_impl(args(&["program", "filename", "--tenant", "acme"])).is_err());
+
+        for scope in ["acme", "acme=tenant"] {
+            assert!(process_args_impl(args(&[
+                "program",
+                "filename",
+                "--tenant-lock-scope",
+                scope
+            ]))
+            .is_err());
+        }
+        assert!(process_args_impl(args(&[
+            "program",
+            "filename",
+            "--tenant-lock-scope",
+            "acme=client",
+            "--tenant-lock-scope",
+            "acme=account",
+        ]))
+        .is_err());
+    }
+
+    #[test]
+    fn type_aliases() {
+        let mut record_types = RecordTypes::default();
+        record_types
+            .alias("withdraw", "withdrawal")
+            .unwrap()
+            .alias("cb", "chargeback")
+            .unwrap();
+        assert_eq!(
+            process_args_impl(args(&[
+                "program",
+                "filename",
+                "--type-alias",
+                "withdraw=withdrawal",
+                "--type-alias",
+                "CB=Chargeback",
+            ])),
+            Ok(Args {
+                record_types,
+                ..defaults()
+            }),
+        );
+
+        for alias in ["withdraw", "withdraw=withdraw", "deposit=withdrawal"] {
+            assert!(
+                process_args_impl(args(&["program", "filename", "--type-alias", alias])).is_err()
+            );
+        }
+        assert!(process_args_impl(args(&[
+            "program",
+            "filename",
+            "--type-alias",
+            "cb=chargeback",
+            "--type-alias",
+            "cb=dispute",
+        ]))
+        .is_err());
     }
 }
@@ -1,109 +1,578 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{fmt, num::IntErrorKind, str::FromStr, sync::OnceLock};
+
+/*
+An amount of money, stored as a whole number of "cents".
+A cent is the smallest unit at the scale of the run, 1/10,000th of an amount by default, see the README.
+All arithmetic is checked, so a Money can never silently wrap.
+*/
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct Money(Cents);
+
+/*
+The integer type backing Money. An i64 at the default scale holds amounts up to
+about 922 trillion, the i128 feature raises that to about 1.7 * 10^34.
+*/
+#[cfg(not(feature = "i128"))]
 pub type Cents = i64;
+#[cfg(feature = "i128")]
+pub type Cents = i128;
+
+/*
+Number of decimal places of an amount, unless --scale says otherwise.
+*/
+pub const DEFAULT_SCALE: u32 = 4;
+
+/*
+The largest power of ten that fits into Cents.
+*/
+#[cfg(not(feature = "i128"))]
+pub const MAX_SCALE: u32 = 18;
+#[cfg(feature = "i128")]
+pub const MAX_SCALE: u32 = 38;
+
+#[cfg(not(feature = "i128"))]
+const SCALE_ERROR: &str = "Scale can be at most 18";
+#[cfg(feature = "i128")]
+const SCALE_ERROR: &str = "Scale can be at most 38";
+
+/*
+The error for any amount or balance that doesn't fit into Money.
+*/
+pub const OVERFLOW_ERROR: &str = "Amount is too large";
+
+const DECIMALS_ERROR: &str = "Amount has too many decimal places";
+
+/*
+What to do with an amount that has more decimal places than the scale.
+HalfUp rounds halves away from zero, Truncate rounds towards zero.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rounding {
+    Reject,
+    HalfEven,
+    HalfUp,
+    Truncate,
+}
+
+impl FromStr for Rounding {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "reject" => Ok(Self::Reject),
+            "half-even" => Ok(Self::HalfEven),
+            "half-up" => Ok(Self::HalfUp),
+            "truncate" => Ok(Self::Truncate),
+            _ => Err("Unknown rounding mode"),
+        }
+    }
+}
+
+/*
+How amounts are parsed and formatted.
+Amounts are written with at least `min_decimals` decimal places, and trailing
+zeros beyond that are trimmed.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Precision {
+    pub scale: u32,
+    pub rounding: Rounding,
+    pub min_decimals: u32,
+}
+
+static PRECISION: OnceLock<Precision> = OnceLock::new();
+
+impl Default for Precision {
+    fn default() -> Self {
+        Self {
+            scale: DEFAULT_SCALE,
+            rounding: Rounding::Reject,
+            min_decimals: DEFAULT_SCALE,
+        }
+    }
+}
+
+impl Precision {
+    pub fn new(scale: u32, rounding: Rounding, min_decimals: u32) -> Result<Self, &'static str> {
+        if scale > MAX_SCALE {
+            return Err(SCALE_ERROR);
+        }
+        if min_decimals > scale {
+            return Err("Minimum decimals can't be larger than the scale");
+        }
+        Ok(Self {
+            scale,
+            rounding,
+            min_decimals,
+        })
+    }
+
+    /*
+    Sets the precision for the rest of the run. Can only be done once, before
+    any amount was parsed or formatted.
+    */
+    pub fn set(self) -> Result<(), &'static str> {
+        PRECISION
+            .set(self)
+            .map_err(|_| "The precision was already set")
+    }
+
+    /*
+    The precision of the run, used by Money's FromStr, Display and serde.
+    */
+    pub fn current() -> Self {
+        PRECISION.get().copied().unwrap_or_default()
+    }
+
+    fn cents_per_amount(&self) -> u128 {
+        10_u128.pow(self.scale)
+    }
+
+    pub fn parse(&self, s: &str) -> Result<Money, &'static str> {
+        let (negative, s) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s),
+        };
+
+        let (amount, decimal) = match s.split_once('.') {
+            Some((s1, s2)) => (s1, s2),
+            None => (s, ""),
+        };
 
-const CENTS_PER_AMOUNT: Cents = 10_000;
+        // amount, a + is only accepted by the lenient parser
+        if amount.starts_with('+') {
+            return Err("Failed to parse amount");
+        }
+        let amount = amount.parse::<u128>().map_err(|e| match e.kind() {
+            IntErrorKind::PosOverflow => OVERFLOW_ERROR,
+            _ => "Failed to parse amount",
+        })?;
 
-pub fn string_to_cents(s: &str) -> Result<Cents, &'static str> {
-    let mut cents = 0;
+        // decimal
+        if !decimal.bytes().all(|b| b.is_ascii_digit()) {
+            return Err("Failed to parse decimal amount");
+        }
+        let scale = self.scale as usize;
+        let (kept, excess) = decimal.split_at(decimal.len().min(scale));
+        // at most MAX_SCALE digits, padded with zeros to the scale
+        let kept = kept
+            .bytes()
+            .fold(0_u128, |sum, b| sum * 10 + u128::from(b - b'0'))
+            * 10_u128.pow((scale - kept.len()) as u32);
 
-    let negative = s.chars().next() == Some('-');
+        let mut cents = amount
+            .checked_mul(self.cents_per_amount())
+            .and_then(|c| c.checked_add(kept))
+            .ok_or(OVERFLOW_ERROR)?;
 
-    let (amount, decimal) = match s.split_once('.') {
-        Some((s1, s2)) => (s1, s2),
-        None => (s, "0"),
-    };
+        if excess.bytes().any(|b| b != b'0') && self.round_up(cents, excess)? {
+            cents = cents.checked_add(1).ok_or(OVERFLOW_ERROR)?;
+        }
 
-    // amount
-    let amount = amount
-        .parse::<Cents>()
-        .map_err(|_| "Failed to parse amount")?;
-    cents += amount * CENTS_PER_AMOUNT;
+        // Cents::MIN has no positive counterpart
+        let cents = if negative {
+            0_i128.checked_sub_unsigned(cents)
+        } else {
+            i128::try_from(cents).ok()
+        }
+        .ok_or(OVERFLOW_ERROR)?;
+        Cents::try_from(cents)
+            .map(Money)
+            .map_err(|_| OVERFLOW_ERROR)
+    }
 
-    // decimal
-    if decimal.len() > 4 {
-        return Err("Amount has too many decimal places");
+    /*
+    Whether the magnitude `cents` has to be rounded up because of the non-zero
+    `excess` decimal places.
+    */
+    fn round_up(&self, cents: u128, excess: &str) -> Result<bool, &'static str> {
+        let half = excess.trim_end_matches('0').cmp("5");
+        Ok(match self.rounding {
+            Rounding::Reject => return Err(DECIMALS_ERROR),
+            Rounding::Truncate => false,
+            Rounding::HalfUp => half.is_ge(),
+            Rounding::HalfEven => half.is_gt() || (half.is_eq() && cents % 2 == 1),
+        })
     }
-    let decimal = format!("{:0<4}", decimal);
-    let mut decimal = decimal
-        .parse::<Cents>()
-        .map_err(|_| "Failed to parse decimal amount")?;
-    if negative {
-        decimal *= -1;
+
+    pub fn format(&self, money: Money) -> String {
+        let sign = if money.0 >= 0 { "" } else { "-" };
+        // Cents::MIN has no positive counterpart
+        #[allow(clippy::useless_conversion)] // already a u128 with the i128 feature
+        let c = u128::from(money.0.unsigned_abs());
+
+        let amount = c / self.cents_per_amount();
+        let decimal = c % self.cents_per_amount();
+
+        let decimal_fmt = format!("{:0>width$}", decimal, width = self.scale as usize);
+        let trimmed = decimal_fmt.trim_end_matches('0');
+        let decimal_fmt = &decimal_fmt[..trimmed.len().max(self.min_decimals as usize)];
+
+        if decimal_fmt.is_empty() {
+            format!("{}{}", sign, amount)
+        } else {
+            format!("{}{}.{}", sign, amount, decimal_fmt)
+        }
+    }
+}
+
+impl Money {
+    pub const ZERO: Self = Self(0);
+    pub const MAX: Self = Self(Cents::MAX);
+    pub const MIN: Self = Self(Cents::MIN);
+
+    /*
+    From a number of cents, e.g. 1_2345 is 1.2345 at the default scale.
+    */
+    pub const fn from_cents(cents: Cents) -> Self {
+        Self(cents)
+    }
+
+    /*
+    From a whole amount, e.g. 12 is 12.0000.
+    */
+    pub fn from_units(units: Cents) -> Result<Self, &'static str> {
+        Self::from_scaled(units, 0)
     }
-    cents += decimal;
 
-    Ok(cents)
+    /*
+    From a value with `scale` decimal places, e.g. 123 at scale 2 is 1.23.
+    Fails if the value has more significant decimal places than Money can hold.
+    */
+    pub fn from_scaled(value: Cents, scale: u32) -> Result<Self, &'static str> {
+        let run_scale = Precision::current().scale;
+        if scale <= run_scale {
+            let factor = (10 as Cents).pow(run_scale - scale);
+            value.checked_mul(factor).map(Self).ok_or(OVERFLOW_ERROR)
+        } else {
+            let divisor = (10 as Cents)
+                .checked_pow(scale - run_scale)
+                .ok_or(DECIMALS_ERROR)?;
+            if value % divisor != 0 {
+                return Err(DECIMALS_ERROR);
+            }
+            Ok(Self(value / divisor))
+        }
+    }
+
+    pub fn as_cents(self) -> Cents {
+        self.0
+    }
+
+    pub fn is_negative(self) -> bool {
+        self.0 < 0
+    }
+
+    pub fn checked_add(self, other: Self) -> Result<Self, &'static str> {
+        self.0.checked_add(other.0).map(Self).ok_or(OVERFLOW_ERROR)
+    }
+
+    pub fn checked_sub(self, other: Self) -> Result<Self, &'static str> {
+        self.0.checked_sub(other.0).map(Self).ok_or(OVERFLOW_ERROR)
+    }
 }
 
-pub fn cents_to_string(c: Cents) -> String {
-    let sign = if c >= 0 { "" } else { "-" };
-    let c = c.abs();
+impl FromStr for Money {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Precision::current().parse(s)
+    }
+}
 
-    let amount = c / CENTS_PER_AMOUNT;
-    let decimal = c % CENTS_PER_AMOUNT;
+impl fmt::Display for Money {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&Precision::current().format(*self))
+    }
+}
 
-    let decimal_fmt = format!("{:0>4}", decimal);
-    let decimal_fmt = decimal_fmt.trim_end_matches('0');
+/*
+Serialized as a string in the same format as Display, so it can't lose precision.
+*/
+impl Serialize for Money {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
 
-    if decimal > 0 {
-        format!("{}{}.{}", sign, amount, decimal_fmt)
-    } else {
-        format!("{}{}", sign, amount)
+impl<'de> Deserialize<'de> for Money {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
     }
 }
 
 #[cfg(test)]
+#[allow(clippy::zero_prefixed_literal)]
 mod tests {
-    mod string_to_cents {
-        use crate::cents::string_to_cents;
+    mod from_str {
+        use crate::cents::{Cents, Money, OVERFLOW_ERROR};
+
+        fn parse(s: &str) -> Result<Money, &'static str> {
+            s.parse()
+        }
+
+        fn cents(c: Cents) -> Result<Money, &'static str> {
+            Ok(Money::from_cents(c))
+        }
 
         #[test]
         fn invalid() {
-            assert!(string_to_cents("abc").is_err());
-            assert!(string_to_cents("1.1.1").is_err());
-            assert!(string_to_cents("1.a").is_err());
-            assert!(string_to_cents("0.12345").is_err());
+            assert!(parse("abc").is_err());
+            assert!(parse("1.1.1").is_err());
+            assert!(parse("1.a").is_err());
+            assert!(parse("0.12345").is_err());
+            assert!(parse("+1").is_err());
+            assert!(parse("1,000").is_err());
         }
 
         #[test]
         fn whole_amounts() {
-            assert_eq!(string_to_cents("-123"), Ok(-123_0000));
-            assert_eq!(string_to_cents("0"), Ok(0));
-            assert_eq!(string_to_cents("1"), Ok(1_0000));
-            assert_eq!(string_to_cents("10"), Ok(10_0000));
-            assert_eq!(string_to_cents("123"), Ok(123_0000));
+            assert_eq!(parse("-123"), cents(-123_0000));
+            assert_eq!(parse("0"), cents(0));
+            assert_eq!(parse("1"), cents(1_0000));
+            assert_eq!(parse("10"), cents(10_0000));
+            assert_eq!(parse("123"), cents(123_0000));
         }
 
         #[test]
         fn with_decimals() {
-            assert_eq!(string_to_cents("-1.01"), Ok(-1_0100));
-            assert_eq!(string_to_cents("-0.1"), Ok(-0_1000));
-            assert_eq!(string_to_cents("0.0123"), Ok(0_0123));
-            assert_eq!(string_to_cents("0.1"), Ok(0_1000));
-            assert_eq!(string_to_cents("0.1234"), Ok(0_1234));
-            assert_eq!(string_to_cents("1.01"), Ok(1_0100));
+            assert_eq!(parse("-1.01"), cents(-1_0100));
+            assert_eq!(parse("-0.1"), cents(-0_1000));
+            assert_eq!(parse("0.0123"), cents(0_0123));
+            assert_eq!(parse("0.1"), cents(0_1000));
+            assert_eq!(parse("0.1234"), cents(0_1234));
+            assert_eq!(parse("1.01"), cents(1_0100));
+        }
+
+        #[test]
+        #[cfg(not(feature = "i128"))]
+        fn overflow() {
+            // largest and smallest amounts that fit
+            assert_eq!(parse("922337203685477.5807"), Ok(Money::MAX));
+            assert_eq!(parse("-922337203685477.5808"), Ok(Money::MIN));
+
+            // too large after scaling
+            assert_eq!(parse("922337203685478"), Err(OVERFLOW_ERROR));
+            // too large after adding the decimals
+            assert_eq!(parse("922337203685477.5808"), Err(OVERFLOW_ERROR));
+            assert_eq!(parse("-922337203685477.5809"), Err(OVERFLOW_ERROR));
+            // too large to even parse
+            assert_eq!(parse("99999999999999999999"), Err(OVERFLOW_ERROR));
+        }
+
+        #[test]
+        #[cfg(feature = "i128")]
+        fn overflow() {
+            // largest and smallest amounts that fit
+            assert_eq!(
+                parse("17014118346046923173168730371588410.5727"),
+                Ok(Money::MAX)
+            );
+            assert_eq!(
+                parse("-17014118346046923173168730371588410.5728"),
+                Ok(Money::MIN)
+            );
+
+            assert_eq!(
+                parse("17014118346046923173168730371588410.5728"),
+                Err(OVERFLOW_ERROR)
+            );
+            // fits into an i64 but no longer overflows
+            assert!(parse("922337203685478").is_ok());
+            assert_eq!(
+                parse("999999999999999999999999999999999999999"),
+                Err(OVERFLOW_ERROR)
+            );
         }
     }
 
-    mod cents_to_string {
-        use super::super::cents_to_string;
+    mod display {
+        use crate::cents::{Cents, Money};
+
+        fn format(c: Cents) -> String {
+            Money::from_cents(c).to_string()
+        }
 
         #[test]
         fn whole_amounts() {
-            assert_eq!(cents_to_string(-123_0000), "-123");
-            assert_eq!(cents_to_string(0), "0");
-            assert_eq!(cents_to_string(1_0000), "1");
-            assert_eq!(cents_to_string(10_0000), "10");
-            assert_eq!(cents_to_string(123_0000), "123");
+            assert_eq!(format(-123_0000), "-123.0000");
+            assert_eq!(format(0), "0.0000");
+            assert_eq!(format(1_0000), "1.0000");
+            assert_eq!(format(10_0000), "10.0000");
+            assert_eq!(format(123_0000), "123.0000");
         }
 
         #[test]
         fn with_decimals() {
-            assert_eq!(cents_to_string(-1_0100), "-1.01");
-            assert_eq!(cents_to_string(-0_1000), "-0.1");
-            assert_eq!(cents_to_string(0_0123), "0.0123");
-            assert_eq!(cents_to_string(0_1000), "0.1");
-            assert_eq!(cents_to_string(0_1234), "0.1234");
-            assert_eq!(cents_to_string(1_0100), "1.01");
+            assert_eq!(format(-1_0100), "-1.0100");
+            assert_eq!(format(-0_1000), "-0.1000");
+            assert_eq!(format(0_0123), "0.0123");
+            assert_eq!(format(0_1000), "0.1000");
+            assert_eq!(format(0_1234), "0.1234");
+            assert_eq!(format(1_0100), "1.0100");
+        }
+
+        #[test]
+        #[cfg(not(feature = "i128"))]
+        fn extremes() {
+            assert_eq!(Money::MAX.to_string(), "922337203685477.5807");
+            assert_eq!(Money::MIN.to_string(), "-922337203685477.5808");
+        }
+
+        #[test]
+        #[cfg(feature = "i128")]
+        fn extremes() {
+            assert_eq!(
+                Money::MAX.to_string(),
+                "17014118346046923173168730371588410.5727"
+            );
+            assert_eq!(
+                Money::MIN.to_string(),
+                "-17014118346046923173168730371588410.5728"
+            );
+        }
+    }
+
+    mod constructors {
+        use crate::cents::{Cents, Money, OVERFLOW_ERROR};
+
+        #[test]
+        fn from_units() {
+            assert_eq!(Money::from_units(12), Ok(Money::from_cents(12_0000)));
+            assert_eq!(Money::from_units(-1), Ok(Money::from_cents(-1_0000)));
+            assert_eq!(Money::from_units(Cents::MAX), Err(OVERFLOW_ERROR));
+        }
+
+        #[test]
+        fn from_scaled() {
+            assert_eq!(Money::from_scaled(123, 2), Ok(Money::from_cents(1_2300)));
+            assert_eq!(Money::from_scaled(1_2345, 4), Ok(Money::from_cents(1_2345)));
+            // extra decimal places are fine as long as they are zero
+            assert_eq!(
+                Money::from_scaled(1_234_500, 6),
+                Ok(Money::from_cents(1_2345)),
+            );
+            assert!(Money::from_scaled(1_234_567, 6).is_err());
+            assert!(Money::from_scaled(1, 40).is_err());
+        }
+    }
+
+    mod arithmetic {
+        use crate::cents::{Money, OVERFLOW_ERROR};
+
+        #[test]
+        fn checked() {
+            let one = Money::from_cents(1);
+
+            assert_eq!(one.checked_add(one), Ok(Money::from_cents(2)));
+            assert_eq!(one.checked_sub(one), Ok(Money::ZERO));
+            assert_eq!(Money::MAX.checked_add(one), Err(OVERFLOW_ERROR));
+            assert_eq!(Money::MIN.checked_sub(one), Err(OVERFLOW_ERROR));
+        }
+    }
+
+    mod precision {
+        use crate::cents::{Cents, Money, Precision, Rounding, MAX_SCALE, OVERFLOW_ERROR};
+
+        fn precision(scale: u32, rounding: Rounding) -> Precision {
+            Precision::new(scale, rounding, 0).unwrap()
+        }
+
+        fn cents(c: Cents) -> Result<Money, &'static str> {
+            Ok(Money::from_cents(c))
+        }
+
+        #[test]
+        fn scale() {
+            let p = precision(8, Rounding::Reject);
+            assert_eq!(p.parse("1.12345678"), cents(1_1234_5678));
+            assert_eq!(p.parse("-0.00000001"), cents(-1));
+            assert_eq!(p.format(Money::from_cents(1_1234_5678)), "1.12345678");
+
+            let p = precision(0, Rounding::Reject);
+            assert_eq!(p.parse("12"), cents(12));
+            assert_eq!(p.parse("12.000"), cents(12));
+            assert!(p.parse("12.5").is_err());
+            assert_eq!(p.format(Money::from_cents(12)), "12");
+
+            // the largest amount at the largest scale is below 10
+            let p = precision(MAX_SCALE, Rounding::Reject);
+            assert_eq!(p.parse("10"), Err(OVERFLOW_ERROR));
+        }
+
+        #[test]
+        fn invalid() {
+            assert!(Precision::new(MAX_SCALE + 1, Rounding::Reject, 0).is_err());
+            assert!(Precision::new(2, Rounding::Reject, 3).is_err());
+            assert!(precision(2, Rounding::Truncate).parse("1.2x").is_err());
+            assert!(precision(2, Rounding::Truncate).parse("--1").is_err());
+        }
+
+        #[test]
+        fn rounding() {
+            let cases = [
+                // input, half-even, half-up, truncate
+                ("1.125", 1_12, 1_13, 1_12),
+                ("1.135", 1_14, 1_14, 1_13),
+                ("1.1251", 1_13, 1_13, 1_12),
+                ("1.1249", 1_12, 1_12, 1_12),
+                ("-1.125", -1_12, -1_13, -1_12),
+                ("-1.135", -1_14, -1_14, -1_13),
+                ("1.1200", 1_12, 1_12, 1_12),
+            ];
+
+            for (input, half_even, half_up, truncate) in cases {
+                assert_eq!(
+                    precision(2, Rounding::HalfEven).parse(input),
+                    cents(half_even)
+                );
+                assert_eq!(precision(2, Rounding::HalfUp).parse(input), cents(half_up));
+                assert_eq!(
+                    precision(2, Rounding::Truncate).parse(input),
+                    cents(truncate)
+                );
+            }
+
+            assert!(precision(2, Rounding::Reject).parse("1.125").is_err());
+            assert_eq!(precision(2, Rounding::Reject).parse("1.1200"), cents(1_12));
+        }
+
+        #[test]
+        fn min_decimals() {
+            let money = Money::from_cents(1_5000);
+
+            let format = |min_decimals| {
+                Precision::new(4, Rounding::Reject, min_decimals)
+                    .unwrap()
+                    .format(money)
+            };
+
+            assert_eq!(format(0), "1.5");
+            assert_eq!(format(2), "1.50");
+            assert_eq!(format(4), "1.5000");
+            assert_eq!(
+                Precision::new(4, Rounding::Reject, 2)
+                    .unwrap()
+                    .format(Money::from_cents(1_2345)),
+                "1.2345"
+            );
+        }
+    }
+
+    mod serde {
+        use crate::cents::Money;
+
+        #[test]
+        fn round_trip() {
+            let money = Money::from_cents(-1_0100);
+
+            let json = serde_json::to_string(&money).unwrap();
+            assert_eq!(json, r#""-1.0100""#);
+            assert_eq!(serde_json::from_str::<Money>(&json).unwrap(), money);
+
+            assert!(serde_json::from_str::<Money>(r#""1.00001""#).is_err());
         }
     }
 }
@@ -0,0 +1,396 @@
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    fs::{self, File},
+    io::{self, BufReader, Read},
+};
+
+use crate::{
+    fraud::FraudHistory,
+    input::{self, ApplyOptions, InputFormat, STRICT_STOP},
+    output::AtomicFile,
+    rejects::Rejects,
+    report::{Outcome, Report},
+    review::ReviewQueue,
+    sources::Location,
+    state::State,
+};
+
+/*
+How often a long run saves a checkpoint, and whether it resumes from one.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Checkpoints {
+    pub filename: String,
+    // records between checkpoints
+    pub every: u64,
+    pub resume: bool,
+}
+
+/*
+The engine state and the report after a prefix of an input file, and where the
+rest of the file starts. The size and the hash of the prefix tell whether the
+input file is still the one the checkpoint was saved for. The fraud history
+isn't part of a state file, so it is saved separately. The rejects file and
+review queue are written in place, the checkpoint has how long they were.
+*/
+#[derive(Deserialize, Serialize)]
+struct Checkpoint<'a> {
+    input: Cow<'a, str>,
+    size: u64,
+    // where the next record starts, and the line before it
+    offset: u64,
+    line: u32,
+    // SHA-256 of the input up to the offset
+    hash: String,
+    state: Cow<'a, State>,
+    fraud: Cow<'a, FraudHistory>,
+    report: Cow<'a, Report>,
+    rejects: Option<u64>,
+    review: Option<u64>,
+}
+
+impl Checkpoint<'_> {
+    fn load(filename: &str) -> Result<Self, &'static str> {
+        let file = File::open(filename).map_err(|_| "Failed to open checkpoint file")?;
+        let checkpoint: Self = serde_json::from_reader(BufReader::new(file))
+            .map_err(|_| "Failed to parse checkpoint file")?;
+        checkpoint.state.check_scale()?;
+        Ok(checkpoint)
+    }
+
+    fn save(&self, filename: &str) -> Result<(), &'static str> {
+        // the previous checkpoint stays until this one is complete
+        let mut file =
+            AtomicFile::create(filename).map_err(|_| "Failed to create checkpoint file")?;
+        serde_json::to_writer(&mut file, self).map_err(|_| "Failed to write checkpoint file")?;
+        file.commit().map_err(|_| "Failed to write checkpoint file")
+    }
+}
+
+/*
+The hash of an input file up to some offset. Every byte is read a second time,
+separately from the records, but only once however many checkpoints there are.
+*/
+struct PrefixHash {
+    file: File,
+    hasher: Sha256,
+    offset: u64,
+}
+
+impl PrefixHash {
+    fn open(filename: &str) -> Result<Self, &'static str> {
+        Ok(Self {
+            file: File::open(filename).map_err(|_| "Failed to open input file")?,
+            hasher: Sha256::new(),
+            offset: 0,
+        })
+    }
+
+    fn advance(&mut self, offset: u64) -> Result<String, &'static str> {
+        let mut prefix = (&mut self.file).take(offset - self.offset);
+        io::copy(&mut prefix, &mut self.hasher).map_err(|_| "Failed to read input file")?;
+        self.offset = offset;
+
+        let hash = self.hasher.clone().finalize();
+        Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+    }
+}
+
+/*
+Processes every record in a single input file like `input::process_input_file`,
+saving a checkpoint every `checkpoints.every` records. A checkpoint is only
+saved while no record is pending, so it never has to hold the pending buffer.
+With `checkpoints.resume` the state, the fraud history and the report come
+from the checkpoint, the rejects file and review queue are appended to, and
+processing continues after the last record it covers.
+Returns the rejects file and review queue, which are opened here.
+*/
+#[allow(clippy::too_many_arguments)]
+pub fn process_input_file(
+    filename: &str,
+    format: InputFormat,
+    checkpoints: &Checkpoints,
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: Option<&str>,
+    review: Option<&str>,
+    strict: bool,
+) -> Result<(Rejects, ReviewQueue), &'static str> {
+    let size = fs::metadata(filename)
+        .map_err(|_| "Failed to open input file")?
+        .len();
+    let mut prefix = PrefixHash::open(filename)?;
+
+    let (start, rejects_at, review_at) = if checkpoints.resume {
+        let checkpoint = Checkpoint::load(&checkpoints.filename)?;
+        if checkpoint.input != filename {
+            return Err("The checkpoint was saved for a different input file");
+        }
+        if checkpoint.size != size || prefix.advance(checkpoint.offset)? != checkpoint.hash {
+            return Err("The input file changed since the checkpoint was saved");
+        }
+        if checkpoint.rejects.is_some() != rejects.is_some()
+            || checkpoint.review.is_some() != review.is_some()
+        {
+            return Err("The checkpoint was saved with a different rejects file or review queue");
+        }
+        *state = checkpoint.state.into_owned();
+        state.fraud = checkpoint.fraud.into_owned();
+        report.resume(checkpoint.report.into_owned());
+        (
+            Some((checkpoint.offset, checkpoint.line)),
+            checkpoint.rejects,
+            checkpoint.review,
+        )
+    } else {
+        (None, None, None)
+    };
+    let mut rejects = Rejects::open_checkpointed(rejects, rejects_at)?;
+    let mut review = ReviewQueue::open_checkpointed(review, review_at)?;
+
+    let mut since_checkpoint = 0;
+    for (line, result, offset) in input::read_records_from(filename, format, start)? {
+        let location = Location::line(line);
+        // the report also has the records applied from the pending buffer
+        let _ = input::apply_record(
+            &location,
+            &result,
+            options,
+            state,
+            report,
+            &mut rejects,
+            &mut review,
+        )?;
+        if strict && report.outcome() == Outcome::Rejections {
+            return Err(STRICT_STOP);
+        }
+
+        since_checkpoint += 1;
+        if since_checkpoint >= checkpoints.every && state.pending.is_empty() {
+            let checkpoint = Checkpoint {
+                input: Cow::Borrowed(filename),
+                size,
+                offset,
+                line,
+                hash: prefix.advance(offset)?,
+                state: Cow::Borrowed(state),
+                fraud: Cow::Borrowed(&state.fraud),
+                report: Cow::Borrowed(report),
+                rejects: rejects.checkpoint()?,
+                review: review.checkpoint()?,
+            };
+            checkpoint.save(&checkpoints.filename)?;
+            since_checkpoint = 0;
+        }
+    }
+
+    input::finish_input(options, state, report, &mut rejects, &mut review)?;
+    if strict && report.outcome() == Outcome::Rejections {
+        return Err(STRICT_STOP);
+    }
+
+    Ok((rejects, review))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{process_input_file, Checkpoints};
+    use crate::{
+        input::{ApplyOptions, InputFormat},
+        output,
+        policy::Policy,
+        report::{Outcome, Report},
+        state::{State, DEFAULT_TENANT},
+    };
+
+    fn accounts(state: &State) -> String {
+        let mut out = Vec::new();
+        let clients = &state.ledger(DEFAULT_TENANT).unwrap().clients;
+        output::write_accounts(
+            clients,
+            false,
+            output::account_writer(output::OutputFormat::Csv, &mut out).as_mut(),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    // the report without the duration
+    fn counts(report: &Report) -> serde_json::Value {
+        let mut json = serde_json::to_value(report).unwrap();
+        json.as_object_mut().unwrap().remove("duration_secs");
+        json
+    }
+
+    fn run(
+        input: &str,
+        format: InputFormat,
+        checkpoints: &Checkpoints,
+        rejects: Option<&str>,
+    ) -> Result<(State, Report), &'static str> {
+        run_with(
+            input,
+            format,
+            checkpoints,
+            rejects,
+            &ApplyOptions::default(),
+        )
+    }
+
+    fn run_with(
+        input: &str,
+        format: InputFormat,
+        checkpoints: &Checkpoints,
+        rejects: Option<&str>,
+        options: &ApplyOptions,
+    ) -> Result<(State, Report), &'static str> {
+        let mut state = State::new();
+        let mut report = Report::new();
+        let (rejects, review) = process_input_file(
+            input,
+            format,
+            checkpoints,
+            options,
+            &mut state,
+            &mut report,
+            rejects,
+            None,
+            false,
+        )?;
+        rejects.finish()?;
+        review.finish()?;
+        Ok((state, report))
+    }
+
+    #[test]
+    fn resume() {
+        for (name, format) in [
+            ("checkpoint.csv", InputFormat::Csv),
+            ("checkpoint.ndjson", InputFormat::Ndjson),
+        ] {
+            let input = env::temp_dir().join(format!("transaction_processor_resume_{}", name));
+            let input = input.to_str().unwrap();
+            fs::copy(format!("test_data/{}", name), input).unwrap();
+            let rejects = format!("{}.rejects", input);
+
+            // checkpoints after records 2 and 4, the second record is rejected
+            let mut checkpoints = Checkpoints {
+                filename: format!("{}.checkpoint", input),
+                every: 2,
+                resume: false,
+            };
+            let (state, report) = run(input, format, &checkpoints, Some(&rejects)).unwrap();
+            assert_eq!(report.outcome(), Outcome::Rejections);
+            let full = (
+                accounts(&state),
+                counts(&report),
+                fs::read_to_string(&rejects).unwrap(),
+            );
+            assert_eq!(full.2.lines().count(), 2);
+
+            // only the last record is applied again, so nothing is a duplicate,
+            // and the rejection from before the checkpoint is kept
+            checkpoints.resume = true;
+            let (state, report) = run(input, format, &checkpoints, Some(&rejects)).unwrap();
+            assert_eq!(report.outcome(), Outcome::Rejections);
+            let resumed = (
+                accounts(&state),
+                counts(&report),
+                fs::read_to_string(&rejects).unwrap(),
+            );
+            assert_eq!(resumed, full);
+
+            // the checkpoint has a rejects file
+            assert!(run(input, format, &checkpoints, None).is_err());
+
+            // a different input file
+            let other = Checkpoints {
+                filename: checkpoints.filename.clone(),
+                every: 2,
+                resume: true,
+            };
+            assert!(run(
+                &format!("test_data/{}", name),
+                format,
+                &other,
+                Some(&rejects)
+            )
+            .is_err());
+
+            // the input changed before the checkpoint offset
+            let changed = fs::read_to_string(input).unwrap().replacen("2.0", "2.5", 1);
+            fs::write(input, changed).unwrap();
+            assert!(run(input, format, &checkpoints, Some(&rejects)).is_err());
+
+            fs::remove_file(input).unwrap();
+            fs::remove_file(&rejects).unwrap();
+            fs::remove_file(&checkpoints.filename).unwrap();
+        }
+    }
+
+    #[test]
+    fn fraud_history() {
+        let input = env::temp_dir().join("transaction_processor_resume_fraud.csv");
+        let input = input.to_str().unwrap();
+        fs::write(
+            input,
+            "type,client,tx,amount\n\
+             deposit,1,1,10.0\n\
+             withdrawal,1,2,1.0\n\
+             withdrawal,1,3,1.0\n",
+        )
+        .unwrap();
+        let options = ApplyOptions {
+            policy: Policy::parse(
+                "[[fraud]]\nrule = \"velocity\"\nmax = 1\nwithin_records = 10\naction = \"reject\"",
+            )
+            .unwrap(),
+            ..ApplyOptions::default()
+        };
+
+        // a checkpoint after the first withdrawal, the second one breaks the rule
+        let mut checkpoints = Checkpoints {
+            filename: format!("{}.checkpoint", input),
+            every: 2,
+            resume: false,
+        };
+        let (state, report) =
+            run_with(input, InputFormat::Csv, &checkpoints, None, &options).unwrap();
+        let full = (accounts(&state), counts(&report));
+        assert_eq!(full.1["fraud_hits"]["velocity"], 1);
+
+        // the first withdrawal still counts after resuming
+        checkpoints.resume = true;
+        let (state, report) =
+            run_with(input, InputFormat::Csv, &checkpoints, None, &options).unwrap();
+        assert_eq!((accounts(&state), counts(&report)), full);
+
+        fs::remove_file(input).unwrap();
+        fs::remove_file(&checkpoints.filename).unwrap();
+    }
+
+    #[test]
+    fn json() {
+        let checkpoints = Checkpoints {
+            filename: env::temp_dir()
+                .join("transaction_processor_checkpoint_json")
+                .to_str()
+                .unwrap()
+                .to_string(),
+            every: 2,
+            resume: false,
+        };
+        assert!(run(
+            "test_data/transactions.json",
+            InputFormat::Json,
+            &checkpoints,
+            None
+        )
+        .is_err());
+    }
+}
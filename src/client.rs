@@ -1,56 +1,366 @@
-use std::collections::HashMap;
-
-use crate::cents::Cents;
-
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    str::FromStr,
+};
+
+use crate::cents::Money;
+
+/*
+Client ids are u16 unless a wider id feature is enabled, see the README.
+The widest enabled feature wins.
+*/
+#[cfg(feature = "string-ids")]
+pub type ClientId = crate::ids::InternedId;
+#[cfg(all(feature = "client-id-u64", not(feature = "string-ids")))]
+pub type ClientId = u64;
+#[cfg(all(
+    feature = "client-id-u32",
+    not(any(feature = "client-id-u64", feature = "string-ids"))
+))]
+pub type ClientId = u32;
+#[cfg(not(any(
+    feature = "client-id-u32",
+    feature = "client-id-u64",
+    feature = "string-ids"
+)))]
 pub type ClientId = u16;
 pub type ClientsMap = HashMap<ClientId, ClientData>;
 
+/*
+A client id in tests, whatever the id type.
+*/
+#[cfg(test)]
+pub fn client_id(id: u16) -> ClientId {
+    ClientId::from(id)
+}
+
+/*
+The sub-account of records without an account.
+*/
+pub const DEFAULT_ACCOUNT: &str = "main";
+
+/*
+The account of a client's roll-up in the output of --sub-accounts, so it
+can't be the name of a sub-account.
+*/
+pub const ROLL_UP: &str = "*";
+
+const ROLL_UP_ERROR: &str = "The account name * is the client roll-up";
+
+/*
+What a chargeback locks: only the sub-account of the deposit, or the whole
+client with all its sub-accounts.
+*/
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockScope {
+    #[default]
+    Client,
+    Account,
+}
+
+impl FromStr for LockScope {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "client" => Ok(Self::Client),
+            "account" => Ok(Self::Account),
+            _ => Err("Unsupported lock scope, expected client or account"),
+        }
+    }
+}
+
+/*
+What a locked client or sub-account still accepts: nothing, or deposits, which
+can then only be paid out once it is unlocked.
+*/
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LockedAccepts {
+    #[default]
+    Nothing,
+    Deposits,
+}
+
+/*
+A client with one or more sub-accounts (e.g. "main", "savings", "escrow").
+Balances are kept per sub-account, the getters give the roll-up over all of them.
+Every change checks that the roll-up still fits, so only the getters of a
+loaded state can overflow.
+*/
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(try_from = "ClientDataFile")]
 pub struct ClientData {
-    available: Cents,
-    held: Cents,
+    accounts: BTreeMap<String, AccountData>,
     locked: bool,
 }
 
+/*
+State files from before sub-accounts have a single account per client, whose
+lock was the lock of the whole client.
+*/
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ClientDataFile {
+    Accounts {
+        accounts: BTreeMap<String, AccountData>,
+        locked: bool,
+    },
+    Single(AccountData),
+}
+
+impl TryFrom<ClientDataFile> for ClientData {
+    type Error = &'static str;
+
+    fn try_from(file: ClientDataFile) -> Result<Self, Self::Error> {
+        match file {
+            ClientDataFile::Accounts { accounts, .. } if accounts.contains_key(ROLL_UP) => {
+                Err(ROLL_UP_ERROR)
+            }
+            ClientDataFile::Accounts { accounts, locked } => Ok(Self { accounts, locked }),
+            ClientDataFile::Single(mut account) => {
+                let locked = account.locked;
+                account.locked = false;
+                Ok(Self {
+                    accounts: BTreeMap::from([(DEFAULT_ACCOUNT.to_string(), account)]),
+                    locked,
+                })
+            }
+        }
+    }
+}
+
 impl ClientData {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get_available(&self) -> Result<Money, &'static str> {
+        self.sum(|a| Ok(a.available))
+    }
+
+    pub fn get_held(&self) -> Result<Money, &'static str> {
+        self.sum(|a| Ok(a.held))
+    }
+
+    pub fn get_total(&self) -> Result<Money, &'static str> {
+        self.sum(AccountData::get_total)
+    }
+
+    /*
+    Whether the whole client or any of its sub-accounts is locked.
+    */
+    pub fn is_locked(&self) -> bool {
+        self.locked || self.accounts.values().any(AccountData::is_locked)
+    }
+
+    /*
+    Whether the whole client is locked, not just some sub-accounts.
+    */
+    pub fn is_client_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn accounts(&self) -> impl Iterator<Item = (&str, &AccountData)> {
+        self.accounts.iter().map(|(name, a)| (name.as_str(), a))
+    }
+
+    pub fn get_account(&self, name: &str) -> Option<&AccountData> {
+        self.accounts.get(name)
+    }
+
+    /*
+    Creates the sub-account if it doesn't exist yet.
+    */
+    pub fn deposit(&mut self, account: &str, cents: Money) -> Result<(), &'static str> {
+        self.update(account, true, true, |a| a.deposit(cents))
+    }
+
+    /*
+    Same as `deposit`, but also to a locked client or sub-account.
+    */
+    pub fn deposit_to_locked(&mut self, account: &str, cents: Money) -> Result<(), &'static str> {
+        self.update(account, true, false, |a| a.deposit_to_locked(cents))
+    }
+
+    pub fn withdrawal(&mut self, account: &str, cents: Money) -> Result<(), &'static str> {
+        self.update(account, false, true, |a| a.withdrawal(cents))
+    }
+
+    pub fn dispute(&mut self, account: &str, cents: Money) -> Result<(), &'static str> {
+        self.update(account, false, true, |a| a.dispute(cents))
+    }
+
+    pub fn resolve(&mut self, account: &str, cents: Money) -> Result<(), &'static str> {
+        self.update(account, false, true, |a| a.resolve(cents))
+    }
+
+    pub fn chargeback(
+        &mut self,
+        account: &str,
+        cents: Money,
+        scope: LockScope,
+    ) -> Result<(), &'static str> {
+        self.update(account, false, true, |a| a.chargeback(cents))?;
+        if scope == LockScope::Client {
+            self.locked = true;
+        }
+        Ok(())
+    }
+
+    /*
+    Creates a sub-account with opening balances, e.g. from the accounts file of
+    an earlier run.
+    */
+    pub fn open_account(
+        &mut self,
+        name: &str,
+        available: Money,
+        held: Money,
+        locked: bool,
+    ) -> Result<(), &'static str> {
+        if self.accounts.contains_key(name) {
+            return Err("Account is given more than once");
+        }
+        self.update(name, true, false, |a| {
+            a.available = available;
+            a.held = held;
+            a.locked = locked;
+            Ok(())
+        })
+    }
+
+    pub fn lock(&mut self) {
+        self.locked = true;
+    }
+
+    /*
+    Applies `change` to a copy of the sub-account, which only replaces the
+    sub-account if the change succeeded and the roll-up still fits.
+    A locked client is only changed without `check_locked`.
+    */
+    fn update(
+        &mut self,
+        name: &str,
+        create: bool,
+        check_locked: bool,
+        change: impl FnOnce(&mut AccountData) -> Result<(), &'static str>,
+    ) -> Result<(), &'static str> {
+        let mut account = match self.accounts.get(name) {
+            Some(a) => a.clone(),
+            None if create && name == ROLL_UP => return Err(ROLL_UP_ERROR),
+            None if create => AccountData::new(),
+            None => return Err("Account not found"),
+        };
+
+        change(&mut account)?;
+        if check_locked && self.locked {
+            return Err("Client is locked");
+        }
+
+        let mut available = account.available;
+        let mut held = account.held;
+        for (_, other) in self.accounts.iter().filter(|(n, _)| n.as_str() != name) {
+            available = available.checked_add(other.available)?;
+            held = held.checked_add(other.held)?;
+        }
+        available.checked_add(held)?;
+
+        // only a new sub-account needs its name allocated
+        match self.accounts.get_mut(name) {
+            Some(a) => *a = account,
+            None => {
+                self.accounts.insert(name.to_string(), account);
+            }
+        }
+        Ok(())
+    }
+
+    /*
+    Changes can't overflow the sums, see update, but a loaded state may.
+    */
+    fn sum(
+        &self,
+        field: impl Fn(&AccountData) -> Result<Money, &'static str>,
+    ) -> Result<Money, &'static str> {
+        self.accounts
+            .values()
+            .try_fold(Money::ZERO, |sum, a| sum.checked_add(field(a)?))
+    }
+}
+
+/*
+The balances of a single sub-account.
+*/
+#[derive(Clone, Deserialize, Serialize)]
+pub struct AccountData {
+    available: Money,
+    held: Money,
+    locked: bool,
+}
+
+impl Default for AccountData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AccountData {
     pub fn new() -> Self {
         Self {
-            available: 0,
-            held: 0,
+            available: Money::ZERO,
+            held: Money::ZERO,
             locked: false,
         }
     }
 
-    pub fn get_available(&self) -> Cents {
+    pub fn get_available(&self) -> Money {
         self.available
     }
 
-    pub fn get_held(&self) -> Cents {
+    pub fn get_held(&self) -> Money {
         self.held
     }
 
-    pub fn get_total(&self) -> Cents {
-        self.available + self.held
+    /*
+    Changes below check the new total, but a loaded state may overflow it.
+    */
+    pub fn get_total(&self) -> Result<Money, &'static str> {
+        self.available.checked_add(self.held)
     }
 
     pub fn is_locked(&self) -> bool {
         self.locked
     }
 
-    pub fn deposit(&mut self, cents: Cents) -> Result<(), &'static str> {
+    pub fn deposit(&mut self, cents: Money) -> Result<(), &'static str> {
         Self::check_positive(cents)?;
         self.check_locked()?;
+        self.deposit_to_locked(cents)
+    }
 
-        self.available += cents;
+    pub fn deposit_to_locked(&mut self, cents: Money) -> Result<(), &'static str> {
+        Self::check_positive(cents)?;
+
+        let available = self.available.checked_add(cents)?;
+        available.checked_add(self.held)?;
+
+        self.available = available;
 
         Ok(())
     }
 
-    pub fn withdrawal(&mut self, cents: Cents) -> Result<(), &'static str> {
+    pub fn withdrawal(&mut self, cents: Money) -> Result<(), &'static str> {
         Self::check_positive(cents)?;
         self.check_locked()?;
 
         if self.available >= cents {
-            self.available -= cents;
+            let available = self.available.checked_sub(cents)?;
+            available.checked_add(self.held)?;
+
+            self.available = available;
 
             Ok(())
         } else {
@@ -58,31 +368,42 @@ impl ClientData {
         }
     }
 
-    pub fn dispute(&mut self, cents: Cents) -> Result<(), &'static str> {
+    pub fn dispute(&mut self, cents: Money) -> Result<(), &'static str> {
         Self::check_positive(cents)?;
         self.check_locked()?;
 
-        self.available -= cents;
-        self.held += cents;
+        let available = self.available.checked_sub(cents)?;
+        let held = self.held.checked_add(cents)?;
+        available.checked_add(held)?;
+
+        self.available = available;
+        self.held = held;
 
         Ok(())
     }
 
-    pub fn resolve(&mut self, cents: Cents) -> Result<(), &'static str> {
+    pub fn resolve(&mut self, cents: Money) -> Result<(), &'static str> {
         Self::check_positive(cents)?;
         self.check_locked()?;
 
-        self.available += cents;
-        self.held -= cents;
+        let available = self.available.checked_add(cents)?;
+        let held = self.held.checked_sub(cents)?;
+        available.checked_add(held)?;
+
+        self.available = available;
+        self.held = held;
 
         Ok(())
     }
 
-    pub fn chargeback(&mut self, cents: Cents) -> Result<(), &'static str> {
+    pub fn chargeback(&mut self, cents: Money) -> Result<(), &'static str> {
         Self::check_positive(cents)?;
         self.check_locked()?;
 
-        self.held -= cents;
+        let held = self.held.checked_sub(cents)?;
+        self.available.checked_add(held)?;
+
+        self.held = held;
         self.locked = true;
 
         Ok(())
@@ -96,8 +417,8 @@ impl ClientData {
         }
     }
 
-    fn check_positive(cents: Cents) -> Result<(), &'static str> {
-        if cents >= 0 {
+    fn check_positive(cents: Money) -> Result<(), &'static str> {
+        if !cents.is_negative() {
             Ok(())
         } else {
             Err("Amount may not be negative")
@@ -107,95 +428,100 @@ impl ClientData {
 
 #[cfg(test)]
 mod tests {
-    use super::ClientData;
+    use super::{AccountData, ClientData, LockScope, ROLL_UP, ROLL_UP_ERROR};
+    use crate::cents::{Cents, Money, OVERFLOW_ERROR};
+
+    fn cents(c: Cents) -> Money {
+        Money::from_cents(c)
+    }
 
     #[test]
     fn deposit() {
-        let mut client = ClientData::new();
-        assert!(client.deposit(100).is_ok());
+        let mut client = AccountData::new();
+        assert!(client.deposit(cents(100)).is_ok());
 
-        assert_eq!(client.get_available(), 100);
-        assert_eq!(client.get_held(), 0);
-        assert_eq!(client.get_total(), 100);
+        assert_eq!(client.get_available(), cents(100));
+        assert_eq!(client.get_held(), cents(0));
+        assert_eq!(client.get_total(), Ok(cents(100)));
     }
 
     #[test]
     fn negative_cents() {
-        let mut client = ClientData::new();
-        assert!(client.deposit(-100).is_err());
-        assert!(client.withdrawal(-100).is_err());
-        assert!(client.dispute(-100).is_err());
-        assert!(client.resolve(-100).is_err());
-        assert!(client.chargeback(-100).is_err());
-
-        assert_eq!(client.get_available(), 0);
-        assert_eq!(client.get_held(), 0);
-        assert_eq!(client.get_total(), 0);
+        let mut client = AccountData::new();
+        assert!(client.deposit(cents(-100)).is_err());
+        assert!(client.withdrawal(cents(-100)).is_err());
+        assert!(client.dispute(cents(-100)).is_err());
+        assert!(client.resolve(cents(-100)).is_err());
+        assert!(client.chargeback(cents(-100)).is_err());
+
+        assert_eq!(client.get_available(), cents(0));
+        assert_eq!(client.get_held(), cents(0));
+        assert_eq!(client.get_total(), Ok(cents(0)));
     }
 
     #[test]
     fn withdrawal_ok() {
         // 100 - 20 = 80
-        let mut client = ClientData::new();
-        assert!(client.deposit(100).is_ok());
+        let mut client = AccountData::new();
+        assert!(client.deposit(cents(100)).is_ok());
 
-        assert!(client.withdrawal(20).is_ok());
+        assert!(client.withdrawal(cents(20)).is_ok());
 
-        assert_eq!(client.get_available(), 80);
-        assert_eq!(client.get_held(), 0);
-        assert_eq!(client.get_total(), 80);
+        assert_eq!(client.get_available(), cents(80));
+        assert_eq!(client.get_held(), cents(0));
+        assert_eq!(client.get_total(), Ok(cents(80)));
 
         // 100 - 100 = 0
-        let mut client = ClientData::new();
-        assert!(client.deposit(100).is_ok());
+        let mut client = AccountData::new();
+        assert!(client.deposit(cents(100)).is_ok());
 
-        assert!(client.withdrawal(100).is_ok());
+        assert!(client.withdrawal(cents(100)).is_ok());
 
-        assert_eq!(client.get_available(), 0);
-        assert_eq!(client.get_held(), 0);
-        assert_eq!(client.get_total(), 0);
+        assert_eq!(client.get_available(), cents(0));
+        assert_eq!(client.get_held(), cents(0));
+        assert_eq!(client.get_total(), Ok(cents(0)));
     }
 
     #[test]
     fn withdrawal_insufficent_funds() {
-        let mut client = ClientData::new();
-        assert!(client.deposit(100).is_ok());
+        let mut client = AccountData::new();
+        assert!(client.deposit(cents(100)).is_ok());
 
-        assert!(client.withdrawal(101).is_err());
+        assert!(client.withdrawal(cents(101)).is_err());
 
-        assert_eq!(client.get_available(), 100);
-        assert_eq!(client.get_held(), 0);
-        assert_eq!(client.get_total(), 100);
+        assert_eq!(client.get_available(), cents(100));
+        assert_eq!(client.get_held(), cents(0));
+        assert_eq!(client.get_total(), Ok(cents(100)));
     }
 
     #[test]
     fn dispute() {
-        let mut client = ClientData::new();
-        assert!(client.dispute(100).is_ok());
+        let mut client = AccountData::new();
+        assert!(client.dispute(cents(100)).is_ok());
 
-        assert_eq!(client.get_available(), -100);
-        assert_eq!(client.get_held(), 100);
-        assert_eq!(client.get_total(), 0);
+        assert_eq!(client.get_available(), cents(-100));
+        assert_eq!(client.get_held(), cents(100));
+        assert_eq!(client.get_total(), Ok(cents(0)));
     }
 
     #[test]
     fn resolve() {
-        let mut client = ClientData::new();
-        assert!(client.resolve(100).is_ok());
+        let mut client = AccountData::new();
+        assert!(client.resolve(cents(100)).is_ok());
 
-        assert_eq!(client.get_available(), 100);
-        assert_eq!(client.get_held(), -100);
-        assert_eq!(client.get_total(), 0);
+        assert_eq!(client.get_available(), cents(100));
+        assert_eq!(client.get_held(), cents(-100));
+        assert_eq!(client.get_total(), Ok(cents(0)));
     }
 
     #[test]
     fn chargeback() {
-        let mut client = ClientData::new();
-        assert!(client.chargeback(100).is_ok());
+        let mut client = AccountData::new();
+        assert!(client.chargeback(cents(100)).is_ok());
 
-        assert_eq!(client.get_available(), 0);
-        assert_eq!(client.get_held(), -100);
-        assert_eq!(client.get_total(), -100);
+        assert_eq!(client.get_available(), cents(0));
+        assert_eq!(client.get_held(), cents(-100));
+        assert_eq!(client.get_total(), Ok(cents(-100)));
 
         assert!(client.is_locked());
     }
@@ -203,13 +529,185 @@ mod tests {
     #[test]
     fn locked() {
         // create and immediately lock an account
+        let mut client = AccountData::new();
+        assert!(client.chargeback(cents(100)).is_ok());
+
+        assert!(client.deposit(cents(100)).is_err());
+        assert!(client.withdrawal(cents(100)).is_err());
+        assert!(client.dispute(cents(100)).is_err());
+        assert!(client.resolve(cents(100)).is_err());
+        assert!(client.chargeback(cents(100)).is_err());
+    }
+
+    #[test]
+    fn overflow() {
+        let mut client = AccountData::new();
+        assert!(client.deposit(Money::MAX).is_ok());
+
+        // nothing changes when an operation would overflow
+        assert_eq!(client.deposit(cents(1)), Err(OVERFLOW_ERROR));
+        assert_eq!(client.resolve(cents(1)), Err(OVERFLOW_ERROR));
+        assert_eq!(client.get_available(), Money::MAX);
+        assert_eq!(client.get_held(), cents(0));
+
+        // a held amount still counts towards the total
+        let mut client = AccountData::new();
+        assert!(client.deposit(cents(Cents::MAX - 1)).is_ok());
+        assert!(client.dispute(cents(Cents::MAX - 1)).is_ok());
+        assert_eq!(client.deposit(cents(2)), Err(OVERFLOW_ERROR));
+        assert!(client.deposit(cents(1)).is_ok());
+        assert_eq!(client.get_total(), Ok(Money::MAX));
+
+        // available going too far negative
+        let mut client = AccountData::new();
+        assert!(client.dispute(Money::MAX).is_ok());
+        assert!(client.resolve(Money::MAX).is_ok());
+        assert!(client.dispute(Money::MAX).is_ok());
+        assert!(client.dispute(Money::MAX).is_err());
+        assert_eq!(client.get_available(), cents(-Cents::MAX));
+        assert_eq!(client.get_held(), Money::MAX);
+    }
+
+    #[test]
+    fn sub_accounts() {
+        let mut client = ClientData::new();
+        assert!(client.deposit("main", cents(100)).is_ok());
+        assert!(client.deposit("savings", cents(50)).is_ok());
+        assert!(client.dispute("savings", cents(20)).is_ok());
+
+        // the roll-up
+        assert_eq!(client.get_available(), Ok(cents(130)));
+        assert_eq!(client.get_held(), Ok(cents(20)));
+        assert_eq!(client.get_total(), Ok(cents(150)));
+
+        let savings = client.get_account("savings").unwrap();
+        assert_eq!(savings.get_available(), cents(30));
+        assert_eq!(savings.get_held(), cents(20));
+
+        // only deposits create sub-accounts
+        assert_eq!(
+            client.withdrawal("escrow", cents(1)),
+            Err("Account not found")
+        );
+        assert_eq!(client.accounts().count(), 2);
+
+        // a sub-account can't withdraw another one's funds
+        assert!(client.withdrawal("savings", cents(31)).is_err());
+    }
+
+    #[test]
+    fn lock_scope() {
+        let mut client = ClientData::new();
+        assert!(client.deposit("main", cents(100)).is_ok());
+        assert!(client.deposit("escrow", cents(100)).is_ok());
+        assert!(client.dispute("escrow", cents(100)).is_ok());
+        assert!(client
+            .chargeback("escrow", cents(100), LockScope::Account)
+            .is_ok());
+
+        // the other sub-account keeps working
+        assert!(client.deposit("main", cents(1)).is_ok());
+        assert!(client.deposit("escrow", cents(1)).is_err());
+        assert!(client.is_locked());
+        assert!(!client.is_client_locked());
+
         let mut client = ClientData::new();
-        assert!(client.chargeback(100).is_ok());
+        assert!(client.deposit("main", cents(100)).is_ok());
+        assert!(client.dispute("main", cents(100)).is_ok());
+        assert!(client
+            .chargeback("main", cents(100), LockScope::Client)
+            .is_ok());
+
+        // even new sub-accounts are locked
+        assert_eq!(client.deposit("savings", cents(1)), Err("Client is locked"));
+        assert!(client.is_client_locked());
+
+        // unless deposits to locked clients are allowed
+        assert!(client.deposit_to_locked("savings", cents(1)).is_ok());
+        assert!(client.deposit_to_locked("main", cents(1)).is_ok());
+        assert_eq!(client.get_total(), Ok(cents(2)));
+        assert!(client.withdrawal("main", cents(1)).is_err());
+    }
+
+    #[test]
+    fn roll_up_overflow() {
+        let mut client = ClientData::new();
+        assert!(client.deposit("main", Money::MAX).is_ok());
+
+        // fits into the sub-account, but not into the roll-up
+        assert_eq!(client.deposit("savings", cents(1)), Err(OVERFLOW_ERROR));
+        assert!(client.get_account("savings").is_none());
+        assert_eq!(client.get_total(), Ok(Money::MAX));
+
+        // a loaded state isn't checked by update
+        let account = format!(
+            r#"{{"available":"{}","held":"0","locked":false}}"#,
+            Money::MAX
+        );
+        let client: ClientData = serde_json::from_str(&format!(
+            r#"{{"accounts":{{"main":{0},"savings":{0}}},"locked":false}}"#,
+            account
+        ))
+        .unwrap();
+        assert_eq!(client.get_available(), Err(OVERFLOW_ERROR));
+        assert_eq!(client.get_total(), Err(OVERFLOW_ERROR));
+        assert_eq!(
+            client.get_account("main").unwrap().get_total(),
+            Ok(Money::MAX)
+        );
+    }
 
-        assert!(client.deposit(100).is_err());
-        assert!(client.withdrawal(100).is_err());
-        assert!(client.dispute(100).is_err());
-        assert!(client.resolve(100).is_err());
-        assert!(client.chargeback(100).is_err());
+    #[test]
+    fn roll_up_name() {
+        let mut client = ClientData::new();
+        assert_eq!(client.deposit(ROLL_UP, cents(1)), Err(ROLL_UP_ERROR));
+        assert_eq!(
+            client.open_account(ROLL_UP, cents(1), cents(0), false),
+            Err(ROLL_UP_ERROR)
+        );
+        assert_eq!(client.accounts().count(), 0);
+
+        // nor in a loaded state
+        let state =
+            r#"{"accounts":{"*":{"available":"1","held":"0","locked":false}},"locked":false}"#;
+        assert!(serde_json::from_str::<ClientData>(state).is_err());
+    }
+
+    #[test]
+    fn single_account_state() {
+        // state files from before sub-accounts
+        let client: ClientData =
+            serde_json::from_str(r#"{"available":"1","held":"2","locked":true}"#).unwrap();
+
+        assert_eq!(
+            client.get_account("main").unwrap().get_held(),
+            cents(2_0000)
+        );
+        assert!(client.is_client_locked());
+        assert!(!client.get_account("main").unwrap().is_locked());
+    }
+
+    #[test]
+    fn open_account() {
+        let mut client = ClientData::new();
+        assert!(client
+            .open_account("main", cents(100), cents(50), false)
+            .is_ok());
+        assert!(client
+            .open_account("savings", cents(10), cents(0), true)
+            .is_ok());
+
+        assert_eq!(client.get_total(), Ok(cents(160)));
+        assert!(client.is_locked());
+        assert!(!client.is_client_locked());
+        assert!(client.withdrawal("main", cents(100)).is_ok());
+
+        assert!(client
+            .open_account("main", cents(1), cents(0), false)
+            .is_err());
+        assert_eq!(
+            client.open_account("escrow", Money::MAX, cents(0), false),
+            Err(OVERFLOW_ERROR)
+        );
     }
 }
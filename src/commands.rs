@@ -0,0 +1,859 @@
+use csv::Writer;
+use serde::Serialize;
+use std::{
+    fs,
+    io::{ErrorKind, Write},
+    sync::Arc,
+};
+
+use crate::{
+    args::{Args, Command},
+    checkpoint::{self, Checkpoints},
+    input::{self, ApplyOptions, STRICT_STOP},
+    output::{self, Output},
+    policy::Policy,
+    process::{Handlers, Rules},
+    rejects::Rejects,
+    report::{Outcome, Report},
+    review::ReviewQueue,
+    script::Script,
+    sources::Inputs,
+    state::{State, DEFAULT_TENANT},
+};
+
+/*
+Runs the command given on the command line.
+*/
+pub fn run(args: &Args) -> Result<Outcome, &'static str> {
+    let options = apply_options(args)?;
+
+    match args.command {
+        Command::Process => process(args, &options),
+        Command::Validate => validate(args, &options),
+        Command::Report => report(args, &options),
+        Command::Statement => statement(args, &options),
+        Command::Serve => serve(args, &options),
+        Command::Snapshot => snapshot(args, &options),
+    }
+}
+
+fn process(args: &Args, options: &ApplyOptions) -> Result<Outcome, &'static str> {
+    let (state, mut report, rejects, review) = apply_input(args, options)?;
+
+    let mut out = Output::open(args.output.as_deref())?;
+    output::write_tenant_accounts(
+        &state,
+        args.sub_accounts,
+        output::account_writer(args.output_format, &mut out).as_mut(),
+    )?;
+
+    finish(args, &state, &mut report, rejects, review, out)
+}
+
+/*
+A dry run. Every record is applied to a scratch copy of the state, which is
+never saved, and the records that would be rejected are written as CSV.
+*/
+fn validate(args: &Args, options: &ApplyOptions) -> Result<Outcome, &'static str> {
+    let mut state = load_state(args)?;
+    let mut report = new_report(options);
+    let inputs = inputs(args)?;
+    let mut rejects =
+        Rejects::to_output(Output::open(args.output.as_deref())?, inputs.has_sources())?;
+    let mut review = ReviewQueue::open(args.review_queue.as_deref(), inputs.has_sources())?;
+
+    input::process_inputs(
+        &inputs,
+        options,
+        &mut state,
+        &mut report,
+        &mut rejects,
+        &mut review,
+        args.strict,
+    )?;
+
+    rejects.finish()?;
+    review.finish()?;
+
+    if let Some(filename) = &args.report {
+        report.finish();
+        report.write_file(filename)?;
+    }
+
+    Ok(report.outcome())
+}
+
+fn report(args: &Args, options: &ApplyOptions) -> Result<Outcome, &'static str> {
+    let (state, mut report, rejects, review) = apply_input(args, options)?;
+
+    let mut out = Output::open(args.output.as_deref())?;
+    report.finish();
+    report.write(&mut out)?;
+
+    finish(args, &state, &mut report, rejects, review, out)
+}
+
+/*
+Every record of one client, with the outcome and the client's balances after it.
+*/
+fn statement(args: &Args, options: &ApplyOptions) -> Result<Outcome, &'static str> {
+    // checked when parsing the args
+    let client_id = args.client.unwrap();
+    let tenant = args.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+
+    let mut state = load_state(args)?;
+    let mut report = new_report(options);
+    let inputs = inputs(args)?;
+    let mut rejects = Rejects::open(args.rejects.as_deref(), inputs.has_sources())?;
+    let mut review = ReviewQueue::open(args.review_queue.as_deref(), inputs.has_sources())?;
+
+    // rows are only written once the whole input was processed, so a strict
+    // mode stop doesn't leave a partial statement behind
+    let mut rows = Vec::new();
+
+    for (location, result) in inputs.read()? {
+        let outcome = input::apply_record(
+            &location,
+            &result,
+            options,
+            &mut state,
+            &mut report,
+            &mut rejects,
+            &mut review,
+        )?;
+        if args.strict && report.outcome() == Outcome::Rejections {
+            return Err(STRICT_STOP);
+        }
+
+        let record = match &result {
+            Ok(r)
+                if r.client == client_id
+                    && r.tenant.as_deref().unwrap_or(DEFAULT_TENANT) == tenant =>
+            {
+                r
+            }
+            _ => continue,
+        };
+
+        let (available, held, total, locked) =
+            match state.ledger(tenant).and_then(|l| l.clients.get(&client_id)) {
+                Some(c) => (
+                    c.get_available()?.to_string(),
+                    c.get_held()?.to_string(),
+                    c.get_total()?.to_string(),
+                    c.is_locked().to_string(),
+                ),
+                // nothing was ever applied to the client
+                None => Default::default(),
+            };
+
+        let mut row = Vec::with_capacity(10);
+        if inputs.has_sources() {
+            row.push(location.source.as_deref().unwrap_or_default().to_string());
+        }
+        row.extend([
+            location.line.to_string(),
+            record.record_type.to_string(),
+            record.transaction.to_string(),
+            record.amount.as_deref().unwrap_or_default().to_string(),
+            match outcome {
+                Ok(Some(_)) => "applied",
+                Ok(None) => "pending",
+                Err(error) => error,
+            }
+            .to_string(),
+            available,
+            held,
+            total,
+            locked,
+        ]);
+        rows.push(row);
+    }
+
+    input::finish_input(options, &mut state, &mut report, &mut rejects, &mut review)?;
+    if args.strict && report.outcome() == Outcome::Rejections {
+        return Err(STRICT_STOP);
+    }
+
+    let mut out = Output::open(args.output.as_deref())?;
+    let mut writer = Writer::from_writer(&mut out);
+
+    let header = [
+        "source",
+        "line",
+        "type",
+        "tx",
+        "amount",
+        "result",
+        "available",
+        "held",
+        "total",
+        "locked",
+    ];
+    let header = if inputs.has_sources() {
+        &header[..]
+    } else {
+        &header[1..]
+    };
+    writer
+        .write_record(header)
+        .map_err(|_| "Failed to write output")?;
+    for row in rows {
+        writer
+            .write_record(&row)
+            .map_err(|_| "Failed to write output")?;
+    }
+    writer.flush().map_err(|_| "Failed to write output")?;
+    drop(writer);
+
+    finish(args, &state, &mut report, rejects, review, out)
+}
+
+#[derive(Serialize)]
+struct ServeResponse {
+    line: u32,
+    result: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'static str>,
+}
+
+/*
+Applies records from stdin as they arrive and answers each one with a JSON line.
+A pending record is answered as such, and not again once it is applied.
+The state is saved once stdin is closed.
+In strict mode serving stops after answering the first rejected record.
+*/
+fn serve(args: &Args, options: &ApplyOptions) -> Result<Outcome, &'static str> {
+    let mut state = load_state(args)?;
+    let mut report = new_report(options);
+    let inputs = inputs(args)?;
+    let mut rejects = Rejects::open(args.rejects.as_deref(), false)?;
+    let mut review = ReviewQueue::open(args.review_queue.as_deref(), false)?;
+    let mut out = Output::open(args.output.as_deref())?;
+
+    for (location, result) in inputs.read()? {
+        let outcome = input::apply_record(
+            &location,
+            &result,
+            options,
+            &mut state,
+            &mut report,
+            &mut rejects,
+            &mut review,
+        )?;
+
+        let response = ServeResponse {
+            line: location.line,
+            result: match outcome {
+                Ok(Some(_)) => "applied",
+                Ok(None) => "pending",
+                Err(_) => "rejected",
+            },
+            error: outcome.err(),
+        };
+
+        serde_json::to_writer(&mut out, &response).map_err(|_| "Failed to write output")?;
+        writeln!(out).map_err(|_| "Failed to write output")?;
+        // the other side is waiting for the answer
+        out.flush().map_err(|_| "Failed to write output")?;
+
+        if args.strict && report.outcome() == Outcome::Rejections {
+            return Err(STRICT_STOP);
+        }
+    }
+
+    input::finish_input(options, &mut state, &mut report, &mut rejects, &mut review)?;
+    if args.strict && report.outcome() == Outcome::Rejections {
+        return Err(STRICT_STOP);
+    }
+
+    finish(args, &state, &mut report, rejects, review, out)
+}
+
+fn snapshot(args: &Args, options: &ApplyOptions) -> Result<Outcome, &'static str> {
+    let (state, mut report, rejects, review) = apply_input(args, options)?;
+
+    let mut out = Output::open(args.output.as_deref())?;
+    state.write(&mut out)?;
+
+    finish(args, &state, &mut report, rejects, review, out)
+}
+
+/*
+The policy comes from the policy file, or else from the lock scope options.
+The script, if any, is compiled here so a broken one fails before any input is
+read.
+*/
+fn apply_options(args: &Args) -> Result<ApplyOptions, &'static str> {
+    let policy = match &args.policy {
+        Some(filename) => Policy::load(filename)?,
+        None => {
+            let rules = Rules {
+                lock_scope: args.lock_scope,
+                ..Rules::default()
+            };
+            let tenants = args
+                .tenant_lock_scopes
+                .iter()
+                .map(|(tenant, &lock_scope)| {
+                    let rules = Rules {
+                        lock_scope,
+                        ..rules.clone()
+                    };
+                    (tenant.clone(), rules)
+                })
+                .collect();
+            Policy {
+                rules,
+                tenants,
+                fraud: Vec::new(),
+            }
+        }
+    };
+
+    let script = match &args.script {
+        Some(filename) => Some(Arc::new(Script::load(filename)?)),
+        None => None,
+    };
+
+    Ok(ApplyOptions {
+        lenient: args.lenient,
+        policy,
+        pending_window: args.pending,
+        script,
+        handlers: Handlers::default(),
+    })
+}
+
+fn new_report(options: &ApplyOptions) -> Report {
+    let mut report = Report::new();
+    report.policy(&options.policy);
+    report
+}
+
+fn inputs(args: &Args) -> Result<Inputs, &'static str> {
+    Inputs::new(&args.inputs, args.input_format, args.merge_by.clone())
+}
+
+fn load_state(args: &Args) -> Result<State, &'static str> {
+    match (&args.load_state, &args.opening_balances) {
+        (Some(filename), _) => State::load(filename),
+        (None, Some(filename)) => State::load_accounts(filename),
+        (None, None) => Ok(State::new()),
+    }
+}
+
+/*
+Applies all the input files to the (loaded) state.
+*/
+fn apply_input(
+    args: &Args,
+    options: &ApplyOptions,
+) -> Result<(State, Report, Rejects, ReviewQueue), &'static str> {
+    let mut state = load_state(args)?;
+    let mut report = new_report(options);
+    let inputs = inputs(args)?;
+
+    // with checkpoints the rejects and review queue are opened to resume from
+    if let Some(filename) = &args.checkpoint {
+        let (rejects, review) = checkpoint::process_input_file(
+            inputs
+                .single_file()
+                .ok_or("--checkpoint needs a single input file")?,
+            args.input_format,
+            &Checkpoints {
+                filename: filename.clone(),
+                every: args.checkpoint_every,
+                resume: args.resume,
+            },
+            options,
+            &mut state,
+            &mut report,
+            args.rejects.as_deref(),
+            args.review_queue.as_deref(),
+            args.strict,
+        )?;
+        return Ok((state, report, rejects, review));
+    }
+
+    let mut rejects = Rejects::open(args.rejects.as_deref(), inputs.has_sources())?;
+    let mut review = ReviewQueue::open(args.review_queue.as_deref(), inputs.has_sources())?;
+    input::process_inputs(
+        &inputs,
+        options,
+        &mut state,
+        &mut report,
+        &mut rejects,
+        &mut review,
+        args.strict,
+    )?;
+
+    Ok((state, report, rejects, review))
+}
+
+/*
+Commits the output and writes the optional rejects, review queue, report and
+state files.
+Nothing is written before this point, so a failed run leaves no output files,
+except for the rejects and review queue of a run with checkpoints.
+*/
+fn finish(
+    args: &Args,
+    state: &State,
+    report: &mut Report,
+    rejects: Rejects,
+    review: ReviewQueue,
+    out: Output,
+) -> Result<Outcome, &'static str> {
+    out.commit()?;
+    rejects.finish()?;
+    review.finish()?;
+
+    if let Some(filename) = &args.report {
+        report.finish();
+        report.write_file(filename)?;
+    }
+
+    if let Some(filename) = &args.save_state {
+        state.save(filename)?;
+    }
+
+    // the run is complete, there is nothing left to resume
+    if let Some(filename) = &args.checkpoint {
+        match fs::remove_file(filename) {
+            Err(e) if e.kind() != ErrorKind::NotFound => {
+                return Err("Failed to remove checkpoint file")
+            }
+            _ => {}
+        }
+    }
+
+    Ok(report.outcome())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, env, fs};
+
+    use super::run;
+    use crate::{
+        args::{Args, Command, DEFAULT_CHECKPOINT_EVERY},
+        cents::Precision,
+        checkpoint::{self, Checkpoints},
+        client::LockScope,
+        input::{ApplyOptions, InputFormat, STRICT_STOP},
+        output::OutputFormat,
+        record_type::RecordTypes,
+        report::{Outcome, Report},
+        state::State,
+    };
+
+    fn args(input: &str, name: &str) -> Args {
+        let dir = env::temp_dir();
+        let path = |suffix: &str| {
+            dir.join(format!("transaction_processor_{}_{}", name, suffix))
+                .to_str()
+                .unwrap()
+                .to_string()
+        };
+
+        Args {
+            command: Command::Process,
+            inputs: vec![input.to_string()],
+            input_format: InputFormat::Csv,
+            merge_by: None,
+            output: Some(path("accounts.csv")),
+            output_format: OutputFormat::Csv,
+            report: Some(path("report.json")),
+            rejects: Some(path("rejects.csv")),
+            review_queue: None,
+            load_state: None,
+            save_state: Some(path("state.json")),
+            opening_balances: None,
+            client: None,
+            tenant: None,
+            strict: false,
+            policy: None,
+            script: None,
+            precision: Precision::default(),
+            record_types: RecordTypes::default(),
+            lenient: None,
+            lock_scope: LockScope::Client,
+            tenant_lock_scopes: BTreeMap::new(),
+            sub_accounts: false,
+            pending: 0,
+            checkpoint: None,
+            checkpoint_every: DEFAULT_CHECKPOINT_EVERY,
+            resume: false,
+        }
+    }
+
+    fn outputs(args: &Args) -> Vec<String> {
+        vec![
+            args.output.clone().unwrap(),
+            args.report.clone().unwrap(),
+            args.rejects.clone().unwrap(),
+            args.save_state.clone().unwrap(),
+        ]
+    }
+
+    // JSON with sorted keys, and the report without its duration
+    fn read_outputs(args: &Args) -> Vec<String> {
+        outputs(args)
+            .iter()
+            .map(|output| {
+                let content = fs::read_to_string(output).unwrap();
+                if !output.ends_with(".json") {
+                    return content;
+                }
+                let mut json: serde_json::Value = serde_json::from_str(&content).unwrap();
+                json.as_object_mut().unwrap().remove("duration_secs");
+                json.to_string()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn clean() {
+        let args = args("test_data/single_deposit.csv", "clean");
+
+        assert_eq!(run(&args), Ok(Outcome::Clean));
+
+        for output in outputs(&args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+    }
+
+    #[test]
+    fn lenient_rejections() {
+        let args = args("test_data/transactions.csv", "lenient");
+
+        assert_eq!(run(&args), Ok(Outcome::Rejections));
+
+        // everything is written anyway
+        assert_eq!(
+            fs::read_to_string(args.output.as_ref().unwrap()).unwrap(),
+            "client,available,held,total,locked\n1,2.0100,1.5000,3.5100,false\n",
+        );
+        for output in outputs(&args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+    }
+
+    #[test]
+    fn strict_leaves_no_output() {
+        for command in [Command::Process, Command::Report, Command::Snapshot] {
+            let args = Args {
+                command,
+                strict: true,
+                ..args("test_data/transactions.csv", "strict")
+            };
+
+            assert_eq!(run(&args), Err(STRICT_STOP));
+
+            for output in outputs(&args) {
+                assert!(fs::metadata(&output).is_err());
+                assert!(fs::metadata(format!("{}.tmp", output)).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn validate() {
+        let mut args = Args {
+            command: Command::Validate,
+            report: None,
+            rejects: None,
+            save_state: None,
+            ..args("test_data/validate.csv", "validate")
+        };
+
+        // the loaded state is used but never written back
+        let state = env::temp_dir().join("transaction_processor_validate_in.json");
+        fs::write(
+            &state,
+            r#"{"clients":{"1":{"available":"1","held":"0","locked":false}},"transactions":{"1":{"type":"deposit","client":1,"amount":"1","state":"Ok"}}}"#,
+        )
+        .unwrap();
+        args.load_state = Some(state.to_str().unwrap().to_string());
+        let before = fs::read_to_string(&state).unwrap();
+
+        assert_eq!(run(&args), Ok(Outcome::Rejections));
+
+        // the last withdrawal only succeeds because the loaded state and the deposit are applied
+        assert_eq!(
+            fs::read_to_string(args.output.as_ref().unwrap()).unwrap(),
+            concat!(
+                "line,type,client,tx,amount,error\n",
+                "3,withdrawal,1,3,2,Insufficient available funds for withdrawal\n",
+                "4,dispute,1,9,,Transaction not found\n",
+            ),
+        );
+        assert_eq!(fs::read_to_string(&state).unwrap(), before);
+
+        fs::remove_file(args.output.as_ref().unwrap()).unwrap();
+        fs::remove_file(&state).unwrap();
+    }
+
+    #[test]
+    fn policy() {
+        let policy_args = Args {
+            policy: Some("test_data/policy.toml".to_string()),
+            ..args("test_data/zero_deposit.csv", "policy")
+        };
+
+        assert_eq!(run(&policy_args), Ok(Outcome::Rejections));
+
+        let report: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(policy_args.report.as_ref().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report["rejections"]["Amount may not be zero"], 1);
+        assert_eq!(report["policy"]["rules"]["zero_amounts"], false);
+        assert_eq!(report["policy"]["rules"]["lock_scope"], "client");
+        assert_eq!(report["policy"]["tenants"]["acme"]["lock_scope"], "account");
+        assert_eq!(report["policy"]["tenants"]["acme"]["zero_amounts"], false);
+
+        for output in outputs(&policy_args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+
+        // nothing is written without a valid policy
+        let missing = Args {
+            policy: Some("test_data/missing.toml".to_string()),
+            ..args("test_data/zero_deposit.csv", "policy_missing")
+        };
+        assert_eq!(run(&missing), Err("Failed to open policy file"));
+        for output in outputs(&missing) {
+            assert!(fs::metadata(&output).is_err());
+        }
+    }
+
+    #[test]
+    fn fraud() {
+        let mut args = Args {
+            policy: Some("test_data/fraud.toml".to_string()),
+            ..args("test_data/fraud.csv", "fraud")
+        };
+        let review = format!("{}.review", args.output.as_ref().unwrap());
+        args.review_queue = Some(review.clone());
+
+        // the flagged withdrawal is applied, the held one is not
+        assert_eq!(run(&args), Ok(Outcome::Rejections));
+        assert_eq!(
+            fs::read_to_string(args.output.as_ref().unwrap()).unwrap(),
+            concat!(
+                "client,available,held,total,locked\n",
+                "1,0.5000,0.0000,0.5000,false\n",
+                "2,4.0000,0.0000,4.0000,false\n",
+            ),
+        );
+        assert_eq!(
+            fs::read_to_string(&review).unwrap(),
+            concat!(
+                "line,rule,action,tenant,type,client,tx,amount\n",
+                "3,drain,flag,,withdrawal,1,2,9.5\n",
+                "4,velocity,hold,,withdrawal,1,3,0.1\n",
+            ),
+        );
+
+        let report: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(args.report.as_ref().unwrap()).unwrap())
+                .unwrap();
+        assert_eq!(report["fraud_hits"]["drain"], 1);
+        assert_eq!(report["fraud_hits"]["velocity"], 1);
+        assert_eq!(report["rejections"]["Held for review by a fraud rule"], 1);
+
+        for output in outputs(&args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+        fs::remove_file(review).unwrap();
+    }
+
+    #[test]
+    fn script() {
+        let mut script_args = Args {
+            script: Some("test_data/script.rhai".to_string()),
+            ..args("test_data/script.csv", "script")
+        };
+        let review = format!("{}.review", script_args.output.as_ref().unwrap());
+        script_args.review_queue = Some(review.clone());
+
+        // the fee of the deposit is applied, the large withdrawal is vetoed
+        assert_eq!(run(&script_args), Ok(Outcome::Rejections));
+        assert_eq!(
+            fs::read_to_string(script_args.output.as_ref().unwrap()).unwrap(),
+            "client,available,held,total,locked\n1,1949.5000,0.0000,1949.5000,false\n",
+        );
+        assert_eq!(
+            fs::read_to_string(script_args.rejects.as_ref().unwrap()).unwrap(),
+            concat!(
+                "line,type,client,tx,amount,error\n",
+                "3,withdrawal,1,2,150,Withdrawal over 100 needs approval\n",
+            ),
+        );
+        assert_eq!(
+            fs::read_to_string(&review).unwrap(),
+            concat!(
+                "line,rule,action,tenant,type,client,tx,amount\n",
+                "2,large_deposit,tag,,deposit,1,1,2000\n",
+            ),
+        );
+
+        let report: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(script_args.report.as_ref().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report["records_emitted"], 1);
+        assert_eq!(report["tags"]["large_deposit"], 1);
+        assert_eq!(report["records"]["withdrawal"]["applied"], 2);
+
+        for output in outputs(&script_args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+        fs::remove_file(review).unwrap();
+
+        // a record whose after_apply hook fails stays applied and is reviewed
+        let failing = env::temp_dir().join("transaction_processor_after_apply.rhai");
+        fs::write(
+            &failing,
+            "fn after_apply(record, client) { if record.type == \"deposit\" { throw \"no fee\"; } }",
+        )
+        .unwrap();
+        let mut failing_args = Args {
+            script: Some(failing.to_str().unwrap().to_string()),
+            ..args("test_data/script.csv", "script_after_apply")
+        };
+        let review = format!("{}.review", failing_args.output.as_ref().unwrap());
+        failing_args.review_queue = Some(review.clone());
+
+        assert_eq!(run(&failing_args), Ok(Outcome::Clean));
+        assert_eq!(
+            fs::read_to_string(failing_args.output.as_ref().unwrap()).unwrap(),
+            "client,available,held,total,locked\n1,1800.0000,0.0000,1800.0000,false\n",
+        );
+        assert_eq!(
+            fs::read_to_string(&review).unwrap(),
+            concat!(
+                "line,rule,action,tenant,type,client,tx,amount\n",
+                "2,after_apply,failed,,deposit,1,1,2000\n",
+            ),
+        );
+        let report: serde_json::Value = serde_json::from_str(
+            &fs::read_to_string(failing_args.report.as_ref().unwrap()).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(report["hook_failures"], 1);
+
+        for output in outputs(&failing_args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+        fs::remove_file(review).unwrap();
+        fs::remove_file(failing).unwrap();
+
+        // nothing is read or written with a broken script
+        let broken = Args {
+            script: Some("test_data/script.csv".to_string()),
+            ..args("test_data/script.csv", "script_broken")
+        };
+        assert_eq!(run(&broken), Err("Failed to parse script file"));
+        for output in outputs(&broken) {
+            assert!(fs::metadata(&output).is_err());
+        }
+    }
+
+    #[test]
+    fn merge_by() {
+        let mut args = Args {
+            command: Command::Validate,
+            inputs: vec![
+                "test_data/merge_b.csv".to_string(),
+                "test_data/merge_a.csv".to_string(),
+            ],
+            report: None,
+            rejects: None,
+            save_state: None,
+            ..args("", "merge_by")
+        };
+
+        // one after the other the withdrawal and dispute come too early
+        assert_eq!(run(&args), Ok(Outcome::Rejections));
+        assert_eq!(
+            fs::read_to_string(args.output.as_ref().unwrap()).unwrap(),
+            concat!(
+                "source,line,type,client,tx,amount,error\n",
+                "test_data/merge_b.csv,2,withdrawal,1,4,0.5,Client not found\n",
+                "test_data/merge_b.csv,3,dispute,1,2,,Transaction not found\n",
+            ),
+        );
+
+        args.merge_by = Some("seq".to_string());
+        assert_eq!(run(&args), Ok(Outcome::Clean));
+
+        fs::remove_file(args.output.as_ref().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn checkpoint() {
+        let mut args = args("test_data/checkpoint.csv", "checkpoint");
+        let checkpoint = format!("{}.checkpoint", args.output.as_ref().unwrap());
+        args.checkpoint = Some(checkpoint.clone());
+        args.checkpoint_every = 2;
+
+        // a complete run leaves no checkpoint to resume from
+        assert_eq!(run(&args), Ok(Outcome::Rejections));
+        assert!(fs::metadata(&checkpoint).is_err());
+        let full = read_outputs(&args);
+
+        // a run that stopped after its last checkpoint, with the rejection of
+        // record 2 before it, resumes to the same outputs and exit code
+        let mut report = Report::new();
+        let _ = checkpoint::process_input_file(
+            "test_data/checkpoint.csv",
+            InputFormat::Csv,
+            &Checkpoints {
+                filename: checkpoint.clone(),
+                every: 2,
+                resume: false,
+            },
+            &ApplyOptions::default(),
+            &mut State::new(),
+            &mut report,
+            args.rejects.as_deref(),
+            None,
+            false,
+        )
+        .unwrap();
+        args.resume = true;
+        assert_eq!(run(&args), Ok(Outcome::Rejections));
+        assert_eq!(read_outputs(&args), full);
+        for output in outputs(&args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+
+        assert_eq!(run(&args), Err("Failed to open checkpoint file"));
+
+        args.inputs.push("test_data/merge_a.csv".to_string());
+        args.resume = false;
+        assert_eq!(run(&args), Err("--checkpoint needs a single input file"));
+    }
+
+    #[test]
+    fn opening_balances() {
+        let args = Args {
+            opening_balances: Some("test_data/opening_balances.csv".to_string()),
+            ..args("test_data/single_deposit.csv", "opening_balances")
+        };
+
+        assert_eq!(run(&args), Ok(Outcome::Clean));
+        let accounts = fs::read_to_string(args.output.as_ref().unwrap()).unwrap();
+        assert!(accounts.starts_with("client,available,held,total,locked\n"));
+        // the deposit is on top of the opening balance
+        assert!(accounts.contains("1,2.5000,0.5000,3.0000,false\n"));
+        assert!(accounts.contains("2,3.0000,0.0000,3.0000,true\n"));
+
+        for output in outputs(&args) {
+            assert!(fs::remove_file(output).is_ok());
+        }
+    }
+}
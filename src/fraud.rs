@@ -0,0 +1,409 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+
+use crate::{
+    cents::{Cents, Money},
+    client::ClientId,
+    input::InputRecord,
+    record_type::RecordType,
+};
+
+pub const HELD: &str = "Held for review by a fraud rule";
+pub const REJECTED: &str = "Rejected by a fraud rule";
+
+/*
+A fraud rule from the policy file, see the README.
+A rule looks at the client's records within its window before the record.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct FraudRule {
+    pub name: String,
+    #[serde(flatten)]
+    pub check: FraudCheck,
+    #[serde(flatten)]
+    pub window: Window,
+    pub action: FraudAction,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "rule", rename_all = "snake_case")]
+pub enum FraudCheck {
+    // more than `max` withdrawals of a client within the window
+    Velocity { max: u32 },
+    // a withdrawal of at least `percent` of the available funds within the
+    // window after the client's first deposit
+    DrainAfterFirstDeposit { percent: u32 },
+    // more than `max` disputes of a client within the window
+    RepeatedDisputes { max: u32 },
+}
+
+/*
+A window of records, or of minutes by the records' `time`.
+*/
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum Window {
+    #[serde(rename = "within_records")]
+    Records(u64),
+    #[serde(rename = "within_minutes")]
+    Minutes(u64),
+}
+
+/*
+What happens to a record that breaks a rule. It is always written to the
+review queue; `hold` and `reject` also keep it from being applied.
+The strictest action of all the rules a record breaks wins.
+*/
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum FraudAction {
+    Flag,
+    Hold,
+    Reject,
+}
+
+impl FraudAction {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Flag => "flag",
+            Self::Hold => "hold",
+            Self::Reject => "reject",
+        }
+    }
+}
+
+/*
+A `[[fraud]]` table of the policy file.
+*/
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FraudRuleFile {
+    rule: String,
+    name: Option<String>,
+    max: Option<u32>,
+    percent: Option<u32>,
+    within_records: Option<u64>,
+    within_minutes: Option<u64>,
+    action: FraudAction,
+}
+
+impl FraudRuleFile {
+    pub fn validate(self) -> Result<FraudRule, &'static str> {
+        let check = match (self.rule.as_str(), self.max, self.percent) {
+            ("velocity", Some(max), None) => FraudCheck::Velocity { max },
+            ("repeated_disputes", Some(max), None) => FraudCheck::RepeatedDisputes { max },
+            ("drain_after_first_deposit", None, Some(percent)) if (1..=100).contains(&percent) => {
+                FraudCheck::DrainAfterFirstDeposit { percent }
+            }
+            ("velocity" | "repeated_disputes", _, _) => {
+                return Err("velocity and repeated_disputes fraud rules need a max and no percent")
+            }
+            ("drain_after_first_deposit", _, _) => {
+                return Err("drain_after_first_deposit fraud rules need a percent from 1 to 100")
+            }
+            _ => {
+                return Err(
+                    "Unknown fraud rule, expected velocity, drain_after_first_deposit or repeated_disputes",
+                )
+            }
+        };
+
+        let window = match (self.within_records, self.within_minutes) {
+            (Some(n), None) if n > 0 => Window::Records(n),
+            (None, Some(n)) if n > 0 => Window::Minutes(n),
+            _ => return Err("Fraud rules need a within_records or within_minutes of at least 1"),
+        };
+
+        Ok(FraudRule {
+            name: self.name.unwrap_or(self.rule),
+            check,
+            window,
+            action: self.action,
+        })
+    }
+}
+
+/*
+What the rules need to know about the records applied so far: the recent
+withdrawals and disputes and the first deposit of every client, per tenant.
+Events that no rule's window can reach any more are dropped.
+Saved in checkpoints, but not in state files.
+*/
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct FraudHistory {
+    // records read so far
+    seq: u64,
+    clients: HashMap<String, HashMap<ClientId, ClientHistory>>,
+}
+
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct ClientHistory {
+    withdrawals: VecDeque<Event>,
+    disputes: VecDeque<Event>,
+    first_deposit: Option<Event>,
+}
+
+#[derive(Clone, Copy, Deserialize, Serialize)]
+struct Event {
+    seq: u64,
+    time: Option<u64>,
+}
+
+impl Window {
+    /*
+    Whether `event` is within the window of a record at `now`. An event or
+    record without a time is never within a window of minutes.
+    */
+    fn contains(self, event: Event, now: Event) -> bool {
+        match self {
+            Self::Records(n) => now.seq - event.seq < n,
+            Self::Minutes(n) => match (event.time, now.time) {
+                (Some(event), Some(now)) => now.saturating_sub(event) < n.saturating_mul(60),
+                _ => false,
+            },
+        }
+    }
+}
+
+impl FraudHistory {
+    /*
+    Called once for every record read.
+    */
+    pub fn tick(&mut self) {
+        self.seq += 1;
+    }
+
+    /*
+    The rules a record breaks. `available` is the client's available funds
+    before the record.
+    */
+    pub fn check<'a>(
+        &self,
+        rules: &'a [FraudRule],
+        tenant: &str,
+        record: &InputRecord,
+        available: Money,
+    ) -> Vec<&'a FraudRule> {
+        let now = self.now(record);
+        let history = self
+            .clients
+            .get(tenant)
+            .and_then(|clients| clients.get(&record.client));
+        let count = |events: fn(&ClientHistory) -> &VecDeque<Event>, window: Window| {
+            history.map_or(0, |h| {
+                events(h)
+                    .iter()
+                    .filter(|&&e| window.contains(e, now))
+                    .count()
+            })
+        };
+
+        let withdrawal = record.record_type == RecordType::Withdrawal;
+        let dispute = record.record_type == RecordType::Dispute;
+
+        rules
+            .iter()
+            .filter(|rule| match rule.check {
+                FraudCheck::Velocity { max } => {
+                    withdrawal && count(|h| &h.withdrawals, rule.window) + 1 > max as usize
+                }
+                FraudCheck::RepeatedDisputes { max } => {
+                    dispute && count(|h| &h.disputes, rule.window) + 1 > max as usize
+                }
+                FraudCheck::DrainAfterFirstDeposit { percent } => {
+                    let after_first_deposit = history
+                        .and_then(|h| h.first_deposit)
+                        .is_some_and(|e| rule.window.contains(e, now));
+                    let amount: Option<Money> =
+                        record.amount.as_deref().and_then(|a| a.parse().ok());
+                    withdrawal
+                        && after_first_deposit
+                        && amount.is_some_and(|a| drains(a, available, percent))
+                }
+            })
+            .collect()
+    }
+
+    /*
+    Remembers a record that was applied, for the rules of later records.
+    `new_client` is whether the client didn't exist before the record.
+    */
+    pub fn record(
+        &mut self,
+        rules: &[FraudRule],
+        tenant: &str,
+        record: &InputRecord,
+        new_client: bool,
+    ) {
+        let now = self.now(record);
+        if !self.clients.contains_key(tenant) {
+            self.clients.insert(tenant.to_string(), HashMap::new());
+        }
+        let history = self
+            .clients
+            .get_mut(tenant)
+            .unwrap()
+            .entry(record.client)
+            .or_default();
+
+        let events = match record.record_type {
+            RecordType::Deposit if new_client => {
+                history.first_deposit = Some(now);
+                return;
+            }
+            RecordType::Withdrawal => &mut history.withdrawals,
+            RecordType::Dispute => &mut history.disputes,
+            _ => return,
+        };
+        events.push_back(now);
+
+        // in the order they were applied, so the oldest go first
+        while let Some(&event) = events.front() {
+            if rules.iter().any(|rule| rule.window.contains(event, now)) {
+                break;
+            }
+            events.pop_front();
+        }
+    }
+
+    fn now(&self, record: &InputRecord) -> Event {
+        Event {
+            seq: self.seq,
+            time: record.time,
+        }
+    }
+}
+
+/*
+Whether `amount` is at least `percent` of positive `available` funds.
+*/
+fn drains(amount: Money, available: Money, percent: u32) -> bool {
+    available.as_cents() > 0
+        && amount.as_cents().saturating_mul(100)
+            >= available.as_cents().saturating_mul(Cents::from(percent))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FraudAction, FraudCheck, FraudHistory, FraudRule, Window};
+    use crate::{
+        amount::Amount, cents::Money, client::client_id, input::InputRecord,
+        record_type::RecordType, transaction::tx_id,
+    };
+
+    fn record(record_type: &str, amount: Option<&str>, time: Option<u64>) -> InputRecord {
+        InputRecord {
+            record_type: RecordType::named(record_type),
+            client: client_id(1),
+            transaction: tx_id(1),
+            amount: amount.map(Amount::from),
+            account: None,
+            tenant: None,
+            time,
+        }
+    }
+
+    fn rule(check: FraudCheck, window: Window) -> FraudRule {
+        FraudRule {
+            name: "test".to_string(),
+            check,
+            window,
+            action: FraudAction::Flag,
+        }
+    }
+
+    // feeds the records to the history, returns whether each broke the rule
+    fn hits(rule: &FraudRule, records: &[InputRecord]) -> Vec<bool> {
+        let rules = [rule.clone()];
+        let mut history = FraudHistory::default();
+        let mut new_client = true;
+        records
+            .iter()
+            .map(|r| {
+                history.tick();
+                let hit = !history
+                    .check(&rules, "", r, Money::from_cents(10_0000))
+                    .is_empty();
+                history.record(&rules, "", r, new_client);
+                new_client = false;
+                hit
+            })
+            .collect()
+    }
+
+    #[test]
+    fn velocity() {
+        let velocity = rule(FraudCheck::Velocity { max: 2 }, Window::Records(3));
+        let withdrawal = record("withdrawal", Some("1"), None);
+        let deposit = record("deposit", Some("1"), None);
+
+        assert_eq!(
+            hits(
+                &velocity,
+                &[
+                    withdrawal.clone(),
+                    withdrawal.clone(),
+                    withdrawal.clone(),
+                    deposit.clone(),
+                    deposit,
+                    withdrawal,
+                ]
+            ),
+            vec![false, false, true, false, false, false],
+        );
+    }
+
+    #[test]
+    fn minutes() {
+        let disputes = rule(FraudCheck::RepeatedDisputes { max: 1 }, Window::Minutes(10));
+
+        // 10 minutes apart is outside the window, records without a time never count
+        assert_eq!(
+            hits(
+                &disputes,
+                &[
+                    record("dispute", None, Some(0)),
+                    record("dispute", None, Some(599)),
+                    record("dispute", None, Some(1199)),
+                    record("dispute", None, None),
+                ]
+            ),
+            vec![false, true, false, false],
+        );
+    }
+
+    #[test]
+    fn drain_after_first_deposit() {
+        let drain = rule(
+            FraudCheck::DrainAfterFirstDeposit { percent: 90 },
+            Window::Records(2),
+        );
+        let deposit = record("deposit", Some("10"), None);
+
+        // available is 10 for every record
+        assert_eq!(
+            hits(
+                &drain,
+                &[deposit.clone(), record("withdrawal", Some("9"), None)]
+            ),
+            vec![false, true],
+        );
+        assert_eq!(
+            hits(
+                &drain,
+                &[deposit.clone(), record("withdrawal", Some("8.99"), None)]
+            ),
+            vec![false, false],
+        );
+        assert_eq!(
+            hits(
+                &drain,
+                &[
+                    deposit.clone(),
+                    deposit,
+                    record("withdrawal", Some("10"), None)
+                ]
+            ),
+            vec![false, false, false],
+        );
+    }
+}
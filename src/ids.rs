@@ -0,0 +1,186 @@
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{
+    cmp::Ordering,
+    collections::HashMap,
+    fmt,
+    str::FromStr,
+    sync::{LazyLock, Mutex},
+};
+
+/*
+A string id (e.g. a UUID or a partner reference) interned into a small Copy
+handle, so it can be used as a client or transaction id with the string-ids
+feature.
+Interned strings live for the rest of the run.
+Ids compare and sort by their string, not by the order they were interned in.
+*/
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct InternedId(u32);
+
+struct Interner {
+    ids: HashMap<&'static str, u32>,
+    strings: Vec<&'static str>,
+}
+
+static INTERNER: LazyLock<Mutex<Interner>> = LazyLock::new(|| {
+    Mutex::new(Interner {
+        ids: HashMap::new(),
+        strings: Vec::new(),
+    })
+});
+
+impl InternedId {
+    pub fn intern(s: &str) -> Result<Self, &'static str> {
+        if s.is_empty() {
+            return Err("Id is empty");
+        }
+
+        let mut interner = INTERNER.lock().unwrap_or_else(|e| e.into_inner());
+        if let Some(&id) = interner.ids.get(s) {
+            return Ok(Self(id));
+        }
+
+        let id = u32::try_from(interner.strings.len()).map_err(|_| "Too many different ids")?;
+        let s: &'static str = Box::leak(s.to_string().into_boxed_str());
+        interner.ids.insert(s, id);
+        interner.strings.push(s);
+        Ok(Self(id))
+    }
+
+    pub fn as_str(self) -> &'static str {
+        let interner = INTERNER.lock().unwrap_or_else(|e| e.into_inner());
+        interner.strings[self.0 as usize]
+    }
+}
+
+impl Ord for InternedId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        if self == other {
+            return Ordering::Equal;
+        }
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for InternedId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FromStr for InternedId {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::intern(s)
+    }
+}
+
+/*
+A numeric id is the id of its decimal string, as with numbers in JSON input.
+Panics if there are too many different ids.
+*/
+impl From<u16> for InternedId {
+    fn from(n: u16) -> Self {
+        Self::from(u32::from(n))
+    }
+}
+
+impl From<u32> for InternedId {
+    fn from(n: u32) -> Self {
+        Self::intern(&n.to_string()).unwrap()
+    }
+}
+
+/*
+Pads like a str, so ids line up in the table output.
+*/
+impl fmt::Display for InternedId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.as_str())
+    }
+}
+
+impl fmt::Debug for InternedId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl Serialize for InternedId {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/*
+Accepts numbers too, so JSON input like `"client": 1` still works.
+*/
+impl<'de> Deserialize<'de> for InternedId {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Visitor;
+
+        impl de::Visitor<'_> for Visitor {
+            type Value = InternedId;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a string or integer id")
+            }
+
+            fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+                InternedId::intern(s).map_err(E::custom)
+            }
+
+            fn visit_u64<E: de::Error>(self, n: u64) -> Result<Self::Value, E> {
+                self.visit_str(&n.to_string())
+            }
+
+            fn visit_i64<E: de::Error>(self, n: i64) -> Result<Self::Value, E> {
+                self.visit_str(&n.to_string())
+            }
+        }
+
+        deserializer.deserialize_any(Visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::InternedId;
+
+    fn id(s: &str) -> InternedId {
+        InternedId::intern(s).unwrap()
+    }
+
+    #[test]
+    fn intern() {
+        assert_eq!(id("tx-a"), id("tx-a"));
+        assert_ne!(id("tx-a"), id("tx-b"));
+        assert_eq!(id("tx-b").as_str(), "tx-b");
+        assert!(InternedId::intern("").is_err());
+    }
+
+    #[test]
+    fn order_by_string() {
+        let later = id("order-zz");
+        let earlier = id("order-aa");
+
+        assert!(earlier < later);
+        assert!(id("10") < id("9"));
+    }
+
+    #[test]
+    fn serde() {
+        let uuid = id("b5f8-41c3");
+
+        let json = serde_json::to_string(&uuid).unwrap();
+        assert_eq!(json, r#""b5f8-41c3""#);
+        assert_eq!(serde_json::from_str::<InternedId>(&json).unwrap(), uuid);
+        assert_eq!(
+            serde_json::from_str::<InternedId>("17").unwrap().as_str(),
+            "17"
+        );
+        assert_eq!(format!("{:>6}", id("ab")), "    ab");
+        assert_eq!(InternedId::from(17_u32), id("17"));
+    }
+}
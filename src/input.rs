@@ -1,73 +1,1503 @@
-use csv::{ReaderBuilder, Trim};
+use csv::{ByteRecord, Reader, ReaderBuilder, Trim};
 use serde::Deserialize;
-use std::fs::File;
+use serde_json::{value::RawValue, Value};
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom},
+    str::FromStr,
+    sync::Arc,
+};
 
 use crate::{
-    client::{ClientId, ClientsMap},
-    process::process_record,
-    transaction::{TransactionId, TransactionsMap},
+    amount::Amount,
+    cents::Money,
+    client::{ClientData, ClientId},
+    fraud::{self, FraudAction},
+    lenient::{self, DecimalSeparator},
+    pending::Parked,
+    pipeline::Pipelined,
+    policy::Policy,
+    process::{duplicate_with_handlers, process_record_with_handlers, Duplicate, Handlers},
+    record_type::{RecordType, RecordTypes},
+    rejects::Rejects,
+    report::{Outcome, Report},
+    review::ReviewQueue,
+    script::{self, Script},
+    sources::{Inputs, Location},
+    state::{Ledger, State, DEFAULT_TENANT},
+    transaction::{DepositData, TransactionData, TransactionId},
 };
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug)]
 pub struct InputRecord {
+    pub record_type: RecordType,
+    pub client: ClientId,
+    pub transaction: TransactionId,
+    pub amount: Option<Amount>,
+    // the sub-account, DEFAULT_ACCOUNT if there is none
+    pub account: Option<String>,
+    // the tenant, DEFAULT_TENANT if there is none
+    pub tenant: Option<String>,
+    // when the transaction happened, in seconds since the Unix epoch
+    pub time: Option<u64>,
+}
+
+/*
+A record as it is read, before its type is parsed and its amount checked
+against the type. The type stays a string here rather than a `RecordType`, so
+an unknown type is rejected with its own reason instead of as a record that
+couldn't be parsed.
+*/
+#[derive(Deserialize)]
+pub struct RawInputRecord {
     #[serde(rename(deserialize = "type"))]
     pub record_type: String,
     pub client: ClientId,
     #[serde(rename(deserialize = "tx"))]
     pub transaction: TransactionId,
     pub amount: Option<String>,
+    #[serde(default)]
+    pub account: Option<String>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+    #[serde(default)]
+    pub time: Option<u64>,
+}
+
+impl TryFrom<RawInputRecord> for InputRecord {
+    type Error = &'static str;
+
+    fn try_from(record: RawInputRecord) -> Result<Self, Self::Error> {
+        let record_type = RecordTypes::current().parse(&record.record_type)?;
+        record_type.check_amount(record.amount.as_deref())?;
+
+        Ok(Self {
+            record_type,
+            client: record.client,
+            transaction: record.transaction,
+            amount: record.amount.map(Amount::from),
+            account: record.account,
+            tenant: record.tenant,
+            time: record.time,
+        })
+    }
 }
 
+/*
+Same shape as RawInputRecord, but JSON amounts may be given as strings or
+numbers.
+*/
+#[derive(Deserialize)]
+struct JsonInputRecord {
+    #[serde(rename(deserialize = "type"))]
+    record_type: String,
+    client: ClientId,
+    #[serde(rename(deserialize = "tx"))]
+    transaction: TransactionId,
+    #[serde(default)]
+    amount: Value,
+    #[serde(default)]
+    account: Option<String>,
+    #[serde(default)]
+    tenant: Option<String>,
+    #[serde(default)]
+    time: Option<u64>,
+}
+
+impl TryFrom<JsonInputRecord> for InputRecord {
+    type Error = &'static str;
+
+    fn try_from(record: JsonInputRecord) -> Result<Self, Self::Error> {
+        let amount = match record.amount {
+            Value::Null => None,
+            Value::String(s) => Some(s),
+            // arbitrary_precision keeps the number exactly as it was written
+            Value::Number(n) => Some(n.to_string()),
+            _ => return Err("Amount must be a string or a number"),
+        };
+
+        RawInputRecord {
+            record_type: record.record_type,
+            client: record.client,
+            transaction: record.transaction,
+            amount,
+            account: record.account,
+            tenant: record.tenant,
+            time: record.time,
+        }
+        .try_into()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InputFormat {
+    Csv,
+    Json,
+    Ndjson,
+}
+
+impl FromStr for InputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            _ => Err("Unsupported input format, expected csv, json or ndjson"),
+        }
+    }
+}
+
+/*
+Records paired with the line they started on, for error reporting.
+*/
+pub type Records = Box<dyn Iterator<Item = (u32, Result<InputRecord, &'static str>)>>;
+
+const PARSE_ERROR: &str = "error parsing input";
+
+pub const STRICT_STOP: &str = "Stopped at the first rejected record (strict mode)";
+
+/*
+How records are applied, from the command line and the policy file.
+With `lenient` set amounts are normalized first, see `lenient::normalize`.
+With a `pending_window` disputes, resolves and chargebacks of unknown
+transactions wait for up to that many records, see `Pending`.
+With a `script` its hooks run for every record, see `Script`.
+Records are applied by the `handlers` of their types, see `Handlers`.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ApplyOptions {
+    pub lenient: Option<DecimalSeparator>,
+    pub policy: Policy,
+    pub pending_window: u64,
+    pub script: Option<Arc<Script>>,
+    pub handlers: Handlers,
+}
+
+/*
+Processes every record in a single input file.
+Records that break a fraud rule are only logged to stderr.
+*/
 pub fn process_input_file(
     filename: &str,
-    clients: &mut ClientsMap,
-    transactions: &mut TransactionsMap,
+    format: InputFormat,
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: &mut Rejects,
+    strict: bool,
 ) -> Result<(), &'static str> {
-    let file = File::open(filename).map_err(|_| "Failed to open input file")?;
+    let inputs = Inputs::single(filename, format);
+    let mut review = ReviewQueue::new();
+    process_inputs(
+        &inputs,
+        options,
+        state,
+        report,
+        rejects,
+        &mut review,
+        strict,
+    )
+}
 
-    let mut reader = ReaderBuilder::new()
-        // have to accept whitespace
-        .trim(Trim::All)
-        .from_reader(file);
+/*
+Processes every record in the inputs.
+In strict mode processing stops with an error at the first rejected record.
+*/
+pub fn process_inputs(
+    inputs: &Inputs,
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: &mut Rejects,
+    review: &mut ReviewQueue,
+    strict: bool,
+) -> Result<(), &'static str> {
+    for (location, result) in inputs.read()? {
+        // the report also has the records applied from the pending buffer
+        let _ = apply_record(&location, &result, options, state, report, rejects, review)?;
+        if strict && report.outcome() == Outcome::Rejections {
+            return Err(STRICT_STOP);
+        }
+    }
 
-    // line 1 is the header, data starts at line 2
-    let mut line: u32 = 1;
+    finish_input(options, state, report, rejects, review)?;
+    if strict && report.outcome() == Outcome::Rejections {
+        return Err(STRICT_STOP);
+    }
 
-    for result in reader.deserialize() {
-        line += 1;
+    Ok(())
+}
+
+/*
+Applies a single record read from the input and records the outcome in the
+report, rejects and review queue.
+The outer result is an error if the rejects or review queue could not be
+written, the inner result is the outcome of the record, which is None if the
+record is pending.
+*/
+pub fn apply_record(
+    location: &Location,
+    result: &Result<InputRecord, &'static str>,
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: &mut Rejects,
+    review: &mut ReviewQueue,
+) -> Result<Result<Option<Money>, &'static str>, &'static str> {
+    state.fraud.tick();
+    if options.pending_window > 0 {
+        state.pending.tick();
+        let expired = state.pending.expire(options.pending_window);
+        apply_parked(expired, false, options, state, report, rejects, review)?;
+    }
+
+    let record = match result {
+        Ok(r) => r,
+        Err(error) => {
+            report.unparsed(error);
+            rejects.reject(location, None, error)?;
+            return Ok(Err(error));
+        }
+    };
+
+    let tenant = record.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    if options.pending_window > 0
+        && options
+            .handlers
+            .refers_to_deposit(record.record_type.name())
+    {
+        let known = state
+            .ledger(tenant)
+            .is_some_and(|l| l.transactions.contains_key(&record.transaction));
+        if !known {
+            state.pending.park(tenant, location.clone(), record.clone());
+            report.parked();
+            return Ok(Ok(None));
+        }
+    }
+
+    let outcome = match (options.lenient, &record.amount) {
+        (Some(separator), Some(amount)) => match lenient::normalize(amount, separator) {
+            Ok((normalized, changes)) => {
+                if !changes.is_empty() {
+                    eprintln!("{}: read amount {:?} as {}", location, amount, normalized);
+                    report.normalized(&changes);
+                }
+                let normalized = InputRecord {
+                    amount: Some(normalized.into()),
+                    ..record.clone()
+                };
+                apply(
+                    location,
+                    &normalized,
+                    options,
+                    state,
+                    report,
+                    rejects,
+                    review,
+                )?
+            }
+            Err(error) => Err(error),
+        },
+        _ => apply(location, record, options, state, report, rejects, review)?,
+    };
+    match outcome {
+        Ok(applied) => {
+            count_applied(report, record, applied);
+            // whatever the handler, the records waiting for the transaction
+            // can be applied once it exists
+            let added = state
+                .ledger(tenant)
+                .is_some_and(|l| l.transactions.contains_key(&record.transaction));
+            if options.pending_window > 0 && added {
+                let parked = state.pending.take(tenant, record.transaction);
+                apply_parked(parked, true, options, state, report, rejects, review)?;
+            }
+        }
+        Err(error) => {
+            report.rejected(record, error);
+            rejects.reject(location, Some(record), error)?;
+        }
+    }
+
+    Ok(outcome.map(|applied| Some(applied.amount())))
+}
+
+/*
+A record that wasn't rejected: it moved an amount, or was skipped without
+changing anything because it reused a transaction id.
+*/
+#[derive(Clone, Copy)]
+enum Applied {
+    Amount(Money),
+    Duplicate(Duplicate),
+}
+
+impl Applied {
+    fn amount(self) -> Money {
+        match self {
+            Self::Amount(amount) => amount,
+            Self::Duplicate(_) => Money::ZERO,
+        }
+    }
+}
+
+fn count_applied(report: &mut Report, record: &InputRecord, applied: Applied) {
+    match applied {
+        Applied::Amount(amount) => report.applied(record, amount),
+        Applied::Duplicate(duplicate) => report.duplicate(record, duplicate),
+    }
+}
+
+/*
+Applies the records still pending at the end of the input, which fail because
+their transaction never arrived.
+*/
+pub fn finish_input(
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: &mut Rejects,
+    review: &mut ReviewQueue,
+) -> Result<(), &'static str> {
+    let parked = state.pending.drain();
+    apply_parked(parked, false, options, state, report, rejects, review)
+}
+
+/*
+Applies a record, unless a fraud rule holds or rejects it or a script vetoes
+it. Every fraud rule the record breaks and every tag a script gives it is
+counted in the report and written to the review queue. Side records a script
+emits are applied right after the record, if it was applied. A record whose
+after_apply hook failed stays applied, without the tags and side records of
+that hook, and is counted and written to the review queue as well.
+The outer result is an error if the rejects or review queue could not be
+written.
+*/
+fn apply(
+    location: &Location,
+    record: &InputRecord,
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: &mut Rejects,
+    review: &mut ReviewQueue,
+) -> Result<Result<Applied, &'static str>, &'static str> {
+    let tenant = record.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    let fraud = &options.policy.fraud;
+
+    let mut new_client = false;
+    if !fraud.is_empty() {
+        let client = state
+            .ledger(tenant)
+            .and_then(|l| l.clients.get(&record.client));
+        new_client = client.is_none();
+        let available = match client.map_or(Ok(Money::ZERO), ClientData::get_available) {
+            Ok(a) => a,
+            Err(error) => return Ok(Err(error)),
+        };
 
-        let record: InputRecord = match result {
+        let mut action = None;
+        for rule in state.fraud.check(fraud, tenant, record, available) {
+            report.fraud_hit(&rule.name);
+            review.flag(location, record, rule)?;
+            action = action.max(Some(rule.action));
+        }
+        match action {
+            Some(FraudAction::Hold) => return Ok(Err(fraud::HELD)),
+            Some(FraudAction::Reject) => return Ok(Err(fraud::REJECTED)),
+            Some(FraudAction::Flag) | None => {}
+        }
+    }
+
+    let mut emitted = Vec::new();
+    if let Some(script) = &options.script {
+        let (client, deposit) = hook_data(options, state.ledger(tenant), record);
+        let result = match script.before_apply(record, client, deposit) {
             Ok(r) => r,
-            Err(_) => {
-                eprintln!("line {}: error parsing input", line);
-                continue;
+            Err(error) => {
+                eprintln!("{}: before_apply: {}", location, error);
+                return Ok(Err(script::FAILED));
             }
         };
+        for tag in &result.tags {
+            report.tagged(tag);
+            review.tag(location, record, tag)?;
+        }
+        if let Some(reason) = result.veto {
+            return Ok(Err(reason));
+        }
+        emitted = result.emit;
+    }
+
+    let rules = options.policy.rules_for(tenant);
+    let ledger = state.ledger_mut(tenant);
+    let duplicate = duplicate_with_handlers(record, rules, &options.handlers, &ledger.transactions);
+    let outcome = match process_record_with_handlers(
+        record,
+        rules,
+        &options.handlers,
+        &mut ledger.clients,
+        &mut ledger.transactions,
+    ) {
+        Ok(amount) => match duplicate {
+            Some(duplicate) => Applied::Duplicate(duplicate),
+            None => Applied::Amount(amount),
+        },
+        Err(error) => return Ok(Err(error)),
+    };
+    if !fraud.is_empty() {
+        state.fraud.record(fraud, tenant, record, new_client);
+    }
 
-        if let Err(error) = process_record(&record, clients, transactions) {
-            eprintln!("line {}: {}", line, error);
+    if let Some(script) = &options.script {
+        let (client, deposit) = hook_data(options, state.ledger(tenant), record);
+        match script.after_apply(record, client, deposit) {
+            Ok(result) => {
+                for tag in &result.tags {
+                    report.tagged(tag);
+                    review.tag(location, record, tag)?;
+                }
+                emitted.extend(result.emit);
+            }
+            Err(error) => {
+                eprintln!("{}: after_apply: {}", location, error);
+                report.hook_failed();
+                review.hook_failed(location, record, "after_apply")?;
+            }
+        }
+    }
+
+    for side in emitted {
+        apply_emitted(location, &side, options, state, report, rejects)?;
+    }
+
+    Ok(Ok(outcome))
+}
+
+/*
+The client of a record and the deposit it refers to, as the hooks of a script
+see them.
+*/
+fn hook_data<'a>(
+    options: &ApplyOptions,
+    ledger: Option<&'a Ledger>,
+    record: &InputRecord,
+) -> (Option<&'a ClientData>, Option<&'a DepositData>) {
+    let ledger = match ledger {
+        Some(l) => l,
+        None => return (None, None),
+    };
+
+    let deposit = match ledger.transactions.get(&record.transaction) {
+        Some(TransactionData::Deposit(deposit))
+            if options
+                .handlers
+                .refers_to_deposit(record.record_type.name()) =>
+        {
+            Some(deposit)
+        }
+        _ => None,
+    };
+    (ledger.clients.get(&record.client), deposit)
+}
+
+/*
+Applies a side record emitted by a script. Fraud rules and hooks don't run for
+side records, and they are reported at the location of the record that emitted
+them.
+*/
+fn apply_emitted(
+    location: &Location,
+    record: &InputRecord,
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: &mut Rejects,
+) -> Result<(), &'static str> {
+    let tenant = record.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    let rules = options.policy.rules_for(tenant);
+    let ledger = state.ledger_mut(tenant);
+
+    report.emitted();
+    let duplicate = duplicate_with_handlers(record, rules, &options.handlers, &ledger.transactions);
+    match process_record_with_handlers(
+        record,
+        rules,
+        &options.handlers,
+        &mut ledger.clients,
+        &mut ledger.transactions,
+    ) {
+        Ok(amount) => match duplicate {
+            Some(duplicate) => report.duplicate(record, duplicate),
+            None => report.applied(record, amount),
+        },
+        Err(error) => {
+            report.rejected(record, error);
+            rejects.reject(location, Some(record), error)?;
+        }
+    }
+    Ok(())
+}
+
+/*
+Applies records from the pending buffer, which were `matched` by the
+transaction they wait for or are given up on.
+*/
+fn apply_parked(
+    parked: Vec<Parked>,
+    matched: bool,
+    options: &ApplyOptions,
+    state: &mut State,
+    report: &mut Report,
+    rejects: &mut Rejects,
+    review: &mut ReviewQueue,
+) -> Result<(), &'static str> {
+    for Parked {
+        location, record, ..
+    } in parked
+    {
+        report.unparked(matched);
+        match apply(&location, &record, options, state, report, rejects, review)? {
+            Ok(applied) => count_applied(report, &record, applied),
+            Err(error) => {
+                report.rejected(&record, error);
+                rejects.reject(&location, Some(&record), error)?;
+            }
         }
     }
 
     Ok(())
 }
 
+/*
+Reads records from a file, or from stdin if the filename is "-".
+*/
+pub fn read_records(filename: &str, format: InputFormat) -> Result<Records, &'static str> {
+    let input = open_input(filename)?;
+
+    match format {
+        // stdin is read on this thread, so serve answers every record as soon
+        // as it arrives
+        InputFormat::Csv if filename == "-" => Ok(Box::new(read_csv(input)?)),
+        InputFormat::Csv => Ok(Box::new(Pipelined::new(read_csv(input)?))),
+        InputFormat::Json => read_json(input, parse_json_record),
+        InputFormat::Ndjson => read_ndjson(input, |l| l.and_then(parse_json_record)),
+    }
+}
+
+/*
+A record with the value of its merge column, see `sources::Inputs`.
+*/
+pub type KeyedRecord = (u32, Result<InputRecord, &'static str>, Option<String>);
+pub type KeyedRecords = Box<dyn Iterator<Item = KeyedRecord>>;
+
+/*
+Same as `read_records`, with the value of the `key` column of every record.
+A CSV input without that column is an error.
+*/
+pub fn read_keyed_records(
+    filename: &str,
+    format: InputFormat,
+    key: &str,
+) -> Result<KeyedRecords, &'static str> {
+    let input = open_input(filename)?;
+
+    let key = key.to_string();
+    let records = match format {
+        InputFormat::Csv => return read_keyed_csv(input, &key),
+        InputFormat::Json => read_json(input, move |s| (parse_json_record(s), json_key(s, &key)))?,
+        InputFormat::Ndjson => read_ndjson(input, move |l| {
+            let key = l.ok().and_then(|s| json_key(s, &key));
+            (l.and_then(parse_json_record), key)
+        })?,
+    };
+    Ok(Box::new(
+        records.map(|(line, (record, key))| (line, record, key)),
+    ))
+}
+
+/*
+A record with the byte offset where the record after it starts, see
+`checkpoint`.
+*/
+pub type OffsetRecord = (u32, Result<InputRecord, &'static str>, u64);
+pub type OffsetRecords = Box<dyn Iterator<Item = OffsetRecord>>;
+
+/*
+Same as `read_records` for a CSV or NDJSON file, which is read from `start`,
+the offset of a record and the line before it, or else from the beginning.
+JSON input has to be read as a whole, so it can't be started in the middle.
+*/
+pub fn read_records_from(
+    filename: &str,
+    format: InputFormat,
+    start: Option<(u64, u32)>,
+) -> Result<OffsetRecords, &'static str> {
+    match format {
+        InputFormat::Csv => read_csv_from(filename, start),
+        InputFormat::Ndjson => read_ndjson_from(filename, start),
+        InputFormat::Json => Err("Only csv and ndjson input can be read from an offset"),
+    }
+}
+
+fn open_input_at(filename: &str, offset: u64) -> Result<File, &'static str> {
+    let mut file = File::open(filename).map_err(|_| "Failed to open input file")?;
+    file.seek(SeekFrom::Start(offset))
+        .map_err(|_| "Failed to read input file")?;
+    Ok(file)
+}
+
+fn read_csv_from(filename: &str, start: Option<(u64, u32)>) -> Result<OffsetRecords, &'static str> {
+    // the header is always at the beginning
+    let mut header_reader = ReaderBuilder::new()
+        .trim(Trim::Headers)
+        .from_reader(open_input_at(filename, 0)?);
+    let columns = CsvColumns::new(
+        header_reader
+            .byte_headers()
+            .map_err(|_| "Failed to read input file")?,
+    );
+    let (offset, mut line) = start.unwrap_or((header_reader.position().byte(), 1));
+
+    let mut reader = ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(open_input_at(filename, offset)?);
+    let mut row = ByteRecord::new();
+    let mut failed = false;
+
+    let records = std::iter::from_fn(move || {
+        let result =
+            read_csv_row(&mut reader, &mut row, &mut failed)?.and_then(|_| columns.parse(&row));
+        line += 1;
+        // positions are relative to where the reader started
+        Some((line, result, offset + reader.position().byte()))
+    });
+
+    Ok(Box::new(Pipelined::new(records)))
+}
+
+fn read_ndjson_from(
+    filename: &str,
+    start: Option<(u64, u32)>,
+) -> Result<OffsetRecords, &'static str> {
+    let (mut offset, mut line) = start.unwrap_or((0, 0));
+    let mut reader = BufReader::new(open_input_at(filename, offset)?);
+    let mut buffer = String::new();
+    let mut failed = false;
+
+    let records = std::iter::from_fn(move || loop {
+        if failed {
+            return None;
+        }
+        buffer.clear();
+        line += 1;
+        match reader.read_line(&mut buffer) {
+            Ok(0) => return None,
+            Ok(n) => offset += n as u64,
+            Err(_) => {
+                failed = true;
+                return Some((line, Err("Failed to read input line"), offset));
+            }
+        }
+
+        // blank lines are skipped but still counted
+        if !buffer.trim().is_empty() {
+            return Some((line, parse_json_record(&buffer), offset));
+        }
+    });
+
+    Ok(Box::new(records))
+}
+
+fn open_input(filename: &str) -> Result<Box<dyn Read + Send>, &'static str> {
+    if filename == "-" {
+        Ok(Box::new(io::stdin()))
+    } else {
+        let file = File::open(filename).map_err(|_| "Failed to open input file")?;
+        Ok(Box::new(file))
+    }
+}
+
+/*
+Every row is read into the same ByteRecord and parsed from its bytes, see
+`CsvColumns`.
+*/
+fn read_csv<R: Read + Send + 'static>(
+    input: R,
+) -> Result<impl Iterator<Item = (u32, Result<InputRecord, &'static str>)> + Send, &'static str> {
+    let mut reader = ReaderBuilder::new()
+        // fields are trimmed by CsvColumns
+        .trim(Trim::Headers)
+        .from_reader(input);
+    let columns = CsvColumns::new(
+        reader
+            .byte_headers()
+            .map_err(|_| "Failed to read input file")?,
+    );
+    let mut row = ByteRecord::new();
+    let mut failed = false;
+
+    // line 1 is the header, data starts at line 2
+    let mut line = 1;
+    let records = std::iter::from_fn(move || {
+        let result =
+            read_csv_row(&mut reader, &mut row, &mut failed)?.and_then(|_| columns.parse(&row));
+        line += 1;
+        Some((line, result))
+    });
+
+    Ok(records)
+}
+
+/*
+Reads the next row into `row`, None at the end of the input. A row that
+isn't valid CSV is an error, and so is the first failed read, which also ends
+the input.
+*/
+fn read_csv_row<R: Read>(
+    reader: &mut Reader<R>,
+    row: &mut ByteRecord,
+    failed: &mut bool,
+) -> Option<Result<(), &'static str>> {
+    if *failed {
+        return None;
+    }
+    match reader.read_byte_record(row) {
+        Ok(true) => Some(Ok(())),
+        Ok(false) => None,
+        Err(error) => {
+            *failed = error.is_io_error();
+            Some(Err(PARSE_ERROR))
+        }
+    }
+}
+
+/*
+Same as `read_csv`, with the value of the `key` column of every record.
+*/
+fn read_keyed_csv(input: Box<dyn Read + Send>, key: &str) -> Result<KeyedRecords, &'static str> {
+    let mut reader = ReaderBuilder::new().trim(Trim::Headers).from_reader(input);
+    let headers = reader
+        .byte_headers()
+        .map_err(|_| "Failed to read input file")?;
+    let column = headers
+        .iter()
+        .position(|h| h == key.as_bytes())
+        .ok_or("The merge column is missing from an input file")?;
+    let columns = CsvColumns::new(headers);
+    let mut row = ByteRecord::new();
+    let mut failed = false;
+
+    let mut line = 1;
+    let records = std::iter::from_fn(move || {
+        let result = read_csv_row(&mut reader, &mut row, &mut failed)?;
+        line += 1;
+        Some(match result {
+            Ok(()) => {
+                let key = row
+                    .get(column)
+                    .and_then(|k| std::str::from_utf8(k).ok())
+                    .map(|k| k.trim().to_string());
+                (line, columns.parse(&row), key)
+            }
+            Err(error) => (line, Err(error), None),
+        })
+    });
+
+    Ok(Box::new(records))
+}
+
+/*
+One JSON object per line. Blank lines are skipped but still counted.
+Reading stops after the first line that can't be read, which would otherwise
+fail the same way forever, e.g. for a directory.
+*/
+fn read_ndjson<T: 'static>(
+    input: Box<dyn Read + Send>,
+    parse: impl Fn(Result<&str, &'static str>) -> T + 'static,
+) -> Result<Box<dyn Iterator<Item = (u32, T)>>, &'static str> {
+    let mut failed = false;
+    let records = BufReader::new(input)
+        .lines()
+        .zip(1..)
+        .take_while(move |(result, _)| {
+            let more = !failed;
+            failed = result.is_err();
+            more
+        })
+        .filter(|(result, _)| !matches!(result, Ok(l) if l.trim().is_empty()))
+        .map(move |(result, line)| {
+            let record = match &result {
+                Ok(l) => parse(Ok(l)),
+                Err(_) => parse(Err("Failed to read input line")),
+            };
+            (line, record)
+        });
+
+    Ok(Box::new(records))
+}
+
+/*
+A single JSON array of objects.
+The whole file has to be read to find where each element starts.
+*/
+fn read_json<T: 'static>(
+    mut reader: Box<dyn Read + Send>,
+    parse: impl Fn(&str) -> T,
+) -> Result<Box<dyn Iterator<Item = (u32, T)>>, &'static str> {
+    let mut input = String::new();
+    reader
+        .read_to_string(&mut input)
+        .map_err(|_| "Failed to read input file")?;
+
+    let elements: Vec<&RawValue> =
+        serde_json::from_str(&input).map_err(|_| "Input is not a JSON array")?;
+
+    let mut records = Vec::with_capacity(elements.len());
+
+    // count newlines incrementally, elements are in file order
+    let mut line: u32 = 1;
+    let mut counted = 0;
+
+    for element in elements {
+        let offset = element.get().as_ptr() as usize - input.as_ptr() as usize;
+        line += input[counted..offset].matches('\n').count() as u32;
+        counted = offset;
+
+        records.push((line, parse(element.get())));
+    }
+
+    Ok(Box::new(records.into_iter()))
+}
+
+/*
+Where the fields of an InputRecord are in the rows of a CSV input, found once
+from its header. Rows are parsed straight from the bytes of a ByteRecord, the
+only allocations are for the account and tenant of a record that has them;
+the amount is kept inline, see `Amount`.
+Like deserializing a RawInputRecord, every row is an error if the header has
+no type, client or tx column, or has one of the columns more than once. Empty
+fields are missing, other columns are ignored.
+*/
+struct CsvColumns {
+    record_type: Option<usize>,
+    client: Option<usize>,
+    transaction: Option<usize>,
+    amount: Option<usize>,
+    account: Option<usize>,
+    tenant: Option<usize>,
+    time: Option<usize>,
+    duplicate: bool,
+}
+
+impl CsvColumns {
+    fn new(headers: &ByteRecord) -> Self {
+        let names = [
+            "type", "client", "tx", "amount", "account", "tenant", "time",
+        ];
+        let duplicate = names
+            .iter()
+            .any(|name| headers.iter().filter(|h| h == &name.as_bytes()).count() > 1);
+        let column = |name: &str| headers.iter().position(|h| h == name.as_bytes());
+
+        Self {
+            record_type: column("type"),
+            client: column("client"),
+            transaction: column("tx"),
+            amount: column("amount"),
+            account: column("account"),
+            tenant: column("tenant"),
+            time: column("time"),
+            duplicate,
+        }
+    }
+
+    fn parse(&self, row: &ByteRecord) -> Result<InputRecord, &'static str> {
+        if self.duplicate {
+            return Err(PARSE_ERROR);
+        }
+
+        // fields that can't be read come before the checks of the type
+        let name = match self.record_type.and_then(|c| row.get(c)) {
+            Some(name) => std::str::from_utf8(name).map_err(|_| PARSE_ERROR)?.trim(),
+            None => return Err(PARSE_ERROR),
+        };
+        let client = csv_value(row, self.client)?.ok_or(PARSE_ERROR)?;
+        let transaction = csv_value(row, self.transaction)?.ok_or(PARSE_ERROR)?;
+        let amount = csv_field(row, self.amount)?.map(Amount::from);
+        let account = csv_field(row, self.account)?.map(str::to_string);
+        let tenant = csv_field(row, self.tenant)?.map(str::to_string);
+        let time = csv_value(row, self.time)?;
+
+        let record_type = RecordTypes::current().parse(name)?;
+        record_type.check_amount(amount.as_deref())?;
+
+        Ok(InputRecord {
+            record_type,
+            client,
+            transaction,
+            amount,
+            account,
+            tenant,
+            time,
+        })
+    }
+}
+
+/*
+The field in a column, trimmed like by a reader with Trim::All, which would
+allocate a new record for every row. An empty field is missing.
+*/
+fn csv_field(row: &ByteRecord, column: Option<usize>) -> Result<Option<&str>, &'static str> {
+    let field = match column.and_then(|c| row.get(c)) {
+        Some(f) => std::str::from_utf8(f).map_err(|_| PARSE_ERROR)?.trim(),
+        None => return Ok(None),
+    };
+    Ok((!field.is_empty()).then_some(field))
+}
+
+fn csv_value<T: FromStr>(
+    row: &ByteRecord,
+    column: Option<usize>,
+) -> Result<Option<T>, &'static str> {
+    csv_field(row, column)?
+        .map(|s| s.parse().map_err(|_| PARSE_ERROR))
+        .transpose()
+}
+
+fn parse_json_record(s: &str) -> Result<InputRecord, &'static str> {
+    serde_json::from_str::<JsonInputRecord>(s)
+        .map_err(|_| PARSE_ERROR)?
+        .try_into()
+}
+
+/*
+The value of the `key` field of a JSON object, as written.
+*/
+fn json_key(s: &str, key: &str) -> Option<String> {
+    let mut object = serde_json::from_str::<HashMap<String, Value>>(s).ok()?;
+    match object.remove(key)? {
+        Value::String(s) => Some(s),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::{client::ClientsMap, input::process_input_file, transaction::TransactionsMap};
+    use crate::{
+        amount::Amount,
+        cents::Money,
+        client::{client_id, LockScope},
+        input::{
+            apply_record, process_input_file, read_records, ApplyOptions, InputFormat, InputRecord,
+        },
+        lenient::DecimalSeparator,
+        policy::Policy,
+        process::{DepositHandler, DuplicateTx, Handlers, Rules},
+        record_type::RecordType,
+        rejects::Rejects,
+        report::Report,
+        review::ReviewQueue,
+        sources::Location,
+        state::{State, DEFAULT_TENANT},
+        transaction::tx_id,
+    };
+    use std::{env, fs};
 
     #[test]
     fn single_deposit() {
         let filename = "test_data/single_deposit.csv";
-        let mut clients = ClientsMap::new();
-        let mut transactions = TransactionsMap::new();
+        let mut state = State::new();
+
+        assert!(process_input_file(
+            filename,
+            InputFormat::Csv,
+            &ApplyOptions::default(),
+            &mut state,
+            &mut Report::new(),
+            &mut Rejects::new(),
+            false,
+        )
+        .is_ok());
+
+        assert_eq!(state.ledger(DEFAULT_TENANT).unwrap().clients.len(), 1);
+        assert_eq!(state.ledger(DEFAULT_TENANT).unwrap().transactions.len(), 1);
+
+        let client = state
+            .ledger(DEFAULT_TENANT)
+            .unwrap()
+            .clients
+            .get(&client_id(1))
+            .unwrap();
+        assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
+    }
+
+    #[test]
+    fn ndjson() {
+        let records: Vec<_> = read_records("test_data/transactions.ndjson", InputFormat::Ndjson)
+            .unwrap()
+            .collect();
+
+        // blank line 3 is skipped
+        let lines: Vec<_> = records.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![1, 2, 4, 5]);
+
+        // string amount
+        let record = records[0].1.as_ref().unwrap();
+        assert_eq!(record.record_type, RecordType::Deposit);
+        assert_eq!(record.amount.as_deref(), Some("1.5"));
+
+        // number amount keeps its digits
+        let record = records[1].1.as_ref().unwrap();
+        assert_eq!(record.amount.as_deref(), Some("2.0100"));
+
+        // no amount
+        let record = records[2].1.as_ref().unwrap();
+        assert_eq!(record.record_type, RecordType::Dispute);
+        assert_eq!(record.amount.as_deref(), None);
+
+        // invalid
+        assert!(records[3].1.is_err());
+    }
+
+    #[test]
+    fn ndjson_read_error() {
+        // a directory can be opened but not read
+        let records: Vec<_> = read_records("test_data", InputFormat::Ndjson)
+            .unwrap()
+            .collect();
+        assert_eq!(records.len(), 1);
+        assert!(records[0].1.is_err());
+    }
+
+    #[test]
+    fn record_types() {
+        let records: Vec<_> = read_records("test_data/record_types.csv", InputFormat::Csv)
+            .unwrap()
+            .map(|(_, record)| record.map(|r| r.record_type))
+            .collect();
+
+        // types are read case-insensitively, amounts are checked per type
+        assert_eq!(
+            records,
+            vec![
+                Ok(RecordType::Deposit),
+                Err("Deposits need an amount"),
+                Err("Disputes can't have an amount"),
+                Err("Unknown record type"),
+            ]
+        );
+    }
+
+    #[test]
+    fn csv_fields() {
+        let records: Vec<_> = read_records("test_data/csv_fields.csv", InputFormat::Csv)
+            .unwrap()
+            .collect();
+
+        let lines: Vec<_> = records.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 5, 6, 7, 8]);
 
-        assert!(process_input_file(&filename, &mut clients, &mut transactions).is_ok());
+        // columns in any order, trimmed, other columns are ignored
+        let record = records[0].1.as_ref().unwrap();
+        assert_eq!(record.record_type, RecordType::Deposit);
+        assert_eq!(record.client, client_id(1));
+        assert_eq!(record.transaction, tx_id(1));
+        assert_eq!(record.amount.as_deref(), Some("1.5"));
+        assert_eq!(record.account, Some("savings".to_string()));
+        assert_eq!(record.tenant, Some("acme".to_string()));
+        assert_eq!(record.time, Some(100));
 
-        assert_eq!(clients.len(), 1);
-        assert_eq!(transactions.len(), 1);
+        // empty fields are missing
+        let record = records[1].1.as_ref().unwrap();
+        assert_eq!(
+            (&record.account, &record.tenant, record.time),
+            (&None, &None, None)
+        );
+        let record = records[2].1.as_ref().unwrap();
+        assert_eq!(record.record_type, RecordType::Dispute);
+        assert_eq!(record.amount.as_deref(), None);
+
+        // a missing id and rows with too few or too many fields
+        for (_, record) in &records[3..6] {
+            assert_eq!(record.as_ref().err(), Some(&"error parsing input"));
+        }
+        assert!(records[6].1.is_ok());
+    }
+
+    #[test]
+    fn csv_headers() {
+        let dir = env::temp_dir();
+        for (name, header) in [
+            ("missing", "type,client,amount"),
+            ("duplicate", "type,client,tx,amount,amount"),
+        ] {
+            let filename = dir.join(format!("transaction_processor_header_{}.csv", name));
+            let row = ",1.0".repeat(header.split(',').count() - 2);
+            fs::write(&filename, format!("{}\ndeposit{}\n", header, row)).unwrap();
+
+            let records: Vec<_> = read_records(filename.to_str().unwrap(), InputFormat::Csv)
+                .unwrap()
+                .map(|(line, record)| (line, record.err()))
+                .collect();
+            assert_eq!(records, vec![(2, Some("error parsing input"))]);
+        }
+    }
+
+    #[test]
+    fn csv_order() {
+        // several batches of the reader thread
+        let filename = env::temp_dir().join("transaction_processor_order.csv");
+        let mut input = "type,client,tx,amount\n".to_string();
+        for tx in 1..=5000 {
+            input.push_str(&format!("deposit,1,{},1.0\n", tx));
+        }
+        fs::write(&filename, input).unwrap();
+
+        let records = read_records(filename.to_str().unwrap(), InputFormat::Csv).unwrap();
+        let mut count = 0;
+        for ((line, record), tx) in records.zip(1..) {
+            assert_eq!(line, tx + 1);
+            assert_eq!(record.unwrap().transaction.to_string(), tx.to_string());
+            count += 1;
+        }
+        assert_eq!(count, 5000);
+    }
+
+    #[test]
+    fn json() {
+        let records: Vec<_> = read_records("test_data/transactions.json", InputFormat::Json)
+            .unwrap()
+            .collect();
+
+        let lines: Vec<_> = records.iter().map(|(line, _)| *line).collect();
+        assert_eq!(lines, vec![2, 3, 4, 9]);
+
+        let record = records[0].1.as_ref().unwrap();
+        assert_eq!(record.amount.as_deref(), Some("1.5"));
+
+        let record = records[1].1.as_ref().unwrap();
+        assert_eq!(record.amount.as_deref(), Some("2.0100"));
+
+        // object spanning several lines
+        let record = records[2].1.as_ref().unwrap();
+        assert_eq!(record.record_type, RecordType::Dispute);
+        assert_eq!(record.amount.as_deref(), None);
+
+        // amount of the wrong type
+        assert!(records[3].1.is_err());
+    }
+
+    #[test]
+    fn json_not_an_array() {
+        assert!(read_records("test_data/transactions.ndjson", InputFormat::Json).is_err());
+    }
+
+    #[test]
+    fn same_result_for_all_formats() {
+        for (filename, format) in [
+            ("test_data/transactions.csv", InputFormat::Csv),
+            ("test_data/transactions.json", InputFormat::Json),
+            ("test_data/transactions.ndjson", InputFormat::Ndjson),
+        ] {
+            let mut state = State::new();
+
+            assert!(process_input_file(
+                filename,
+                format,
+                &ApplyOptions::default(),
+                &mut state,
+                &mut Report::new(),
+                &mut Rejects::new(),
+                false,
+            )
+            .is_ok());
+
+            let client = state
+                .ledger(DEFAULT_TENANT)
+                .unwrap()
+                .clients
+                .get(&client_id(1))
+                .unwrap();
+            assert_eq!(client.get_available(), Ok(Money::from_cents(2_0100)));
+            assert_eq!(client.get_held(), Ok(Money::from_cents(1_5000)));
+        }
+    }
+
+    #[test]
+    fn strict() {
+        let mut state = State::new();
+
+        // line 5 can't be parsed
+        assert!(process_input_file(
+            "test_data/transactions.csv",
+            InputFormat::Csv,
+            &ApplyOptions::default(),
+            &mut state,
+            &mut Report::new(),
+            &mut Rejects::new(),
+            true,
+        )
+        .is_err());
+
+        // everything before the rejected record was applied
+        assert_eq!(state.ledger(DEFAULT_TENANT).unwrap().transactions.len(), 2);
+    }
+
+    #[test]
+    fn lenient() {
+        let mut state = State::new();
+        let mut report = Report::new();
+
+        assert!(process_input_file(
+            "test_data/lenient.csv",
+            InputFormat::Csv,
+            &ApplyOptions {
+                lenient: Some(DecimalSeparator::Dot),
+                ..Default::default()
+            },
+            &mut state,
+            &mut report,
+            &mut Rejects::new(),
+            false,
+        )
+        .is_ok());
+
+        // $1,000.50 + 1e3 - +0.5, the ambiguous 1,5 is rejected
+        let client = state
+            .ledger(DEFAULT_TENANT)
+            .unwrap()
+            .clients
+            .get(&client_id(1))
+            .unwrap();
+        assert_eq!(client.get_available(), Ok(Money::from_cents(2000_0000)));
+        assert_eq!(state.ledger(DEFAULT_TENANT).unwrap().transactions.len(), 3);
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["normalized"]["currency symbol"], 1);
+        assert_eq!(json["normalized"]["thousands separators"], 1);
+        assert_eq!(json["normalized"]["exponent"], 1);
+        assert_eq!(json["normalized"]["plus sign"], 1);
+    }
+
+    #[test]
+    fn tenants() {
+        let mut state = State::new();
+        let options = ApplyOptions {
+            policy: Policy {
+                tenants: [(
+                    "acme".to_string(),
+                    Rules {
+                        lock_scope: LockScope::Account,
+                        ..Default::default()
+                    },
+                )]
+                .into(),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert!(process_input_file(
+            "test_data/tenants.csv",
+            InputFormat::Csv,
+            &options,
+            &mut state,
+            &mut Report::new(),
+            &mut Rejects::new(),
+            false,
+        )
+        .is_ok());
+
+        // the same client and tx ids in every tenant
+        assert_eq!(state.tenants.len(), 3);
+        assert!(state.has_tenants());
+
+        let client = state
+            .ledger(DEFAULT_TENANT)
+            .unwrap()
+            .clients
+            .get(&client_id(1))
+            .unwrap();
+        assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
+        assert!(!client.is_locked());
+
+        // the chargeback only locks the sub-account with acme's rules
+        let client = state
+            .ledger("acme")
+            .unwrap()
+            .clients
+            .get(&client_id(1))
+            .unwrap();
+        assert_eq!(client.get_total(), Ok(Money::from_cents(0)));
+        assert!(!client.is_client_locked());
+
+        let client = state
+            .ledger("globex")
+            .unwrap()
+            .clients
+            .get(&client_id(1))
+            .unwrap();
+        assert_eq!(client.get_total(), Ok(Money::from_cents(0)));
+        assert!(client.is_client_locked());
+    }
+
+    #[test]
+    fn pending() {
+        let mut state = State::new();
+        let mut report = Report::new();
+
+        assert!(process_input_file(
+            "test_data/pending.csv",
+            InputFormat::Csv,
+            &ApplyOptions {
+                pending_window: 2,
+                ..Default::default()
+            },
+            &mut state,
+            &mut report,
+            &mut Rejects::new(),
+            false,
+        )
+        .is_ok());
+
+        // the first dispute waited for its deposit, the chargeback of tx 2
+        // gave up two records before tx 2 arrived and tx 9 never did
+        let client = state
+            .ledger(DEFAULT_TENANT)
+            .unwrap()
+            .clients
+            .get(&client_id(1))
+            .unwrap();
+        assert_eq!(client.get_available(), Ok(Money::from_cents(7_0000)));
+        assert_eq!(client.get_held(), Ok(Money::from_cents(1_0000)));
+        assert!(state.pending.is_empty());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(
+            json["pending"],
+            serde_json::json!({"parked": 3, "matched": 1, "unmatched": 2})
+        );
+        assert_eq!(json["rejections"]["Transaction not found"], 2);
+
+        // without a window the first dispute is lost too
+        let mut report = Report::new();
+        assert!(process_input_file(
+            "test_data/pending.csv",
+            InputFormat::Csv,
+            &ApplyOptions::default(),
+            &mut State::new(),
+            &mut report,
+            &mut Rejects::new(),
+            false,
+        )
+        .is_ok());
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["rejections"]["Transaction not found"], 3);
+        assert!(json.get("pending").is_none());
+    }
+
+    #[test]
+    fn pending_custom_handler() {
+        // a custom type whose records are deposits
+        let mut handlers = Handlers::default();
+        handlers.register("bonus", DepositHandler);
+        let options = ApplyOptions {
+            pending_window: 2,
+            handlers,
+            ..Default::default()
+        };
+        let mut state = State::new();
+        let mut report = Report::new();
+
+        let record = |record_type: &str, amount: Option<&str>| InputRecord {
+            record_type: RecordType::named(record_type),
+            client: client_id(1),
+            transaction: tx_id(1),
+            amount: amount.map(Amount::from),
+            account: None,
+            tenant: None,
+            time: None,
+        };
+        let records = [record("dispute", None), record("bonus", Some("5"))];
+        for (record, line) in records.into_iter().zip(1..) {
+            apply_record(
+                &Location::line(line),
+                &Ok(record),
+                &options,
+                &mut state,
+                &mut report,
+                &mut Rejects::new(),
+                &mut ReviewQueue::new(),
+            )
+            .unwrap()
+            .unwrap();
+        }
+
+        // the dispute waited for the bonus
+        let client = state
+            .ledger(DEFAULT_TENANT)
+            .unwrap()
+            .clients
+            .get(&client_id(1))
+            .unwrap();
+        assert_eq!(client.get_held(), Ok(Money::from_cents(5_0000)));
+        assert!(state.pending.is_empty());
+    }
+
+    #[test]
+    fn duplicates_not_applied() {
+        let options = ApplyOptions {
+            policy: Policy {
+                rules: Rules {
+                    duplicate_tx: DuplicateTx::Skip,
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut state = State::new();
+        let mut report = Report::new();
+
+        let deposit = |amount: &str| InputRecord {
+            record_type: RecordType::Deposit,
+            client: client_id(1),
+            transaction: tx_id(1),
+            amount: Some(Amount::from(amount)),
+            account: None,
+            tenant: None,
+            time: None,
+        };
+        // the deposit, a replay of it and a conflicting reuse of its id
+        let records = [deposit("1"), deposit("1.0"), deposit("2")];
+        for (record, line) in records.into_iter().zip(1..) {
+            let outcome = apply_record(
+                &Location::line(line),
+                &Ok(record),
+                &options,
+                &mut state,
+                &mut report,
+                &mut Rejects::new(),
+                &mut ReviewQueue::new(),
+            );
+            assert!(matches!(outcome, Ok(Ok(Some(_)))));
+        }
 
-        let client = clients.get(&1).unwrap();
-        assert_eq!(client.get_available(), 1_0000);
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["records"]["deposit"]["read"], 3);
+        assert_eq!(json["records"]["deposit"]["applied"], 1);
+        assert_eq!(json["records"]["deposit"]["replayed"], 1);
+        assert_eq!(json["records"]["deposit"]["skipped"], 1);
+        assert_eq!(
+            json["totals"]["deposited"],
+            Money::from_cents(1_0000).to_string()
+        );
     }
 }
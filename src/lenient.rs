@@ -0,0 +1,263 @@
+use std::str::FromStr;
+
+/*
+Which character separates the decimals in amounts from partner exports.
+The other one of . and , is then the thousands separator.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DecimalSeparator {
+    Dot,
+    Comma,
+}
+
+impl FromStr for DecimalSeparator {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dot" => Ok(Self::Dot),
+            "comma" => Ok(Self::Comma),
+            _ => Err("Unsupported decimal separator, expected dot or comma"),
+        }
+    }
+}
+
+const CURRENCY_SYMBOLS: [char; 4] = ['$', '€', '£', '¥'];
+
+// keeps a typo like 1e999999 from building a huge string
+const MAX_EXPONENT: i32 = 100;
+
+const FORMAT_ERROR: &str = "Failed to parse amount";
+
+/*
+Turns an amount like "$1,000.50", "+1.00", "1e3" or "1.234,56" into the plain
+form the amount parser accepts.
+Returns the plain amount and what was normalized, which is empty if the amount
+already was plain.
+Anything that could be read in more than one way (e.g. "1,5" with a decimal
+dot) is an error instead of a guess.
+*/
+pub fn normalize(
+    amount: &str,
+    separator: DecimalSeparator,
+) -> Result<(String, Vec<&'static str>), &'static str> {
+    let mut changes = Vec::new();
+
+    // the sign may come before or after the currency symbol
+    let (sign, rest) = take_sign(amount.trim());
+    let rest = match take_currency_symbol(rest) {
+        Some(r) => {
+            changes.push("currency symbol");
+            r
+        }
+        None => rest,
+    };
+    let (sign, rest) = match (sign, take_sign(rest)) {
+        (Some(_), (Some(_), _)) => return Err(FORMAT_ERROR),
+        (None, (s, rest)) | (s, (None, rest)) => (s, rest),
+    };
+    let negative = match sign {
+        Some('+') => {
+            changes.push("plus sign");
+            false
+        }
+        Some(_) => true,
+        None => false,
+    };
+
+    // exponent
+    let (mantissa, exponent) = match rest.split_once(['e', 'E']) {
+        Some((m, e)) => {
+            let e = e.parse::<i32>().map_err(|_| FORMAT_ERROR)?;
+            if e.abs() > MAX_EXPONENT {
+                return Err("Amount exponent is too large");
+            }
+            changes.push("exponent");
+            (m, e)
+        }
+        None => (rest, 0),
+    };
+
+    // separators
+    let (decimal_char, thousands_char) = match separator {
+        DecimalSeparator::Dot => ('.', ','),
+        DecimalSeparator::Comma => (',', '.'),
+    };
+    let (whole, decimals) = match mantissa.split_once(decimal_char) {
+        Some((w, d)) => {
+            if decimal_char == ',' {
+                changes.push("decimal comma");
+            }
+            (w, d)
+        }
+        None => (mantissa, ""),
+    };
+    if decimals.contains([decimal_char, thousands_char]) {
+        return Err(FORMAT_ERROR);
+    }
+    let whole = if whole.contains(thousands_char) {
+        changes.push("thousands separators");
+        ungroup(whole, thousands_char)?
+    } else {
+        whole.to_string()
+    };
+
+    if whole.is_empty() || !(whole.bytes().chain(decimals.bytes())).all(|b| b.is_ascii_digit()) {
+        return Err(FORMAT_ERROR);
+    }
+
+    let plain = shift_decimal_point(&whole, decimals, exponent);
+    Ok((
+        if negative {
+            format!("-{}", plain)
+        } else {
+            plain
+        },
+        changes,
+    ))
+}
+
+fn take_sign(s: &str) -> (Option<char>, &str) {
+    match s.chars().next() {
+        Some(c @ ('+' | '-')) => (Some(c), &s[1..]),
+        _ => (None, s),
+    }
+}
+
+fn take_currency_symbol(s: &str) -> Option<&str> {
+    s.strip_prefix(CURRENCY_SYMBOLS)
+        .or_else(|| s.strip_suffix(CURRENCY_SYMBOLS))
+        .map(str::trim)
+}
+
+/*
+Removes the thousands separators, which have to be exactly every three digits.
+*/
+fn ungroup(whole: &str, thousands_char: char) -> Result<String, &'static str> {
+    let mut groups = whole.split(thousands_char);
+    // split always returns at least one group
+    let first = groups.next().unwrap_or_default();
+    if first.is_empty() || first.len() > 3 {
+        return Err("Amount has ambiguous thousands separators");
+    }
+
+    let mut ungrouped = first.to_string();
+    for group in groups {
+        if group.len() != 3 {
+            return Err("Amount has ambiguous thousands separators");
+        }
+        ungrouped.push_str(group);
+    }
+    Ok(ungrouped)
+}
+
+/*
+Moves the decimal point of whole.decimals by exponent places.
+*/
+fn shift_decimal_point(whole: &str, decimals: &str, exponent: i32) -> String {
+    let digits = format!("{}{}", whole, decimals);
+    let point = whole.len() as i32 + exponent;
+
+    let (whole, decimals) = if point <= 0 {
+        let zeros = "0".repeat(point.unsigned_abs() as usize);
+        ("0".to_string(), format!("{}{}", zeros, digits))
+    } else if point as usize >= digits.len() {
+        let zeros = "0".repeat(point as usize - digits.len());
+        (format!("{}{}", digits, zeros), String::new())
+    } else {
+        let (w, d) = digits.split_at(point as usize);
+        (w.to_string(), d.to_string())
+    };
+
+    if decimals.is_empty() {
+        whole
+    } else {
+        format!("{}.{}", whole, decimals)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{normalize, DecimalSeparator};
+
+    fn dot(s: &str) -> Result<(String, Vec<&'static str>), &'static str> {
+        normalize(s, DecimalSeparator::Dot)
+    }
+
+    fn comma(s: &str) -> Result<(String, Vec<&'static str>), &'static str> {
+        normalize(s, DecimalSeparator::Comma)
+    }
+
+    fn plain(
+        s: &str,
+        changes: &[&'static str],
+    ) -> Result<(String, Vec<&'static str>), &'static str> {
+        Ok((s.to_string(), changes.to_vec()))
+    }
+
+    #[test]
+    fn already_plain() {
+        assert_eq!(dot("1.5"), plain("1.5", &[]));
+        assert_eq!(dot("-12"), plain("-12", &[]));
+        assert_eq!(comma("12"), plain("12", &[]));
+    }
+
+    #[test]
+    fn signs_and_symbols() {
+        assert_eq!(dot("+1.00"), plain("1.00", &["plus sign"]));
+        assert_eq!(dot("$12.00"), plain("12.00", &["currency symbol"]));
+        assert_eq!(dot("-$12"), plain("-12", &["currency symbol"]));
+        assert_eq!(dot("$-12"), plain("-12", &["currency symbol"]));
+        assert_eq!(
+            comma("12,50 €"),
+            plain("12.50", &["currency symbol", "decimal comma"])
+        );
+
+        assert!(dot("-$-12").is_err());
+        assert!(dot("$--12").is_err());
+        assert!(dot("$$12").is_err());
+        assert!(dot("12 USD").is_err());
+    }
+
+    #[test]
+    fn thousands_separators() {
+        assert_eq!(dot("1,000.50"), plain("1000.50", &["thousands separators"]));
+        assert_eq!(
+            dot("1,234,567"),
+            plain("1234567", &["thousands separators"])
+        );
+        assert_eq!(
+            comma("1.234,56"),
+            plain("1234.56", &["decimal comma", "thousands separators"]),
+        );
+
+        // the wrong locale or bad grouping is never guessed at
+        assert!(dot("1,5").is_err());
+        assert!(dot("1.234,56").is_err());
+        assert!(comma("1,234.56").is_err());
+        assert!(dot("1,23,456").is_err());
+        assert!(dot(",123").is_err());
+        assert!(dot("1.000,000").is_err());
+    }
+
+    #[test]
+    fn exponents() {
+        assert_eq!(dot("1e3"), plain("1000", &["exponent"]));
+        assert_eq!(dot("1.5E-2"), plain("0.015", &["exponent"]));
+        assert_eq!(dot("12.5e1"), plain("125", &["exponent"]));
+        assert_eq!(dot("-2e-1"), plain("-0.2", &["exponent"]));
+        assert_eq!(comma("1,5e2"), plain("150", &["exponent", "decimal comma"]));
+
+        assert!(dot("1e").is_err());
+        assert!(dot("1e1000").is_err());
+        assert!(dot("e3").is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(dot("").is_err());
+        assert!(dot("abc").is_err());
+        assert!(dot("1.2.3").is_err());
+        assert!(dot(".5").is_err());
+    }
+}
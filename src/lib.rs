@@ -0,0 +1,23 @@
+pub mod amount;
+pub mod args;
+pub mod cents;
+pub mod checkpoint;
+pub mod client;
+pub mod commands;
+pub mod fraud;
+pub mod ids;
+pub mod input;
+pub mod lenient;
+pub mod output;
+pub mod pending;
+pub mod pipeline;
+pub mod policy;
+pub mod process;
+pub mod record_type;
+pub mod rejects;
+pub mod report;
+pub mod review;
+pub mod script;
+pub mod sources;
+pub mod state;
+pub mod transaction;
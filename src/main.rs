@@ -1,23 +1,42 @@
-use client::ClientsMap;
-use transaction::TransactionsMap;
+use std::process::ExitCode;
 
-mod args;
-mod cents;
-mod client;
-mod input;
-mod output;
-mod process;
-mod transaction;
+use transaction_processor::{args, commands, input, report::Outcome};
 
-fn main() -> Result<(), &'static str> {
-    let filename = args::process_args()?;
+// exit codes, see the README
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_REJECTIONS: u8 = 3;
+const EXIT_STRICT_STOP: u8 = 4;
 
-    let mut clients = ClientsMap::new();
-    let mut transactions = TransactionsMap::new();
+fn main() -> ExitCode {
+    let args = match args::process_args() {
+        Ok(args) => args,
+        Err(error) => {
+            eprintln!("{}\n\n{}", error, args::usage());
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
 
-    input::process_input_file(&filename, &mut clients, &mut transactions)?;
+    // nothing was parsed or formatted yet
+    if let Err(error) = args
+        .precision
+        .set()
+        .and_then(|_| args.record_types.clone().set())
+    {
+        eprintln!("{}", error);
+        return ExitCode::from(EXIT_FAILURE);
+    }
 
-    output::write_accounts(&clients);
-
-    Ok(())
+    match commands::run(&args) {
+        Ok(Outcome::Clean) => ExitCode::SUCCESS,
+        Ok(Outcome::Rejections) => ExitCode::from(EXIT_REJECTIONS),
+        Err(error) => {
+            eprintln!("{}", error);
+            if error == input::STRICT_STOP {
+                ExitCode::from(EXIT_STRICT_STOP)
+            } else {
+                ExitCode::from(EXIT_FAILURE)
+            }
+        }
+    }
 }
@@ -1,59 +1,732 @@
+use serde::Serialize;
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufWriter, Seek, SeekFrom, Stdout, Write},
+    path::PathBuf,
+    str::FromStr,
+};
+
 use crate::{
-    cents::cents_to_string,
-    client::{ClientData, ClientId, ClientsMap},
+    cents::Money,
+    client::{AccountData, ClientData, ClientId, ClientsMap, ROLL_UP},
+    state::State,
 };
 
-pub fn write_accounts(clients: &ClientsMap) {
-    // header
-    println!("client,available,held,total,locked");
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    Csv,
+    Json,
+    Ndjson,
+    Table,
+}
+
+impl FromStr for OutputFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(Self::Csv),
+            "json" => Ok(Self::Json),
+            "ndjson" => Ok(Self::Ndjson),
+            "table" => Ok(Self::Table),
+            _ => Err("Unsupported output format, expected csv, json, ndjson or table"),
+        }
+    }
+}
+
+/*
+A sink for account balances.
+`begin` is called once before the first account and `finish` once after the last.
+*/
+pub trait AccountWriter {
+    fn begin(&mut self, columns: Columns) -> io::Result<()>;
+    fn write_account(&mut self, row: &AccountRow) -> io::Result<()>;
+    fn finish(&mut self) -> io::Result<()>;
+}
+
+/*
+A file that only appears under its name once it is committed.
+Writes go to a temporary file next to it, which is renamed on commit and
+removed if the AtomicFile is dropped without being committed. This way a
+failed run never leaves partial output behind.
+*/
+pub struct AtomicFile {
+    file: Option<BufWriter<File>>,
+    temp: PathBuf,
+    path: PathBuf,
+}
+
+impl AtomicFile {
+    pub fn create(filename: &str) -> io::Result<Self> {
+        let path = PathBuf::from(filename);
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+
+        let file = File::create(&temp)?;
+
+        Ok(Self {
+            file: Some(BufWriter::new(file)),
+            temp,
+            path,
+        })
+    }
+
+    pub fn commit(mut self) -> io::Result<()> {
+        // only None after commit, which consumes self
+        let mut file = self.file.take().unwrap();
+        file.flush()?;
+        file.into_inner()?.sync_all()?;
+
+        fs::rename(&self.temp, &self.path)
+    }
+}
+
+impl Write for AtomicFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.file.as_mut().unwrap().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.as_mut().unwrap().flush()
+    }
+}
+
+impl Drop for AtomicFile {
+    fn drop(&mut self) {
+        if self.file.take().is_some() {
+            // not committed
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
+
+/*
+Where a command writes its output: stdout, or a file given with --output.
+A run with checkpoints writes its rejects and review queue in place instead,
+so what was written before a checkpoint is still there to resume from.
+*/
+pub enum Output {
+    Stdout(BufWriter<Stdout>),
+    File(AtomicFile),
+    Checkpointed(BufWriter<File>),
+}
+
+impl Output {
+    pub fn open(filename: Option<&str>) -> Result<Self, &'static str> {
+        match filename {
+            Some(filename) => {
+                let file =
+                    AtomicFile::create(filename).map_err(|_| "Failed to create output file")?;
+                Ok(Self::File(file))
+            }
+            None => Ok(Self::Stdout(BufWriter::new(io::stdout()))),
+        }
+    }
+
+    /*
+    `resume_at` is the length of the file at the checkpoint to resume from,
+    anything written after it is cut off.
+    */
+    pub fn checkpointed(filename: &str, resume_at: Option<u64>) -> Result<Self, &'static str> {
+        let len = match resume_at {
+            Some(len) => len,
+            None => {
+                let file = File::create(filename).map_err(|_| "Failed to create output file")?;
+                return Ok(Self::Checkpointed(BufWriter::new(file)));
+            }
+        };
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .open(filename)
+            .map_err(|_| "Failed to open output file")?;
+        let size = file
+            .metadata()
+            .map_err(|_| "Failed to open output file")?
+            .len();
+        if size < len {
+            return Err("An output file changed since the checkpoint was saved");
+        }
+        file.set_len(len)
+            .and_then(|_| file.seek(SeekFrom::End(0)))
+            .map_err(|_| "Failed to open output file")?;
+        Ok(Self::Checkpointed(BufWriter::new(file)))
+    }
+
+    /*
+    Flushes a checkpointed file and returns its length, for the checkpoint.
+    */
+    pub fn checkpoint(&mut self) -> Result<u64, &'static str> {
+        match self {
+            Self::Checkpointed(out) => out
+                .flush()
+                .and_then(|_| out.get_ref().metadata())
+                .map(|m| m.len())
+                .map_err(|_| "Failed to write output"),
+            _ => Err("Only a checkpointed file can be resumed"),
+        }
+    }
+
+    pub fn commit(self) -> Result<(), &'static str> {
+        match self {
+            Self::Stdout(mut out) => out.flush(),
+            Self::File(file) => file.commit(),
+            Self::Checkpointed(out) => out
+                .into_inner()
+                .map_err(|e| e.into_error())
+                .and_then(|file| file.sync_all()),
+        }
+        .map_err(|_| "Failed to write output")
+    }
+}
+
+impl Write for Output {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stdout(out) => out.write(buf),
+            Self::File(file) => file.write(buf),
+            Self::Checkpointed(out) => out.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stdout(out) => out.flush(),
+            Self::File(file) => file.flush(),
+            Self::Checkpointed(out) => out.flush(),
+        }
+    }
+}
+
+/*
+A CSV file of records for someone to look at, like the rejects file and the
+review queue. Rows start with the input file of the record, which is only
+written with several input files. Without a filename nothing is written.
+`error` is what every failure to write the file is reported as.
+*/
+pub struct CsvRows {
+    out: Option<csv::Writer<Output>>,
+    sources: bool,
+    error: &'static str,
+}
+
+impl CsvRows {
+    pub fn none(error: &'static str) -> Self {
+        Self {
+            out: None,
+            sources: false,
+            error,
+        }
+    }
+
+    pub fn open(
+        filename: Option<&str>,
+        header: &[&str],
+        sources: bool,
+        error: &'static str,
+    ) -> Result<Self, &'static str> {
+        match filename {
+            Some(_) => Self::to_output(Output::open(filename)?, header, sources, error),
+            None => Ok(Self::none(error)),
+        }
+    }
+
+    /*
+    The file of a run with checkpoints, see `Output::checkpointed`. A resumed
+    run appends to the rows from before the checkpoint. Runs with checkpoints
+    have a single input file.
+    */
+    pub fn open_checkpointed(
+        filename: Option<&str>,
+        header: &[&str],
+        resume_at: Option<u64>,
+        error: &'static str,
+    ) -> Result<Self, &'static str> {
+        let out = match filename {
+            Some(f) => Output::checkpointed(f, resume_at)?,
+            None => return Ok(Self::none(error)),
+        };
+        match resume_at {
+            Some(_) => Ok(Self {
+                out: Some(csv::Writer::from_writer(out)),
+                sources: false,
+                error,
+            }),
+            None => Self::to_output(out, header, false, error),
+        }
+    }
+
+    pub fn to_output(
+        out: Output,
+        header: &[&str],
+        sources: bool,
+        error: &'static str,
+    ) -> Result<Self, &'static str> {
+        let mut rows = Self {
+            out: Some(csv::Writer::from_writer(out)),
+            sources,
+            error,
+        };
+        rows.write(header)?;
+        Ok(rows)
+    }
+
+    /*
+    Whether rows are written anywhere, so they don't have to be built if not.
+    */
+    pub fn is_open(&self) -> bool {
+        self.out.is_some()
+    }
+
+    /*
+    `row` starts with the source, which is left out without sources.
+    */
+    pub fn write<T: AsRef<[u8]>>(&mut self, row: &[T]) -> Result<(), &'static str> {
+        let out = match &mut self.out {
+            Some(o) => o,
+            None => return Ok(()),
+        };
+        let row = if self.sources { row } else { &row[1..] };
+        out.write_record(row).map_err(|_| self.error)
+    }
+
+    /*
+    The length of the file so far, None without one.
+    */
+    pub fn checkpoint(&mut self) -> Result<Option<u64>, &'static str> {
+        let out = match self.out.take() {
+            Some(o) => o,
+            None => return Ok(None),
+        };
+        let mut out = out.into_inner().map_err(|_| self.error)?;
+        let len = out.checkpoint()?;
+        self.out = Some(csv::Writer::from_writer(out));
+        Ok(Some(len))
+    }
+
+    /*
+    The file only appears once this is called, except with checkpoints.
+    */
+    pub fn finish(self) -> Result<(), &'static str> {
+        match self.out {
+            Some(out) => out
+                .into_inner()
+                .map_err(|_| self.error)?
+                .commit()
+                .map_err(|_| self.error),
+            None => Ok(()),
+        }
+    }
+}
+
+pub fn account_writer<'a, W: Write + 'a>(
+    format: OutputFormat,
+    out: W,
+) -> Box<dyn AccountWriter + 'a> {
+    match format {
+        OutputFormat::Csv => Box::new(CsvAccountWriter {
+            out: csv::Writer::from_writer(out),
+        }),
+        OutputFormat::Json => Box::new(JsonAccountWriter { out, first: true }),
+        OutputFormat::Ndjson => Box::new(NdjsonAccountWriter { out }),
+        OutputFormat::Table => Box::new(TableAccountWriter::new(out)),
+    }
+}
+
+/*
+Which optional columns the rows have.
+With `tenant` every row has a tenant and with `account` every row has an
+account, see `write_accounts`.
+*/
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Columns {
+    pub tenant: bool,
+    pub account: bool,
+}
+
+/*
+Writes one row per client, or with `sub_accounts` one row per sub-account
+followed by the client's roll-up, which has the account ROLL_UP. The roll-up
+is then only locked if the client is locked as a whole, so the lock of the
+client can be told from the locks of its sub-accounts.
+*/
+pub fn write_accounts(
+    clients: &ClientsMap,
+    sub_accounts: bool,
+    writer: &mut dyn AccountWriter,
+) -> Result<(), &'static str> {
+    let columns = Columns {
+        tenant: false,
+        account: sub_accounts,
+    };
+    write_ledgers(&[(None, clients)], columns, writer)
+}
+
+/*
+Same as `write_accounts`, for the clients of every tenant grouped by tenant.
+Rows only have a tenant if there are tenants other than the DEFAULT_TENANT.
+*/
+pub fn write_tenant_accounts(
+    state: &State,
+    sub_accounts: bool,
+    writer: &mut dyn AccountWriter,
+) -> Result<(), &'static str> {
+    let columns = Columns {
+        tenant: state.has_tenants(),
+        account: sub_accounts,
+    };
+    let ledgers: Vec<_> = state
+        .tenants
+        .iter()
+        .map(|(tenant, ledger)| (columns.tenant.then_some(tenant.as_str()), &ledger.clients))
+        .collect();
+    write_ledgers(&ledgers, columns, writer)
+}
+
+const WRITE_ERROR: &str = "Failed to write output";
+
+fn write_ledgers(
+    ledgers: &[(Option<&str>, &ClientsMap)],
+    columns: Columns,
+    writer: &mut dyn AccountWriter,
+) -> Result<(), &'static str> {
+    writer.begin(columns).map_err(|_| WRITE_ERROR)?;
+
+    for (tenant, clients) in ledgers {
+        let mut client_ids: Vec<_> = clients.keys().collect();
+        client_ids.sort();
+
+        for client_id in client_ids {
+            let client = clients.get(client_id).unwrap();
+
+            let mut rows = Vec::new();
+            if columns.account {
+                for (name, account) in client.accounts() {
+                    rows.push(AccountRow::sub_account(*client_id, name, account, client)?);
+                }
+            }
+
+            let mut row = AccountRow::new(*client_id, client)?;
+            if columns.account {
+                row.account = Some(ROLL_UP.to_string());
+                row.locked = client.is_client_locked();
+            }
+            rows.push(row);
+
+            for mut row in rows {
+                row.tenant = tenant.map(str::to_string);
+                writer.write_account(&row).map_err(|_| WRITE_ERROR)?;
+            }
+        }
+    }
+
+    writer.finish().map_err(|_| WRITE_ERROR)
+}
+
+/*
+The fields of a single account, with amounts formatted as strings.
+`tenant` and `account` are only set when writing those columns.
+*/
+#[derive(Serialize)]
+pub struct AccountRow {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub client: ClientId,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub account: Option<String>,
+    pub available: String,
+    pub held: String,
+    pub total: String,
+    pub locked: bool,
+}
+
+impl AccountRow {
+    /*
+    The roll-up over all of the client's sub-accounts.
+    */
+    pub fn new(id: ClientId, client: &ClientData) -> Result<Self, &'static str> {
+        Ok(Self {
+            tenant: None,
+            client: id,
+            account: None,
+            available: client.get_available()?.to_string(),
+            held: client.get_held()?.to_string(),
+            total: client.get_total()?.to_string(),
+            locked: client.is_locked(),
+        })
+    }
+
+    pub fn sub_account(
+        id: ClientId,
+        name: &str,
+        account: &AccountData,
+        client: &ClientData,
+    ) -> Result<Self, &'static str> {
+        Ok(Self {
+            tenant: None,
+            client: id,
+            account: Some(name.to_string()),
+            available: account.get_available().to_string(),
+            held: account.get_held().to_string(),
+            total: account.get_total()?.to_string(),
+            locked: account.is_locked() || client.is_client_locked(),
+        })
+    }
+}
+
+struct CsvAccountWriter<W: Write> {
+    out: csv::Writer<W>,
+}
+
+impl<W: Write> AccountWriter for CsvAccountWriter<W> {
+    fn begin(&mut self, columns: Columns) -> io::Result<()> {
+        let mut header = Vec::new();
+        if columns.tenant {
+            header.push("tenant");
+        }
+        header.push("client");
+        if columns.account {
+            header.push("account");
+        }
+        header.extend(["available", "held", "total", "locked"]);
+        self.out.write_record(&header)?;
+        Ok(())
+    }
+
+    fn write_account(&mut self, row: &AccountRow) -> io::Result<()> {
+        let mut record: Vec<_> = row.tenant.iter().cloned().collect();
+        record.push(row.client.to_string());
+        record.extend(row.account.clone());
+        record.extend([
+            row.available.clone(),
+            row.held.clone(),
+            row.total.clone(),
+            row.locked.to_string(),
+        ]);
+        self.out.write_record(&record)?;
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/*
+A single JSON array of account objects.
+*/
+struct JsonAccountWriter<W> {
+    out: W,
+    first: bool,
+}
+
+impl<W: Write> AccountWriter for JsonAccountWriter<W> {
+    fn begin(&mut self, _columns: Columns) -> io::Result<()> {
+        write!(self.out, "[")
+    }
+
+    fn write_account(&mut self, row: &AccountRow) -> io::Result<()> {
+        if !self.first {
+            write!(self.out, ",")?;
+        }
+        self.first = false;
+
+        write!(self.out, "\n  ")?;
+        serde_json::to_writer(&mut self.out, row)?;
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        if !self.first {
+            writeln!(self.out)?;
+        }
+        writeln!(self.out, "]")?;
+        self.out.flush()
+    }
+}
 
-    // body
-    let mut client_ids: Vec<_> = clients.keys().collect();
-    client_ids.sort();
+/*
+One JSON account object per line.
+*/
+struct NdjsonAccountWriter<W> {
+    out: W,
+}
+
+impl<W: Write> AccountWriter for NdjsonAccountWriter<W> {
+    fn begin(&mut self, _columns: Columns) -> io::Result<()> {
+        Ok(())
+    }
 
-    for client_id in client_ids {
-        let client = clients.get(client_id).unwrap();
-        println!("{}", format_client(*client_id, client));
+    fn write_account(&mut self, row: &AccountRow) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, row)?;
+        writeln!(self.out)
     }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/*
+A fixed-width text table for humans.
+Amounts are wide enough for the largest possible balance at the precision of
+the run.
+*/
+struct TableAccountWriter<W> {
+    out: W,
+    amount_width: usize,
 }
 
-fn format_client(id: ClientId, client: &ClientData) -> String {
-    format!(
-        "{},{},{},{},{}",
-        id,
-        cents_to_string(client.get_available()),
-        cents_to_string(client.get_held()),
-        cents_to_string(client.get_total()),
-        client.is_locked(),
-    )
+impl<W> TableAccountWriter<W> {
+    fn new(out: W) -> Self {
+        let amount_width = [Money::MIN, Money::MAX]
+            .iter()
+            .map(|m| m.to_string().len())
+            .max()
+            .unwrap_or_default();
+        Self { out, amount_width }
+    }
+}
+
+const TENANT_WIDTH: usize = 10;
+const CLIENT_WIDTH: usize = 6;
+const ACCOUNT_WIDTH: usize = 10;
+const LOCKED_WIDTH: usize = 6;
+
+impl<W: Write> AccountWriter for TableAccountWriter<W> {
+    fn begin(&mut self, columns: Columns) -> io::Result<()> {
+        let (tenant, tenant_line) = if columns.tenant {
+            (
+                format!("{:<w$} ", "tenant", w = TENANT_WIDTH),
+                format!("{} ", "-".repeat(TENANT_WIDTH)),
+            )
+        } else {
+            Default::default()
+        };
+        let (account, account_line) = if columns.account {
+            (
+                format!(" {:<w$}", "account", w = ACCOUNT_WIDTH),
+                format!(" {}", "-".repeat(ACCOUNT_WIDTH)),
+            )
+        } else {
+            Default::default()
+        };
+
+        writeln!(
+            self.out,
+            "{}{:>cw$}{} {:>aw$} {:>aw$} {:>aw$} {:>lw$}",
+            tenant,
+            "client",
+            account,
+            "available",
+            "held",
+            "total",
+            "locked",
+            cw = CLIENT_WIDTH,
+            aw = self.amount_width,
+            lw = LOCKED_WIDTH,
+        )?;
+        writeln!(
+            self.out,
+            "{}{}{} {} {} {} {}",
+            tenant_line,
+            "-".repeat(CLIENT_WIDTH),
+            account_line,
+            "-".repeat(self.amount_width),
+            "-".repeat(self.amount_width),
+            "-".repeat(self.amount_width),
+            "-".repeat(LOCKED_WIDTH),
+        )
+    }
+
+    fn write_account(&mut self, row: &AccountRow) -> io::Result<()> {
+        let tenant = match &row.tenant {
+            Some(t) => format!("{:<w$} ", t, w = TENANT_WIDTH),
+            None => String::new(),
+        };
+        let account = match &row.account {
+            Some(a) => format!(" {:<w$}", a, w = ACCOUNT_WIDTH),
+            None => String::new(),
+        };
+
+        writeln!(
+            self.out,
+            "{}{:>cw$}{} {:>aw$} {:>aw$} {:>aw$} {:>lw$}",
+            tenant,
+            row.client,
+            account,
+            row.available,
+            row.held,
+            row.total,
+            row.locked,
+            cw = CLIENT_WIDTH,
+            aw = self.amount_width,
+            lw = LOCKED_WIDTH,
+        )
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use std::{fs, io::Write};
+
     use crate::{
-        client::ClientsMap, input::InputRecord, output::format_client, process::process_record,
-        transaction::TransactionsMap,
+        cents::Money,
+        client::{client_id, ClientData, ClientsMap, DEFAULT_ACCOUNT},
+        input::InputRecord,
+        output::{account_writer, write_accounts, write_tenant_accounts, AtomicFile, OutputFormat},
+        process::process_record,
+        record_type::RecordType,
+        state::{State, DEFAULT_TENANT},
+        transaction::{tx_id, TransactionsMap},
     };
 
+    fn format_accounts(clients: &ClientsMap, format: OutputFormat) -> String {
+        format_sub_accounts(clients, false, format)
+    }
+
+    fn format_sub_accounts(
+        clients: &ClientsMap,
+        sub_accounts: bool,
+        format: OutputFormat,
+    ) -> String {
+        let mut out = Vec::new();
+        write_accounts(
+            clients,
+            sub_accounts,
+            account_writer(format, &mut out).as_mut(),
+        )
+        .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
     #[test]
     fn basic() {
         let mut clients = ClientsMap::new();
         let mut transactions = TransactionsMap::new();
-        let client_id = 1;
+        let client = client_id(1);
 
         let deposit = InputRecord {
-            record_type: "deposit".to_string(),
-            client: client_id,
-            transaction: 1,
-            amount: Some("0.1234".to_string()),
+            record_type: RecordType::Deposit,
+            client,
+            transaction: tx_id(1),
+            amount: Some("0.1234".into()),
+            account: None,
+            tenant: None,
+            time: None,
         };
 
         assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
-        let client = clients.get(&client_id).unwrap();
         assert_eq!(
-            format_client(client_id, client),
-            "1,0.1234,0,0.1234,false".to_string(),
+            format_accounts(&clients, OutputFormat::Csv),
+            "client,available,held,total,locked\n1,0.1234,0.0000,0.1234,false\n".to_string(),
         );
     }
 
@@ -61,30 +734,35 @@ mod tests {
     fn held() {
         let mut clients = ClientsMap::new();
         let mut transactions = TransactionsMap::new();
-        let client_id = 1;
+        let client = client_id(1);
 
         let deposit = InputRecord {
-            record_type: "deposit".to_string(),
-            client: client_id,
-            transaction: 1,
-            amount: Some("0.1234".to_string()),
+            record_type: RecordType::Deposit,
+            client,
+            transaction: tx_id(1),
+            amount: Some("0.1234".into()),
+            account: None,
+            tenant: None,
+            time: None,
         };
 
         assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
         let dispute = InputRecord {
-            record_type: "dispute".to_string(),
-            client: client_id,
-            transaction: 1,
+            record_type: RecordType::Dispute,
+            client,
+            transaction: tx_id(1),
             amount: None,
+            account: None,
+            tenant: None,
+            time: None,
         };
 
         assert!(process_record(&dispute, &mut clients, &mut transactions).is_ok());
 
-        let client = clients.get(&client_id).unwrap();
         assert_eq!(
-            format_client(client_id, client),
-            "1,0,0.1234,0.1234,false".to_string(),
+            format_accounts(&clients, OutputFormat::Csv),
+            "client,available,held,total,locked\n1,0.0000,0.1234,0.1234,false\n".to_string(),
         );
     }
 
@@ -92,36 +770,282 @@ mod tests {
     fn locked() {
         let mut clients = ClientsMap::new();
         let mut transactions = TransactionsMap::new();
-        let client_id = 1;
+        let client = client_id(1);
 
         let deposit = InputRecord {
-            record_type: "deposit".to_string(),
-            client: client_id,
-            transaction: 1,
-            amount: Some("0.1234".to_string()),
+            record_type: RecordType::Deposit,
+            client,
+            transaction: tx_id(1),
+            amount: Some("0.1234".into()),
+            account: None,
+            tenant: None,
+            time: None,
         };
 
         assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
         let dispute = InputRecord {
-            record_type: "dispute".to_string(),
-            client: client_id,
-            transaction: 1,
+            record_type: RecordType::Dispute,
+            client,
+            transaction: tx_id(1),
             amount: None,
+            account: None,
+            tenant: None,
+            time: None,
         };
 
         assert!(process_record(&dispute, &mut clients, &mut transactions).is_ok());
 
         let chargeback = InputRecord {
-            record_type: "chargeback".to_string(),
-            client: client_id,
-            transaction: 1,
+            record_type: RecordType::Chargeback,
+            client,
+            transaction: tx_id(1),
             amount: None,
+            account: None,
+            tenant: None,
+            time: None,
         };
 
         assert!(process_record(&chargeback, &mut clients, &mut transactions).is_ok());
 
-        let client = clients.get(&client_id).unwrap();
-        assert_eq!(format_client(client_id, client), "1,0,0,0,true".to_string(),);
+        assert_eq!(
+            format_accounts(&clients, OutputFormat::Csv),
+            "client,available,held,total,locked\n1,0.0000,0.0000,0.0000,true\n".to_string(),
+        );
+    }
+
+    // a number, or a string with string-ids
+    fn client_json(client: u16) -> String {
+        serde_json::to_string(&client_id(client)).unwrap()
+    }
+
+    fn two_clients() -> ClientsMap {
+        let mut clients = ClientsMap::new();
+        let mut transactions = TransactionsMap::new();
+
+        for (client, transaction, amount) in [(2, 1, "1.5"), (1, 2, "0.25")] {
+            let deposit = InputRecord {
+                record_type: RecordType::Deposit,
+                client: client_id(client),
+                transaction: tx_id(transaction),
+                amount: Some(amount.into()),
+                account: None,
+                tenant: None,
+                time: None,
+            };
+
+            assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
+        }
+
+        clients
+    }
+
+    #[test]
+    fn json() {
+        let clients = two_clients();
+
+        let json: serde_json::Value =
+            serde_json::from_str(&format_accounts(&clients, OutputFormat::Json)).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"client": client_id(1), "available": "0.2500", "held": "0.0000", "total": "0.2500", "locked": false},
+                {"client": client_id(2), "available": "1.5000", "held": "0.0000", "total": "1.5000", "locked": false},
+            ]),
+        );
+
+        // no accounts is still a valid array
+        let json: serde_json::Value =
+            serde_json::from_str(&format_accounts(&ClientsMap::new(), OutputFormat::Json)).unwrap();
+        assert_eq!(json, serde_json::json!([]));
+    }
+
+    #[test]
+    fn ndjson() {
+        let clients = two_clients();
+
+        assert_eq!(
+            format_accounts(&clients, OutputFormat::Ndjson),
+            format!(
+                concat!(
+                    r#"{{"client":{},"available":"0.2500","held":"0.0000","total":"0.2500","locked":false}}"#,
+                    "\n",
+                    r#"{{"client":{},"available":"1.5000","held":"0.0000","total":"1.5000","locked":false}}"#,
+                    "\n",
+                ),
+                client_json(1),
+                client_json(2),
+            ),
+        );
+    }
+
+    #[test]
+    fn table() {
+        let clients = two_clients();
+        let table = format_accounts(&clients, OutputFormat::Table);
+        let lines: Vec<_> = table.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        // amounts are as wide as the largest balance, 21 columns with i64
+        assert!(lines[2].starts_with("     1 "));
+        assert_eq!(
+            lines[2].split_whitespace().collect::<Vec<_>>(),
+            vec!["1", "0.2500", "0.0000", "0.2500", "false"]
+        );
+        #[cfg(not(feature = "i128"))]
+        assert_eq!(
+            lines[2],
+            "     1                0.2500                0.0000                0.2500  false",
+        );
+
+        // every line has the same width
+        assert!(lines.iter().all(|l| l.len() == lines[0].len()));
+
+        // also with the smallest possible balance, at any precision
+        let mut clients = two_clients();
+        let mut client = ClientData::new();
+        client
+            .open_account(DEFAULT_ACCOUNT, Money::MIN, Money::ZERO, false)
+            .unwrap();
+        clients.insert(client_id(3), client);
+        let table = format_accounts(&clients, OutputFormat::Table);
+        let lines: Vec<_> = table.lines().collect();
+        assert!(lines[4].contains(&Money::MIN.to_string()));
+        assert!(lines.iter().all(|l| l.len() == lines[0].len()));
+    }
+
+    #[test]
+    fn sub_accounts() {
+        let mut clients = ClientsMap::new();
+        let mut transactions = TransactionsMap::new();
+
+        for (transaction, amount, account) in [(1, "1", "savings"), (2, "0.5", "escrow, EU")] {
+            let deposit = InputRecord {
+                record_type: RecordType::Deposit,
+                client: client_id(1),
+                transaction: tx_id(transaction),
+                amount: Some(amount.into()),
+                account: Some(account.to_string()),
+                tenant: None,
+                time: None,
+            };
+
+            assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
+        }
+
+        assert_eq!(
+            format_sub_accounts(&clients, true, OutputFormat::Csv),
+            concat!(
+                "client,account,available,held,total,locked\n",
+                "1,\"escrow, EU\",0.5000,0.0000,0.5000,false\n",
+                "1,savings,1.0000,0.0000,1.0000,false\n",
+                "1,*,1.5000,0.0000,1.5000,false\n",
+            ),
+        );
+
+        let json: serde_json::Value =
+            serde_json::from_str(&format_sub_accounts(&clients, true, OutputFormat::Json)).unwrap();
+        assert_eq!(json[2]["account"], "*");
+        assert_eq!(json[2]["total"], "1.5000");
+
+        let table = format_sub_accounts(&clients, true, OutputFormat::Table);
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines.iter().all(|l| l.len() == lines[0].len()));
+    }
+
+    #[test]
+    fn tenants() {
+        let mut state = State::new();
+
+        for (tenant, amount) in [("globex", "2"), (DEFAULT_TENANT, "1"), ("acme", "3")] {
+            let deposit = InputRecord {
+                record_type: RecordType::Deposit,
+                client: client_id(1),
+                transaction: tx_id(1),
+                amount: Some(amount.into()),
+                account: None,
+                tenant: None,
+                time: None,
+            };
+
+            let ledger = state.ledger_mut(tenant);
+            assert!(
+                process_record(&deposit, &mut ledger.clients, &mut ledger.transactions).is_ok()
+            );
+        }
+
+        let mut out = Vec::new();
+        write_tenant_accounts(
+            &state,
+            false,
+            account_writer(OutputFormat::Csv, &mut out).as_mut(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            concat!(
+                "tenant,client,available,held,total,locked\n",
+                ",1,1.0000,0.0000,1.0000,false\n",
+                "acme,1,3.0000,0.0000,3.0000,false\n",
+                "globex,1,2.0000,0.0000,2.0000,false\n",
+            ),
+        );
+
+        let mut out = Vec::new();
+        write_tenant_accounts(
+            &state,
+            true,
+            account_writer(OutputFormat::Table, &mut out).as_mut(),
+        )
+        .unwrap();
+        let table = String::from_utf8(out).unwrap();
+        let lines: Vec<_> = table.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert!(lines[4].starts_with("acme "));
+        assert!(lines.iter().all(|l| l.len() == lines[0].len()));
+
+        // without other tenants there is no tenant column
+        state.tenants.retain(|tenant, _| tenant == DEFAULT_TENANT);
+        let mut out = Vec::new();
+        write_tenant_accounts(
+            &state,
+            false,
+            account_writer(OutputFormat::Ndjson, &mut out).as_mut(),
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!(
+                concat!(
+                    r#"{{"client":{},"available":"1.0000","held":"0.0000","total":"1.0000","locked":false}}"#,
+                    "\n",
+                ),
+                client_json(1),
+            ),
+        );
+    }
+
+    #[test]
+    fn atomic_file() {
+        let dir = std::env::temp_dir();
+        let committed = dir.join("transaction_processor_atomic_committed.csv");
+        let dropped = dir.join("transaction_processor_atomic_dropped.csv");
+
+        let mut file = AtomicFile::create(committed.to_str().unwrap()).unwrap();
+        write!(file, "data").unwrap();
+        // nothing under the real name until commit
+        assert!(!committed.exists());
+        file.commit().unwrap();
+        assert_eq!(fs::read_to_string(&committed).unwrap(), "data");
+        fs::remove_file(&committed).unwrap();
+
+        let mut file = AtomicFile::create(dropped.to_str().unwrap()).unwrap();
+        write!(file, "data").unwrap();
+        drop(file);
+        assert!(!dropped.exists());
+        assert!(!dir
+            .join("transaction_processor_atomic_dropped.csv.tmp")
+            .exists());
     }
 }
@@ -0,0 +1,177 @@
+use std::collections::{HashMap, VecDeque};
+
+use crate::{input::InputRecord, sources::Location, transaction::TransactionId};
+
+/*
+Disputes, resolves and chargebacks that arrived before the transaction they
+refer to, e.g. because the input was merged from several feeds.
+They are parked per tenant and transaction id until the transaction arrives,
+and expire once `window` more records were read without it.
+*/
+#[derive(Clone, Default)]
+pub struct Pending {
+    // records read so far
+    seq: u64,
+    parked: HashMap<String, HashMap<TransactionId, Vec<Parked>>>,
+    // in the order they were parked, for expiring
+    order: VecDeque<(u64, String, TransactionId)>,
+}
+
+#[derive(Clone)]
+pub struct Parked {
+    seq: u64,
+    pub location: Location,
+    pub record: InputRecord,
+}
+
+impl Pending {
+    pub fn is_empty(&self) -> bool {
+        self.order.is_empty()
+    }
+
+    /*
+    Called once for every record read, parked or not.
+    */
+    pub fn tick(&mut self) {
+        self.seq += 1;
+    }
+
+    pub fn park(&mut self, tenant: &str, location: Location, record: InputRecord) {
+        let transaction = record.transaction;
+        self.parked
+            .entry(tenant.to_string())
+            .or_default()
+            .entry(transaction)
+            .or_default()
+            .push(Parked {
+                seq: self.seq,
+                location,
+                record,
+            });
+        self.order
+            .push_back((self.seq, tenant.to_string(), transaction));
+    }
+
+    /*
+    The records waiting for a transaction, in the order they were read.
+    */
+    pub fn take(&mut self, tenant: &str, transaction: TransactionId) -> Vec<Parked> {
+        if self.is_empty() {
+            return Vec::new();
+        }
+
+        let taken = match self.parked.get_mut(tenant) {
+            Some(transactions) => transactions.remove(&transaction).unwrap_or_default(),
+            None => Vec::new(),
+        };
+        if !taken.is_empty() {
+            self.order
+                .retain(|(_, t, tx)| !(t == tenant && *tx == transaction));
+        }
+        taken
+    }
+
+    /*
+    The records that waited for more than `window` records, in the order they
+    were read.
+    */
+    pub fn expire(&mut self, window: u64) -> Vec<Parked> {
+        let mut expired = Vec::new();
+        while let Some((seq, _, _)) = self.order.front() {
+            if seq + window >= self.seq {
+                break;
+            }
+            expired.extend(self.pop_front());
+        }
+        expired
+    }
+
+    /*
+    All records that are still waiting, in the order they were read.
+    */
+    pub fn drain(&mut self) -> Vec<Parked> {
+        let mut drained = Vec::new();
+        while !self.order.is_empty() {
+            drained.extend(self.pop_front());
+        }
+        drained
+    }
+
+    fn pop_front(&mut self) -> Option<Parked> {
+        let (seq, tenant, transaction) = self.order.pop_front()?;
+        let transactions = self.parked.get_mut(&tenant)?;
+        let records = transactions.get_mut(&transaction)?;
+
+        // records of a transaction are parked and expire in order
+        let parked = records.remove(0);
+        debug_assert_eq!(parked.seq, seq);
+        if records.is_empty() {
+            transactions.remove(&transaction);
+        }
+        Some(parked)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pending;
+    use crate::{
+        client::client_id,
+        input::InputRecord,
+        record_type::RecordType,
+        sources::Location,
+        transaction::{tx_id, TransactionId},
+    };
+
+    fn dispute(transaction: TransactionId) -> InputRecord {
+        InputRecord {
+            record_type: RecordType::Dispute,
+            client: client_id(1),
+            transaction,
+            amount: None,
+            account: None,
+            tenant: None,
+            time: None,
+        }
+    }
+
+    fn lines(parked: Vec<super::Parked>) -> Vec<u32> {
+        parked.iter().map(|p| p.location.line).collect()
+    }
+
+    #[test]
+    fn take() {
+        let mut pending = Pending::default();
+
+        for (line, transaction) in [(1, 7), (2, 8), (3, 7)] {
+            pending.tick();
+            pending.park("", Location::line(line), dispute(tx_id(transaction)));
+        }
+
+        assert!(pending.take("other", tx_id(7)).is_empty());
+        assert_eq!(lines(pending.take("", tx_id(7))), vec![1, 3]);
+        assert!(pending.take("", tx_id(7)).is_empty());
+        assert_eq!(lines(pending.drain()), vec![2]);
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn expire() {
+        let mut pending = Pending::default();
+
+        for (line, transaction) in [(1, 7), (2, 8), (3, 9)] {
+            pending.tick();
+            pending.park("", Location::line(line), dispute(tx_id(transaction)));
+        }
+        assert_eq!(lines(pending.take("", tx_id(8))), vec![2]);
+
+        // each record gets to wait for 2 more records
+        assert!(pending.expire(2).is_empty());
+        pending.tick();
+        assert_eq!(lines(pending.expire(2)), vec![1]);
+        pending.tick();
+        pending.tick();
+        assert_eq!(lines(pending.expire(2)), vec![3]);
+        assert!(pending.is_empty());
+    }
+}
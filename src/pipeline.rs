@@ -0,0 +1,136 @@
+use std::{
+    panic,
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread::{self, JoinHandle},
+    vec,
+};
+
+/*
+Items are sent in batches, so the threads only sync once per batch.
+*/
+const BATCH: usize = 1024;
+
+// batches read ahead of the consumer
+const DEPTH: usize = 8;
+
+/*
+Runs an iterator on a thread of its own, e.g. reading and parsing input while
+the records are applied on the calling thread. Items come out in the same
+order. The thread is only started by the first `next`, so inputs read one
+after the other don't all read ahead at once, and it stops at its next batch
+once the Pipelined is dropped.
+A panic on the thread is a panic in `next`.
+*/
+pub struct Pipelined<I: Iterator> {
+    items: Option<I>,
+    batches: Option<Receiver<Vec<I::Item>>>,
+    thread: Option<JoinHandle<()>>,
+    batch: vec::IntoIter<I::Item>,
+}
+
+impl<I> Pipelined<I>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    pub fn new(items: I) -> Self {
+        Self {
+            items: Some(items),
+            batches: None,
+            thread: None,
+            batch: Vec::new().into_iter(),
+        }
+    }
+
+    fn start(&mut self, items: I) {
+        let (sender, batches) = mpsc::sync_channel(DEPTH);
+        let thread = thread::spawn(move || send_batches(items, sender));
+        self.batches = Some(batches);
+        self.thread = Some(thread);
+    }
+
+    fn finish(&mut self) {
+        self.batches = None;
+        if let Some(thread) = self.thread.take() {
+            if let Err(payload) = thread.join() {
+                panic::resume_unwind(payload);
+            }
+        }
+    }
+}
+
+fn send_batches<I: Iterator>(mut items: I, sender: SyncSender<Vec<I::Item>>) {
+    loop {
+        let batch: Vec<_> = items.by_ref().take(BATCH).collect();
+        let last = batch.len() < BATCH;
+        // fails once the receiver is dropped
+        if batch.is_empty() || sender.send(batch).is_err() || last {
+            return;
+        }
+    }
+}
+
+impl<I> Iterator for Pipelined<I>
+where
+    I: Iterator + Send + 'static,
+    I::Item: Send + 'static,
+{
+    type Item = I::Item;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(items) = self.items.take() {
+            self.start(items);
+        }
+
+        loop {
+            if let Some(item) = self.batch.next() {
+                return Some(item);
+            }
+            match self.batches.as_ref().map(Receiver::recv) {
+                Some(Ok(batch)) => self.batch = batch.into_iter(),
+                // the thread is done, or was already joined
+                _ => {
+                    self.finish();
+                    return None;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Pipelined, BATCH};
+
+    #[test]
+    fn same_order() {
+        let count = BATCH * 3 + 7;
+        let items: Vec<usize> = Pipelined::new(0..count).collect();
+        assert_eq!(items, (0..count).collect::<Vec<_>>());
+
+        // exactly full batches, and nothing at all
+        assert_eq!(Pipelined::new(0..BATCH * 2).count(), BATCH * 2);
+        let mut empty = Pipelined::new(0..0);
+        assert_eq!(empty.next(), None);
+        assert_eq!(empty.next(), None);
+    }
+
+    #[test]
+    fn dropped_early() {
+        // the thread stops instead of reading the endless iterator
+        let mut items = Pipelined::new(0..);
+        assert_eq!(items.nth(BATCH * 2), Some(BATCH * 2));
+        drop(items);
+    }
+
+    #[test]
+    #[should_panic(expected = "bad record")]
+    fn panic() {
+        let items = (0..BATCH * 2).inspect(|&i| {
+            if i == BATCH {
+                panic!("bad record");
+            }
+        });
+        Pipelined::new(items).for_each(drop);
+    }
+}
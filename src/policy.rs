@@ -0,0 +1,253 @@
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs};
+
+use crate::{
+    client::{LockScope, LockedAccepts},
+    fraud::{FraudRule, FraudRuleFile},
+    process::{DuplicateTx, Rules},
+    state::DEFAULT_TENANT,
+};
+
+/*
+The business rules of a run: the rules of every tenant, unless the tenant has
+its own in `tenants`. The fraud rules apply to every tenant.
+*/
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Policy {
+    pub rules: Rules,
+    pub tenants: BTreeMap<String, Rules>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub fraud: Vec<FraudRule>,
+}
+
+/*
+A TOML policy file, see the README.
+Every key is optional and defaults to the built-in rule. A `[tenants.NAME]`
+table only overrides the keys it sets.
+*/
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PolicyFile {
+    #[serde(default)]
+    rules: RulesFile,
+    #[serde(default)]
+    tenants: BTreeMap<String, RulesFile>,
+    #[serde(default)]
+    fraud: Vec<FraudRuleFile>,
+}
+
+#[derive(Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RulesFile {
+    lock_scope: Option<LockScope>,
+    duplicate_tx: Option<DuplicateTx>,
+    locked_accepts: Option<LockedAccepts>,
+    dispute_below_zero: Option<bool>,
+    zero_amounts: Option<bool>,
+    redispute: Option<bool>,
+}
+
+impl RulesFile {
+    fn apply(&self, rules: &Rules) -> Rules {
+        Rules {
+            lock_scope: self.lock_scope.unwrap_or(rules.lock_scope),
+            duplicate_tx: self.duplicate_tx.unwrap_or(rules.duplicate_tx),
+            locked_accepts: self.locked_accepts.unwrap_or(rules.locked_accepts),
+            dispute_below_zero: self.dispute_below_zero.unwrap_or(rules.dispute_below_zero),
+            zero_amounts: self.zero_amounts.unwrap_or(rules.zero_amounts),
+            redispute: self.redispute.unwrap_or(rules.redispute),
+        }
+    }
+}
+
+impl Policy {
+    /*
+    Loads and validates a policy file.
+    What is wrong with an invalid file is logged to stderr.
+    */
+    pub fn load(filename: &str) -> Result<Self, &'static str> {
+        let toml = fs::read_to_string(filename).map_err(|_| "Failed to open policy file")?;
+        Self::parse(&toml)
+    }
+
+    pub fn parse(toml: &str) -> Result<Self, &'static str> {
+        let file: PolicyFile = toml::from_str(toml).map_err(|e| {
+            eprintln!("{}", e.message());
+            "Failed to parse policy file"
+        })?;
+
+        if file.tenants.contains_key(DEFAULT_TENANT) {
+            return Err("Tenant names in the policy file may not be empty");
+        }
+
+        let rules = file.rules.apply(&Rules::default());
+        let tenants = file
+            .tenants
+            .into_iter()
+            .map(|(tenant, overrides)| {
+                let rules = overrides.apply(&rules);
+                (tenant, rules)
+            })
+            .collect();
+
+        let fraud = file
+            .fraud
+            .into_iter()
+            .map(FraudRuleFile::validate)
+            .collect::<Result<Vec<_>, _>>()?;
+        if (1..fraud.len()).any(|i| fraud[..i].iter().any(|r| r.name == fraud[i].name)) {
+            return Err("Fraud rules of the same kind need different names");
+        }
+
+        Ok(Self {
+            rules,
+            tenants,
+            fraud,
+        })
+    }
+
+    pub fn rules_for(&self, tenant: &str) -> &Rules {
+        self.tenants.get(tenant).unwrap_or(&self.rules)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Policy;
+    use crate::{
+        client::{LockScope, LockedAccepts},
+        fraud::{FraudAction, FraudCheck, FraudRule, Window},
+        process::{DuplicateTx, Rules},
+    };
+
+    #[test]
+    fn empty() {
+        assert_eq!(Policy::parse(""), Ok(Policy::default()));
+    }
+
+    #[test]
+    fn rules_and_tenants() {
+        let policy = Policy::parse(
+            r#"
+            [rules]
+            duplicate_tx = "skip"
+            zero_amounts = false
+
+            [tenants.acme]
+            lock_scope = "account"
+            locked_accepts = "deposits"
+            zero_amounts = true
+
+            [tenants.globex]
+            dispute_below_zero = false
+            redispute = false
+            "#,
+        )
+        .unwrap();
+
+        let rules = Rules {
+            duplicate_tx: DuplicateTx::Skip,
+            zero_amounts: false,
+            ..Rules::default()
+        };
+        assert_eq!(policy.rules, rules);
+        assert_eq!(policy.rules_for("initech"), &rules);
+
+        // tenants only override what they set
+        assert_eq!(
+            policy.rules_for("acme"),
+            &Rules {
+                lock_scope: LockScope::Account,
+                locked_accepts: LockedAccepts::Deposits,
+                zero_amounts: true,
+                ..rules.clone()
+            },
+        );
+        assert_eq!(
+            policy.rules_for("globex"),
+            &Rules {
+                dispute_below_zero: false,
+                redispute: false,
+                ..rules.clone()
+            },
+        );
+    }
+
+    #[test]
+    fn fraud() {
+        let policy = Policy::parse(
+            r#"
+            [[fraud]]
+            rule = "velocity"
+            max = 3
+            within_minutes = 10
+            action = "hold"
+
+            [[fraud]]
+            rule = "velocity"
+            name = "velocity_long"
+            max = 20
+            within_records = 1000
+            action = "flag"
+
+            [[fraud]]
+            rule = "drain_after_first_deposit"
+            percent = 90
+            within_records = 5
+            action = "reject"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            policy.fraud,
+            vec![
+                FraudRule {
+                    name: "velocity".to_string(),
+                    check: FraudCheck::Velocity { max: 3 },
+                    window: Window::Minutes(10),
+                    action: FraudAction::Hold,
+                },
+                FraudRule {
+                    name: "velocity_long".to_string(),
+                    check: FraudCheck::Velocity { max: 20 },
+                    window: Window::Records(1000),
+                    action: FraudAction::Flag,
+                },
+                FraudRule {
+                    name: "drain_after_first_deposit".to_string(),
+                    check: FraudCheck::DrainAfterFirstDeposit { percent: 90 },
+                    window: Window::Records(5),
+                    action: FraudAction::Reject,
+                },
+            ],
+        );
+
+        for toml in [
+            "[[fraud]]\nrule = \"velocity\"\nwithin_records = 5\naction = \"flag\"",
+            "[[fraud]]\nrule = \"velocity\"\nmax = 1\naction = \"flag\"",
+            "[[fraud]]\nrule = \"velocity\"\nmax = 1\nwithin_records = 0\naction = \"flag\"",
+            "[[fraud]]\nrule = \"velocity\"\nmax = 1\nwithin_records = 5\naction = \"block\"",
+            "[[fraud]]\nrule = \"drain_after_first_deposit\"\npercent = 101\nwithin_records = 5\naction = \"flag\"",
+            "[[fraud]]\nrule = \"chargebacks\"\nmax = 1\nwithin_records = 5\naction = \"flag\"",
+            "[[fraud]]\nrule = \"velocity\"\nmax = 1\nwithin_records = 5\naction = \"flag\"\n\
+             [[fraud]]\nrule = \"velocity\"\nmax = 2\nwithin_records = 5\naction = \"flag\"",
+        ] {
+            assert!(Policy::parse(toml).is_err(), "{}", toml);
+        }
+    }
+
+    #[test]
+    fn invalid() {
+        for toml in [
+            "[rules]\nunknown = true",
+            "[rules]\nlock_scope = \"tenant\"",
+            "[rules]\nzero_amounts = \"no\"",
+            "[limits]",
+            "[tenants.\"\"]",
+            "[rules",
+        ] {
+            assert!(Policy::parse(toml).is_err(), "{}", toml);
+        }
+    }
+}
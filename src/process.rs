@@ -1,114 +1,492 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, LazyLock},
+};
+
 use crate::{
-    cents::{string_to_cents, Cents},
-    client::{ClientData, ClientId, ClientsMap},
+    amount::Amount,
+    cents::Money,
+    client::{ClientData, ClientId, ClientsMap, LockScope, LockedAccepts, DEFAULT_ACCOUNT},
     input::InputRecord,
     transaction::{
         DepositData, DepositState, TransactionData, TransactionId, TransactionsMap, WithdrawalData,
     },
 };
 
+/*
+Business rules for applying records, see `policy` for where they come from.
+The defaults are the rules from before they were configurable.
+*/
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Rules {
+    pub lock_scope: LockScope,
+    pub duplicate_tx: DuplicateTx,
+    pub locked_accepts: LockedAccepts,
+    // a dispute may hold more than the available funds
+    pub dispute_below_zero: bool,
+    pub zero_amounts: bool,
+    // a resolved deposit may be disputed again
+    pub redispute: bool,
+}
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self {
+            lock_scope: LockScope::Client,
+            duplicate_tx: DuplicateTx::Reject,
+            locked_accepts: LockedAccepts::Nothing,
+            dispute_below_zero: true,
+            zero_amounts: true,
+            redispute: true,
+        }
+    }
+}
+
+/*
+What happens to a deposit or withdrawal that reuses a transaction id: it is
+rejected, or skipped without changing anything.
+*/
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DuplicateTx {
+    #[default]
+    Reject,
+    Skip,
+}
+
+/*
+Why a deposit or withdrawal reusing a transaction id was skipped: it exactly
+repeats the earlier one, or it doesn't and duplicates are skipped.
+*/
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Duplicate {
+    Replay,
+    Skipped,
+}
+
+/*
+Applies a single record to the clients and transactions with the default rules.
+Returns the amount moved by the record or an error.
+*/
 pub fn process_record(
     record: &InputRecord,
     clients: &mut ClientsMap,
     transactions: &mut TransactionsMap,
-) -> Result<(), &'static str> {
-    match record.record_type.as_str() {
-        "deposit" => {
-            check_transaction_id(record.transaction, &transactions)?;
-            let amount = get_amount(&record.amount)?;
-
-            // find or create client
-            let client_id = record.client;
-            if !clients.contains_key(&client_id) {
-                clients.insert(client_id, ClientData::new());
+) -> Result<Money, &'static str> {
+    process_record_with_rules(record, &Rules::default(), clients, transactions)
+}
+
+pub fn process_record_with_rules(
+    record: &InputRecord,
+    rules: &Rules,
+    clients: &mut ClientsMap,
+    transactions: &mut TransactionsMap,
+) -> Result<Money, &'static str> {
+    process_record_with_handlers(record, rules, &BUILT_IN, clients, transactions)
+}
+
+/*
+Applies a single record with the handler registered for its type.
+*/
+pub fn process_record_with_handlers(
+    record: &InputRecord,
+    rules: &Rules,
+    handlers: &Handlers,
+    clients: &mut ClientsMap,
+    transactions: &mut TransactionsMap,
+) -> Result<Money, &'static str> {
+    match handlers.get(record.record_type.name()) {
+        Some(handler) => handler.apply(record, rules, clients, transactions),
+        None => Err("Unsupported transaction type"),
+    }
+}
+
+/*
+Whether the handler registered for the type of a record would skip it as a
+duplicate, see `TransactionHandler::duplicate`. Has to be asked before the
+record is applied.
+*/
+pub fn duplicate_with_handlers(
+    record: &InputRecord,
+    rules: &Rules,
+    handlers: &Handlers,
+    transactions: &TransactionsMap,
+) -> Option<Duplicate> {
+    handlers
+        .get(record.record_type.name())?
+        .duplicate(record, rules, transactions)
+}
+
+/*
+Applies the records of one type, see `Handlers`.
+*/
+pub trait TransactionHandler: Send + Sync {
+    /*
+    Returns the amount moved by the record or why it was rejected. A record
+    that is rejected must not change the clients or transactions.
+    */
+    fn apply(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        clients: &mut ClientsMap,
+        transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str>;
+
+    /*
+    Whether the transaction id of a record is the id of an earlier deposit,
+    like for disputes, so the record can wait for the deposit with --pending.
+    */
+    fn refers_to_deposit(&self) -> bool {
+        false
+    }
+
+    /*
+    Whether the record reuses a transaction id and would be skipped without
+    changing anything, so it isn't counted as applied.
+    */
+    fn duplicate(
+        &self,
+        _record: &InputRecord,
+        _rules: &Rules,
+        _transactions: &TransactionsMap,
+    ) -> Option<Duplicate> {
+        None
+    }
+}
+
+/*
+The handlers of the record types, by type name. The default registry has the
+built-in types; library users can register handlers for types of their own, or
+replace the built-in ones. Type names are case-insensitive, like in
+`RecordTypes`, and kept lowercase.
+*/
+#[derive(Clone)]
+pub struct Handlers {
+    handlers: HashMap<String, Arc<dyn TransactionHandler>>,
+}
+
+static BUILT_IN: LazyLock<Handlers> = LazyLock::new(Handlers::default);
+
+impl Default for Handlers {
+    fn default() -> Self {
+        let mut handlers = Self::empty();
+        handlers
+            .register("deposit", DepositHandler)
+            .register("withdrawal", WithdrawalHandler)
+            .register("dispute", DisputeHandler)
+            .register("resolve", ResolveHandler)
+            .register("chargeback", ChargebackHandler);
+        handlers
+    }
+}
+
+impl Handlers {
+    /*
+    A registry without any handlers, not even the built-in ones.
+    */
+    pub fn empty() -> Self {
+        Self {
+            handlers: HashMap::new(),
+        }
+    }
+
+    /*
+    Registers the handler of a record type, replacing the one it had.
+    */
+    pub fn register(
+        &mut self,
+        record_type: &str,
+        handler: impl TransactionHandler + 'static,
+    ) -> &mut Self {
+        self.handlers
+            .insert(record_type.to_lowercase(), Arc::new(handler));
+        self
+    }
+
+    pub fn get(&self, record_type: &str) -> Option<&dyn TransactionHandler> {
+        // the types of records are already lowercase
+        let handler = match self.handlers.get(record_type) {
+            Some(h) => h,
+            None if record_type.chars().any(char::is_uppercase) => {
+                self.handlers.get(&record_type.to_lowercase())?
             }
-            let client = get_client(client_id, clients)?;
+            None => return None,
+        };
+        Some(handler.as_ref())
+    }
+
+    /*
+    Whether records of the type refer to an earlier deposit.
+    */
+    pub fn refers_to_deposit(&self, record_type: &str) -> bool {
+        self.get(record_type).is_some_and(|h| h.refers_to_deposit())
+    }
+
+    fn types(&self) -> Vec<&str> {
+        let mut types: Vec<&str> = self.handlers.keys().map(String::as_str).collect();
+        types.sort_unstable();
+        types
+    }
+}
+
+impl fmt::Debug for Handlers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_set().entries(self.types()).finish()
+    }
+}
+
+/*
+Handlers can't be compared, registries are equal if they handle the same types.
+*/
+impl PartialEq for Handlers {
+    fn eq(&self, other: &Self) -> bool {
+        self.types() == other.types()
+    }
+}
 
-            // apply deposit to client
-            client.deposit(amount)?;
+pub struct DepositHandler;
 
-            // insert deposit into transactions map
-            let transaction_id = record.transaction;
-            transactions.insert(
-                transaction_id,
-                TransactionData::Deposit(DepositData::new(client_id, amount)),
-            );
+impl TransactionHandler for DepositHandler {
+    fn apply(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        clients: &mut ClientsMap,
+        transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str> {
+        let account = record.account.as_deref().unwrap_or(DEFAULT_ACCOUNT);
+        let amount = get_amount(&record.amount, rules)?;
+        if is_duplicate(record, account, amount, rules, transactions)? {
+            return Ok(Money::ZERO);
+        }
 
-            Ok(())
+        // find the client, a new one is only inserted once the deposit was applied
+        let client_id = record.client;
+        let mut new_client = None;
+        let client = match clients.get_mut(&client_id) {
+            Some(c) => c,
+            None => new_client.insert(ClientData::new()),
+        };
+
+        // apply deposit to client
+        match rules.locked_accepts {
+            LockedAccepts::Nothing => client.deposit(account, amount)?,
+            LockedAccepts::Deposits => client.deposit_to_locked(account, amount)?,
+        }
+        if let Some(client) = new_client {
+            clients.insert(client_id, client);
         }
-        "withdrawal" => {
-            check_transaction_id(record.transaction, &transactions)?;
-            let amount = get_amount(&record.amount)?;
 
-            let client_id = record.client;
-            let client = get_client(client_id, clients)?;
+        // insert deposit into transactions map
+        let transaction_id = record.transaction;
+        transactions.insert(
+            transaction_id,
+            TransactionData::Deposit(DepositData::new(client_id, account, amount)),
+        );
 
-            // apply withdrawal to client
-            client.withdrawal(amount)?;
+        Ok(amount)
+    }
 
-            // insert deposit into transactions map
-            let transaction_id = record.transaction;
-            transactions.insert(
-                transaction_id,
-                TransactionData::Withdrawal(WithdrawalData::new(client_id, amount)),
-            );
+    fn duplicate(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        transactions: &TransactionsMap,
+    ) -> Option<Duplicate> {
+        let account = record.account.as_deref().unwrap_or(DEFAULT_ACCOUNT);
+        let amount = get_amount(&record.amount, rules).ok()?;
+        duplicate_of(record, account, amount, rules, transactions).ok()?
+    }
+}
 
-            Ok(())
+pub struct WithdrawalHandler;
+
+impl TransactionHandler for WithdrawalHandler {
+    fn apply(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        clients: &mut ClientsMap,
+        transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str> {
+        let account = record.account.as_deref().unwrap_or(DEFAULT_ACCOUNT);
+        let amount = get_amount(&record.amount, rules)?;
+        if is_duplicate(record, account, amount, rules, transactions)? {
+            return Ok(Money::ZERO);
         }
-        "dispute" => {
-            let deposit = get_deposit(record.transaction, record.client, transactions)?;
-            check_amount_is_none(&record.amount)?;
-            let client = get_client(record.client, clients)?;
-
-            if deposit.state == DepositState::Ok {
-                client.dispute(deposit.get_amount())?;
-                deposit.state = DepositState::Dispute;
-
-                Ok(())
-            } else {
-                Err("Deposit is not in a disputable state")
+
+        let client_id = record.client;
+        let client = get_client(client_id, clients)?;
+
+        // apply withdrawal to client
+        client.withdrawal(account, amount)?;
+
+        // insert deposit into transactions map
+        let transaction_id = record.transaction;
+        transactions.insert(
+            transaction_id,
+            TransactionData::Withdrawal(WithdrawalData::new(client_id, account, amount)),
+        );
+
+        Ok(amount)
+    }
+
+    fn duplicate(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        transactions: &TransactionsMap,
+    ) -> Option<Duplicate> {
+        let account = record.account.as_deref().unwrap_or(DEFAULT_ACCOUNT);
+        let amount = get_amount(&record.amount, rules).ok()?;
+        duplicate_of(record, account, amount, rules, transactions).ok()?
+    }
+}
+
+pub struct DisputeHandler;
+
+impl TransactionHandler for DisputeHandler {
+    fn apply(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        clients: &mut ClientsMap,
+        transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str> {
+        let (client, deposit) = get_referenced(record, clients, transactions)?;
+
+        let disputable = match deposit.state {
+            DepositState::Ok => true,
+            DepositState::Resolved => rules.redispute,
+            DepositState::Dispute | DepositState::Chargeback => false,
+        };
+        if disputable {
+            if !rules.dispute_below_zero {
+                check_available(client, deposit)?;
             }
+            client.dispute(deposit.get_account(), deposit.get_amount())?;
+            deposit.state = DepositState::Dispute;
+
+            Ok(deposit.get_amount())
+        } else {
+            Err("Deposit is not in a disputable state")
         }
-        "resolve" => {
-            let deposit = get_deposit(record.transaction, record.client, transactions)?;
-            check_amount_is_none(&record.amount)?;
-            let client = get_client(record.client, clients)?;
-
-            if deposit.state == DepositState::Dispute {
-                client.resolve(deposit.get_amount())?;
-                deposit.state = DepositState::Ok;
-
-                Ok(())
-            } else {
-                Err("Deposit is not dispute")
-            }
+    }
+
+    fn refers_to_deposit(&self) -> bool {
+        true
+    }
+}
+
+pub struct ResolveHandler;
+
+impl TransactionHandler for ResolveHandler {
+    fn apply(
+        &self,
+        record: &InputRecord,
+        _rules: &Rules,
+        clients: &mut ClientsMap,
+        transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str> {
+        let (client, deposit) = get_referenced(record, clients, transactions)?;
+
+        if deposit.state == DepositState::Dispute {
+            client.resolve(deposit.get_account(), deposit.get_amount())?;
+            deposit.state = DepositState::Resolved;
+
+            Ok(deposit.get_amount())
+        } else {
+            Err("Deposit is not dispute")
         }
-        "chargeback" => {
-            let deposit = get_deposit(record.transaction, record.client, transactions)?;
-            check_amount_is_none(&record.amount)?;
-            let client = get_client(record.client, clients)?;
-
-            if deposit.state == DepositState::Dispute {
-                client.chargeback(deposit.get_amount())?;
-                deposit.state = DepositState::Chargeback;
-
-                Ok(())
-            } else {
-                Err("Deposit is not dispute")
-            }
+    }
+
+    fn refers_to_deposit(&self) -> bool {
+        true
+    }
+}
+
+pub struct ChargebackHandler;
+
+impl TransactionHandler for ChargebackHandler {
+    fn apply(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        clients: &mut ClientsMap,
+        transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str> {
+        let (client, deposit) = get_referenced(record, clients, transactions)?;
+
+        if deposit.state == DepositState::Dispute {
+            client.chargeback(
+                deposit.get_account(),
+                deposit.get_amount(),
+                rules.lock_scope,
+            )?;
+            deposit.state = DepositState::Chargeback;
+
+            Ok(deposit.get_amount())
+        } else {
+            Err("Deposit is not dispute")
         }
-        _ => Err("Unsupported transaction type"),
+    }
+
+    fn refers_to_deposit(&self) -> bool {
+        true
     }
 }
 
-fn check_transaction_id(
-    id: TransactionId,
+/*
+The client and deposit of a dispute, resolve or chargeback, which has no amount
+and may only name the sub-account of the deposit.
+*/
+pub fn get_referenced<'a>(
+    record: &InputRecord,
+    clients: &'a mut ClientsMap,
+    transactions: &'a mut TransactionsMap,
+) -> Result<(&'a mut ClientData, &'a mut DepositData), &'static str> {
+    let deposit = get_deposit(record.transaction, record.client, transactions)?;
+    check_account(record, deposit)?;
+    check_amount_is_none(&record.amount)?;
+    let client = get_client(record.client, clients)?;
+    Ok((client, deposit))
+}
+
+/*
+Whether the transaction id was used before and the record has to be skipped.
+An exact replay of the transaction is always skipped, any other reuse of the
+id is an error unless duplicates are skipped.
+*/
+pub fn is_duplicate(
+    record: &InputRecord,
+    account: &str,
+    amount: Money,
+    rules: &Rules,
     transactions: &TransactionsMap,
-) -> Result<(), &'static str> {
-    if transactions.contains_key(&id) {
-        Err("Transaction id already exists")
-    } else {
-        Ok(())
+) -> Result<bool, &'static str> {
+    Ok(duplicate_of(record, account, amount, rules, transactions)?.is_some())
+}
+
+/*
+Like `is_duplicate`, but tells a replay from a skipped reuse of the id.
+*/
+pub fn duplicate_of(
+    record: &InputRecord,
+    account: &str,
+    amount: Money,
+    rules: &Rules,
+    transactions: &TransactionsMap,
+) -> Result<Option<Duplicate>, &'static str> {
+    match transactions.get(&record.transaction) {
+        None => Ok(None),
+        Some(t) if t.is_replay(&record.record_type, record.client, account, amount) => {
+            Ok(Some(Duplicate::Replay))
+        }
+        Some(_) if rules.duplicate_tx == DuplicateTx::Skip => Ok(Some(Duplicate::Skipped)),
+        Some(_) => Err("Transaction id already exists"),
     }
 }
 
@@ -118,11 +496,11 @@ Returns an error if a transaction is found but is not a deposit.
 
 Also takes the expected ClientId and makes sure it matches the TransactionData.
 */
-fn get_deposit<'a>(
+fn get_deposit(
     transaction_id: TransactionId,
     client_id: ClientId,
-    transactions: &'a mut TransactionsMap,
-) -> Result<&'a mut DepositData, &'static str> {
+    transactions: &mut TransactionsMap,
+) -> Result<&mut DepositData, &'static str> {
     match transactions.get_mut(&transaction_id) {
         Some(t) => match t {
             TransactionData::Deposit(d) => {
@@ -138,7 +516,18 @@ fn get_deposit<'a>(
     }
 }
 
-fn check_amount_is_none(amount: &Option<String>) -> Result<(), &'static str> {
+/*
+A record that names a sub-account has to name the one of the deposit.
+Without one the deposit's sub-account is used.
+*/
+fn check_account(record: &InputRecord, deposit: &DepositData) -> Result<(), &'static str> {
+    match &record.account {
+        Some(account) if account != deposit.get_account() => Err("Accounts do not match"),
+        _ => Ok(()),
+    }
+}
+
+fn check_amount_is_none(amount: &Option<Amount>) -> Result<(), &'static str> {
     if amount.is_none() {
         Ok(())
     } else {
@@ -146,17 +535,29 @@ fn check_amount_is_none(amount: &Option<String>) -> Result<(), &'static str> {
     }
 }
 
-fn get_amount(amount: &Option<String>) -> Result<Cents, &'static str> {
-    match amount {
-        Some(amount) => string_to_cents(amount),
-        None => Err("Amount missing"),
+pub fn get_amount(amount: &Option<Amount>, rules: &Rules) -> Result<Money, &'static str> {
+    let amount: Money = match amount {
+        Some(amount) => amount.parse()?,
+        None => return Err("Amount missing"),
+    };
+
+    if amount == Money::ZERO && !rules.zero_amounts {
+        Err("Amount may not be zero")
+    } else {
+        Ok(amount)
     }
 }
 
-fn get_client<'a>(
-    id: ClientId,
-    clients: &'a mut ClientsMap,
-) -> Result<&'a mut ClientData, &'static str> {
+fn check_available(client: &ClientData, deposit: &DepositData) -> Result<(), &'static str> {
+    match client.get_account(deposit.get_account()) {
+        Some(account) if account.get_available() < deposit.get_amount() => {
+            Err("Insufficient available funds for dispute")
+        }
+        _ => Ok(()),
+    }
+}
+
+pub fn get_client(id: ClientId, clients: &mut ClientsMap) -> Result<&mut ClientData, &'static str> {
     match clients.get_mut(&id) {
         Some(c) => Ok(c),
         None => Err("Client not found"),
@@ -1,8 +1,14 @@
 use crate::{
-    client::ClientsMap,
+    amount::Amount,
+    cents::{Money, OVERFLOW_ERROR},
+    client::{client_id, ClientsMap, LockScope, LockedAccepts},
     input::InputRecord,
-    process::{get_deposit, process_record},
-    transaction::{DepositState, TransactionsMap},
+    process::{
+        get_amount, get_client, get_deposit, process_record, process_record_with_handlers,
+        process_record_with_rules, DuplicateTx, Handlers, Rules, TransactionHandler,
+    },
+    record_type::{RecordType, RecordTypes},
+    transaction::{tx_id, DepositState, TransactionId, TransactionsMap},
 };
 
 #[test]
@@ -11,10 +17,13 @@ fn deposit_single() {
     let mut transactions = TransactionsMap::new();
 
     let record = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&record, &mut clients, &mut transactions).is_ok());
@@ -22,8 +31,8 @@ fn deposit_single() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
 }
 
 #[test]
@@ -32,19 +41,25 @@ fn deposit_multiple() {
     let mut transactions = TransactionsMap::new();
 
     let record = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&record, &mut clients, &mut transactions).is_ok());
 
     let record = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 2,
-        amount: Some("2.5".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(2),
+        amount: Some("2.5".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&record, &mut clients, &mut transactions).is_ok());
@@ -52,8 +67,8 @@ fn deposit_multiple() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 2);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 3_5000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(3_5000)));
 }
 
 #[test]
@@ -62,23 +77,54 @@ fn deposit_duplicate() {
     let mut transactions = TransactionsMap::new();
 
     let record = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     // original
     assert!(process_record(&record, &mut clients, &mut transactions).is_ok());
 
-    // duplicate
-    assert!(process_record(&record, &mut clients, &mut transactions).is_err());
+    // an exact replay changes nothing
+    assert_eq!(
+        process_record(&record, &mut clients, &mut transactions),
+        Ok(Money::ZERO)
+    );
+
+    // a conflicting reuse of the id
+    for conflict in [
+        InputRecord {
+            amount: Some("2".into()),
+            ..record.clone()
+        },
+        InputRecord {
+            client: client_id(2),
+            ..record.clone()
+        },
+        InputRecord {
+            account: Some("savings".to_string()),
+            ..record.clone()
+        },
+        InputRecord {
+            record_type: RecordType::Withdrawal,
+            ..record.clone()
+        },
+    ] {
+        assert_eq!(
+            process_record(&conflict, &mut clients, &mut transactions),
+            Err("Transaction id already exists")
+        );
+    }
 
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
 }
 
 #[test]
@@ -87,19 +133,25 @@ fn withdrawal_ok() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let withdrawal = InputRecord {
-        record_type: "withdrawal".to_string(),
-        client: 1,
-        transaction: 2,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Withdrawal,
+        client: client_id(1),
+        transaction: tx_id(2),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&withdrawal, &mut clients, &mut transactions).is_ok());
@@ -107,8 +159,8 @@ fn withdrawal_ok() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 2);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 0);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(0)));
 }
 
 #[test]
@@ -117,19 +169,25 @@ fn withdrawal_duplicate() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let withdrawal = InputRecord {
-        record_type: "withdrawal".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Withdrawal,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&withdrawal, &mut clients, &mut transactions).is_err());
@@ -137,8 +195,8 @@ fn withdrawal_duplicate() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
 }
 
 #[test]
@@ -147,19 +205,25 @@ fn withdrawal_invalid_client() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let withdrawal = InputRecord {
-        record_type: "withdrawal".to_string(),
-        client: 2,
-        transaction: 2,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Withdrawal,
+        client: client_id(2),
+        transaction: tx_id(2),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&withdrawal, &mut clients, &mut transactions).is_err());
@@ -167,8 +231,8 @@ fn withdrawal_invalid_client() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
 }
 
 #[test]
@@ -177,19 +241,25 @@ fn withdrawal_insufficent_funds() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let withdrawal = InputRecord {
-        record_type: "withdrawal".to_string(),
-        client: 1,
-        transaction: 2,
-        amount: Some("2".to_string()),
+        record_type: RecordType::Withdrawal,
+        client: client_id(1),
+        transaction: tx_id(2),
+        amount: Some("2".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&withdrawal, &mut clients, &mut transactions).is_err());
@@ -197,8 +267,8 @@ fn withdrawal_insufficent_funds() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
 }
 
 #[test]
@@ -207,19 +277,25 @@ fn dispute_ok() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Dispute,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&dispute, &mut clients, &mut transactions).is_ok());
@@ -227,12 +303,12 @@ fn dispute_ok() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 0);
-    assert_eq!(client.get_held(), 1_0000);
-    assert_eq!(client.get_total(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(0)));
+    assert_eq!(client.get_held(), Ok(Money::from_cents(1_0000)));
+    assert_eq!(client.get_total(), Ok(Money::from_cents(1_0000)));
 
-    let deposit = get_deposit(1, 1, &mut transactions).unwrap();
+    let deposit = get_deposit(tx_id(1), client_id(1), &mut transactions).unwrap();
     assert_eq!(deposit.state, DepositState::Dispute);
 }
 
@@ -242,19 +318,25 @@ fn dispute_with_amount() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Dispute,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&dispute, &mut clients, &mut transactions).is_err());
@@ -262,12 +344,12 @@ fn dispute_with_amount() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
-    assert_eq!(client.get_held(), 0);
-    assert_eq!(client.get_total(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
+    assert_eq!(client.get_held(), Ok(Money::from_cents(0)));
+    assert_eq!(client.get_total(), Ok(Money::from_cents(1_0000)));
 
-    let deposit = get_deposit(1, 1, &mut transactions).unwrap();
+    let deposit = get_deposit(tx_id(1), client_id(1), &mut transactions).unwrap();
     assert_eq!(deposit.state, DepositState::Ok);
 }
 
@@ -277,10 +359,13 @@ fn dispute_invalid_transaction_id() {
     let mut transactions = TransactionsMap::new();
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Dispute,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&dispute, &mut clients, &mut transactions).is_err());
@@ -292,19 +377,25 @@ fn dispute_client_ids_do_not_match() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 2,
-        transaction: 1,
+        record_type: RecordType::Dispute,
+        client: client_id(2),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&dispute, &mut clients, &mut transactions).is_err());
@@ -312,12 +403,12 @@ fn dispute_client_ids_do_not_match() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
-    assert_eq!(client.get_held(), 0);
-    assert_eq!(client.get_total(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
+    assert_eq!(client.get_held(), Ok(Money::from_cents(0)));
+    assert_eq!(client.get_total(), Ok(Money::from_cents(1_0000)));
 
-    let deposit = get_deposit(1, 1, &mut transactions).unwrap();
+    let deposit = get_deposit(tx_id(1), client_id(1), &mut transactions).unwrap();
     assert_eq!(deposit.state, DepositState::Ok);
 }
 
@@ -327,19 +418,25 @@ fn dispute_invalid_state() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Dispute,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     // first dispute succeeds
@@ -355,28 +452,37 @@ fn resolve_ok() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Dispute,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&dispute, &mut clients, &mut transactions).is_ok());
 
     let resolve = InputRecord {
-        record_type: "resolve".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Resolve,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&resolve, &mut clients, &mut transactions).is_ok());
@@ -384,13 +490,13 @@ fn resolve_ok() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
-    assert_eq!(client.get_held(), 0);
-    assert_eq!(client.get_total(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
+    assert_eq!(client.get_held(), Ok(Money::from_cents(0)));
+    assert_eq!(client.get_total(), Ok(Money::from_cents(1_0000)));
 
-    let deposit = get_deposit(1, 1, &mut transactions).unwrap();
-    assert_eq!(deposit.state, DepositState::Ok);
+    let deposit = get_deposit(tx_id(1), client_id(1), &mut transactions).unwrap();
+    assert_eq!(deposit.state, DepositState::Resolved);
 }
 
 #[test]
@@ -399,19 +505,25 @@ fn resolve_invalid_state() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let resolve = InputRecord {
-        record_type: "resolve".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Resolve,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&resolve, &mut clients, &mut transactions).is_err());
@@ -423,28 +535,37 @@ fn chargeback_ok() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Dispute,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&dispute, &mut clients, &mut transactions).is_ok());
 
     let chargeback = InputRecord {
-        record_type: "chargeback".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Chargeback,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&chargeback, &mut clients, &mut transactions).is_ok());
@@ -452,13 +573,13 @@ fn chargeback_ok() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 0);
-    assert_eq!(client.get_held(), 0);
-    assert_eq!(client.get_total(), 0);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(0)));
+    assert_eq!(client.get_held(), Ok(Money::from_cents(0)));
+    assert_eq!(client.get_total(), Ok(Money::from_cents(0)));
     assert!(client.is_locked());
 
-    let deposit = get_deposit(1, 1, &mut transactions).unwrap();
+    let deposit = get_deposit(tx_id(1), client_id(1), &mut transactions).unwrap();
     assert_eq!(deposit.state, DepositState::Chargeback);
 }
 
@@ -468,19 +589,25 @@ fn chargeback_invalid_state() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let chargeback = InputRecord {
-        record_type: "chargeback".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Chargeback,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&chargeback, &mut clients, &mut transactions).is_err());
@@ -488,13 +615,13 @@ fn chargeback_invalid_state() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
-    assert_eq!(client.get_available(), 1_0000);
-    assert_eq!(client.get_held(), 0);
-    assert_eq!(client.get_total(), 1_0000);
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
+    assert_eq!(client.get_held(), Ok(Money::from_cents(0)));
+    assert_eq!(client.get_total(), Ok(Money::from_cents(1_0000)));
     assert!(!client.is_locked());
 
-    let deposit = get_deposit(1, 1, &mut transactions).unwrap();
+    let deposit = get_deposit(tx_id(1), client_id(1), &mut transactions).unwrap();
     assert_eq!(deposit.state, DepositState::Ok);
 }
 
@@ -504,28 +631,37 @@ fn account_locked() {
     let mut transactions = TransactionsMap::new();
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 1,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
 
     let dispute = InputRecord {
-        record_type: "dispute".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Dispute,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&dispute, &mut clients, &mut transactions).is_ok());
 
     let chargeback = InputRecord {
-        record_type: "chargeback".to_string(),
-        client: 1,
-        transaction: 1,
+        record_type: RecordType::Chargeback,
+        client: client_id(1),
+        transaction: tx_id(1),
         amount: None,
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&chargeback, &mut clients, &mut transactions).is_ok());
@@ -533,19 +669,397 @@ fn account_locked() {
     assert_eq!(clients.len(), 1);
     assert_eq!(transactions.len(), 1);
 
-    let client = clients.get(&1).unwrap();
+    let client = clients.get(&client_id(1)).unwrap();
     assert!(client.is_locked());
 
     // now that the account is locked additional deposits should fail
 
     let deposit = InputRecord {
-        record_type: "deposit".to_string(),
-        client: 1,
-        transaction: 2,
-        amount: Some("1".to_string()),
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(2),
+        amount: Some("1".into()),
+        account: None,
+        tenant: None,
+        time: None,
     };
 
     assert!(process_record(&deposit, &mut clients, &mut transactions).is_err());
 
     assert_eq!(transactions.len(), 1);
 }
+
+#[test]
+fn deposit_overflow() {
+    let mut clients = ClientsMap::new();
+    let mut transactions = TransactionsMap::new();
+
+    let deposit = InputRecord {
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(1),
+        amount: Some(Money::MAX.to_string().into()),
+        account: None,
+        tenant: None,
+        time: None,
+    };
+
+    assert!(process_record(&deposit, &mut clients, &mut transactions).is_ok());
+
+    let deposit = InputRecord {
+        record_type: RecordType::Deposit,
+        client: client_id(1),
+        transaction: tx_id(2),
+        amount: Some("0.0001".into()),
+        account: None,
+        tenant: None,
+        time: None,
+    };
+
+    assert_eq!(
+        process_record(&deposit, &mut clients, &mut transactions),
+        Err(OVERFLOW_ERROR),
+    );
+
+    // the rejected deposit is not stored and the balance is unchanged
+    assert_eq!(transactions.len(), 1);
+
+    let client = clients.get(&client_id(1)).unwrap();
+    assert_eq!(client.get_available(), Ok(Money::MAX));
+}
+
+fn account_record(
+    record_type: &str,
+    tx: TransactionId,
+    amount: Option<&str>,
+    account: &str,
+) -> InputRecord {
+    InputRecord {
+        record_type: RecordType::named(record_type),
+        client: client_id(1),
+        transaction: tx,
+        amount: amount.map(Amount::from),
+        account: Some(account.to_string()),
+        tenant: None,
+        time: None,
+    }
+}
+
+#[test]
+fn sub_accounts() {
+    let mut clients = ClientsMap::new();
+    let mut transactions = TransactionsMap::new();
+    let rules = Rules {
+        lock_scope: LockScope::Account,
+        ..Rules::default()
+    };
+
+    for record in [
+        account_record("deposit", tx_id(1), Some("10"), "main"),
+        account_record("deposit", tx_id(2), Some("5"), "escrow"),
+        account_record("dispute", tx_id(2), None, "escrow"),
+        account_record("chargeback", tx_id(2), None, "escrow"),
+        account_record("withdrawal", tx_id(3), Some("1"), "main"),
+    ] {
+        assert!(
+            process_record_with_rules(&record, &rules, &mut clients, &mut transactions).is_ok()
+        );
+    }
+
+    // the dispute names the wrong sub-account
+    let dispute = account_record("dispute", tx_id(1), None, "savings");
+    assert_eq!(
+        process_record_with_rules(&dispute, &rules, &mut clients, &mut transactions),
+        Err("Accounts do not match"),
+    );
+
+    // the roll-up of the output isn't a sub-account
+    let deposit = account_record("deposit", tx_id(4), Some("1"), "*");
+    assert_eq!(
+        process_record_with_rules(&deposit, &rules, &mut clients, &mut transactions),
+        Err("The account name * is the client roll-up"),
+    );
+    assert!(!transactions.contains_key(&tx_id(4)));
+
+    // a rejected first deposit doesn't create the client
+    let deposit = InputRecord {
+        client: client_id(2),
+        ..account_record("deposit", tx_id(5), Some("1"), "*")
+    };
+    assert!(process_record_with_rules(&deposit, &rules, &mut clients, &mut transactions).is_err());
+    assert!(!clients.contains_key(&client_id(2)));
+
+    // only the escrow sub-account is locked
+    let client = clients.get(&client_id(1)).unwrap();
+    assert!(!client.get_account("main").unwrap().is_locked());
+    assert!(client.get_account("escrow").unwrap().is_locked());
+    assert_eq!(client.get_total(), Ok(Money::from_cents(9_0000)));
+}
+
+/*
+Applies the records with the rules and returns the outcome of the last one.
+*/
+fn apply_all(rules: &Rules, records: &[InputRecord]) -> (Result<Money, &'static str>, ClientsMap) {
+    let mut clients = ClientsMap::new();
+    let mut transactions = TransactionsMap::new();
+
+    let mut outcome = Ok(Money::ZERO);
+    for record in records {
+        outcome = process_record_with_rules(record, rules, &mut clients, &mut transactions);
+    }
+    (outcome, clients)
+}
+
+#[test]
+fn duplicate_tx_rule() {
+    let records = [
+        account_record("deposit", tx_id(1), Some("1"), "main"),
+        account_record("withdrawal", tx_id(1), Some("1"), "main"),
+    ];
+
+    let (outcome, _) = apply_all(&Rules::default(), &records);
+    assert_eq!(outcome, Err("Transaction id already exists"));
+
+    let rules = Rules {
+        duplicate_tx: DuplicateTx::Skip,
+        ..Rules::default()
+    };
+    let (outcome, clients) = apply_all(&rules, &records);
+    assert_eq!(outcome, Ok(Money::ZERO));
+    assert_eq!(
+        clients.get(&client_id(1)).unwrap().get_available(),
+        Ok(Money::from_cents(1_0000))
+    );
+}
+
+#[test]
+fn locked_accepts_rule() {
+    let records = [
+        account_record("deposit", tx_id(1), Some("1"), "main"),
+        account_record("dispute", tx_id(1), None, "main"),
+        account_record("chargeback", tx_id(1), None, "main"),
+        account_record("deposit", tx_id(2), Some("2"), "main"),
+    ];
+
+    let (outcome, _) = apply_all(&Rules::default(), &records);
+    assert!(outcome.is_err());
+
+    let rules = Rules {
+        locked_accepts: LockedAccepts::Deposits,
+        ..Rules::default()
+    };
+    let (outcome, clients) = apply_all(&rules, &records);
+    assert!(outcome.is_ok());
+    assert_eq!(
+        clients.get(&client_id(1)).unwrap().get_available(),
+        Ok(Money::from_cents(2_0000))
+    );
+
+    // nothing else is accepted
+    let mut records = records.to_vec();
+    records.push(account_record("withdrawal", tx_id(3), Some("1"), "main"));
+    let (outcome, _) = apply_all(&rules, &records);
+    assert!(outcome.is_err());
+}
+
+#[test]
+fn dispute_below_zero_rule() {
+    let records = [
+        account_record("deposit", tx_id(1), Some("2"), "main"),
+        account_record("withdrawal", tx_id(2), Some("1.5"), "main"),
+        account_record("dispute", tx_id(1), None, "main"),
+    ];
+
+    let (outcome, clients) = apply_all(&Rules::default(), &records);
+    assert!(outcome.is_ok());
+    assert_eq!(
+        clients.get(&client_id(1)).unwrap().get_available(),
+        Ok(Money::from_cents(-1_5000))
+    );
+
+    let rules = Rules {
+        dispute_below_zero: false,
+        ..Rules::default()
+    };
+    let (outcome, clients) = apply_all(&rules, &records);
+    assert_eq!(outcome, Err("Insufficient available funds for dispute"));
+    assert_eq!(
+        clients.get(&client_id(1)).unwrap().get_held(),
+        Ok(Money::ZERO)
+    );
+}
+
+#[test]
+fn zero_amounts_rule() {
+    let records = [account_record("deposit", tx_id(1), Some("0.00"), "main")];
+
+    let (outcome, _) = apply_all(&Rules::default(), &records);
+    assert_eq!(outcome, Ok(Money::ZERO));
+
+    let rules = Rules {
+        zero_amounts: false,
+        ..Rules::default()
+    };
+    let (outcome, clients) = apply_all(&rules, &records);
+    assert_eq!(outcome, Err("Amount may not be zero"));
+    assert!(clients.is_empty());
+}
+
+#[test]
+fn redispute_rule() {
+    let records = [
+        account_record("deposit", tx_id(1), Some("1"), "main"),
+        account_record("dispute", tx_id(1), None, "main"),
+        account_record("resolve", tx_id(1), None, "main"),
+        account_record("dispute", tx_id(1), None, "main"),
+    ];
+
+    let (outcome, _) = apply_all(&Rules::default(), &records);
+    assert!(outcome.is_ok());
+
+    let rules = Rules {
+        redispute: false,
+        ..Rules::default()
+    };
+    let (outcome, clients) = apply_all(&rules, &records);
+    assert_eq!(outcome, Err("Deposit is not in a disputable state"));
+    assert_eq!(
+        clients.get(&client_id(1)).unwrap().get_held(),
+        Ok(Money::ZERO)
+    );
+}
+
+// a bonus of up to 10 for an existing client, which can't be disputed
+struct BonusHandler;
+
+impl TransactionHandler for BonusHandler {
+    fn apply(
+        &self,
+        record: &InputRecord,
+        rules: &Rules,
+        clients: &mut ClientsMap,
+        _transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str> {
+        let amount = get_amount(&record.amount, rules)?;
+        if amount > Money::from_cents(10_0000) {
+            return Err("Bonus over 10");
+        }
+        get_client(record.client, clients)?.deposit("main", amount)?;
+        Ok(amount)
+    }
+}
+
+struct NoWithdrawals;
+
+impl TransactionHandler for NoWithdrawals {
+    fn apply(
+        &self,
+        _record: &InputRecord,
+        _rules: &Rules,
+        _clients: &mut ClientsMap,
+        _transactions: &mut TransactionsMap,
+    ) -> Result<Money, &'static str> {
+        Err("Withdrawals are closed")
+    }
+}
+
+#[test]
+fn handlers() {
+    let mut handlers = Handlers::default();
+    handlers
+        .register("bonus", BonusHandler)
+        .register("withdrawal", NoWithdrawals);
+    let rules = Rules::default();
+    let mut clients = ClientsMap::new();
+    let mut transactions = TransactionsMap::new();
+    let mut apply = |record: InputRecord| {
+        process_record_with_handlers(&record, &rules, &handlers, &mut clients, &mut transactions)
+    };
+
+    assert_eq!(
+        apply(account_record("bonus", tx_id(1), Some("5"), "main")),
+        Err("Client not found")
+    );
+    assert!(apply(account_record("deposit", tx_id(1), Some("1"), "main")).is_ok());
+    assert_eq!(
+        apply(account_record("bonus", tx_id(2), Some("5"), "main")),
+        Ok(Money::from_cents(5_0000))
+    );
+    assert_eq!(
+        apply(account_record("bonus", tx_id(3), Some("10.01"), "main")),
+        Err("Bonus over 10")
+    );
+    assert_eq!(
+        apply(account_record("dispute", tx_id(2), None, "main")),
+        Err("Transaction not found")
+    );
+    assert_eq!(
+        apply(account_record("withdrawal", tx_id(4), Some("1"), "main")),
+        Err("Withdrawals are closed")
+    );
+    assert_eq!(
+        clients.get(&client_id(1)).unwrap().get_available(),
+        Ok(Money::from_cents(6_0000))
+    );
+
+    // only the registered types are supported
+    let record = account_record("bonus", tx_id(5), Some("1"), "main");
+    assert_eq!(
+        process_record_with_rules(&record, &rules, &mut clients, &mut transactions),
+        Err("Unsupported transaction type")
+    );
+    let deposit = account_record("deposit", tx_id(6), Some("1"), "main");
+    assert_eq!(
+        process_record_with_handlers(
+            &deposit,
+            &rules,
+            &Handlers::empty(),
+            &mut clients,
+            &mut transactions
+        ),
+        Err("Unsupported transaction type")
+    );
+
+    assert!(Handlers::default().refers_to_deposit("chargeback"));
+    assert!(!handlers.refers_to_deposit("bonus"));
+    assert!(!handlers.refers_to_deposit("unknown"));
+}
+
+#[test]
+fn handler_names() {
+    // a custom type is read with its lowercase name, whatever the case it was
+    // registered and added with
+    let mut handlers = Handlers::default();
+    handlers.register("Bonus", BonusHandler);
+    let mut record_types = RecordTypes::default();
+    record_types.custom("BONUS").unwrap();
+
+    let record_type = record_types.parse("bOnUs").unwrap();
+    assert_eq!(record_type, RecordType::named("bonus"));
+    assert!(handlers.get(record_type.name()).is_some());
+    assert!(handlers.get("BONUS").is_some());
+    assert!(handlers.get("Deposit").is_some());
+
+    let rules = Rules::default();
+    let mut clients = ClientsMap::new();
+    let mut transactions = TransactionsMap::new();
+    let deposit = account_record("deposit", tx_id(1), Some("1"), "main");
+    let bonus = InputRecord {
+        record_type,
+        ..account_record("", tx_id(2), Some("5"), "main")
+    };
+    for record in [deposit, bonus] {
+        assert!(process_record_with_handlers(
+            &record,
+            &rules,
+            &handlers,
+            &mut clients,
+            &mut transactions
+        )
+        .is_ok());
+    }
+    assert_eq!(
+        clients.get(&client_id(1)).unwrap().get_available(),
+        Ok(Money::from_cents(6_0000))
+    );
+}
@@ -0,0 +1,247 @@
+use serde::{de, Deserialize, Deserializer};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    sync::OnceLock,
+};
+
+/*
+The type of a record. Records of a custom type are applied by a handler that a
+library user registered for it, see `process::Handlers`.
+*/
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum RecordType {
+    Deposit,
+    Withdrawal,
+    Dispute,
+    Resolve,
+    Chargeback,
+    Custom(String),
+}
+
+impl RecordType {
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Deposit => "deposit",
+            Self::Withdrawal => "withdrawal",
+            Self::Dispute => "dispute",
+            Self::Resolve => "resolve",
+            Self::Chargeback => "chargeback",
+            Self::Custom(name) => name,
+        }
+    }
+
+    /*
+    The type with exactly this name, a custom type unless it is the name of a
+    built-in one. Case and aliases are up to `RecordTypes::parse`.
+    */
+    pub fn named(name: &str) -> Self {
+        Self::built_in(name).unwrap_or_else(|| Self::Custom(name.to_string()))
+    }
+
+    fn built_in(name: &str) -> Option<Self> {
+        match name {
+            "deposit" => Some(Self::Deposit),
+            "withdrawal" => Some(Self::Withdrawal),
+            "dispute" => Some(Self::Dispute),
+            "resolve" => Some(Self::Resolve),
+            "chargeback" => Some(Self::Chargeback),
+            _ => None,
+        }
+    }
+
+    /*
+    Deposits and withdrawals need an amount, disputes, resolves and
+    chargebacks can't have one. Custom types are left to their handler.
+    */
+    pub fn check_amount(&self, amount: Option<&str>) -> Result<(), &'static str> {
+        match (self, amount) {
+            (Self::Deposit, None) => Err("Deposits need an amount"),
+            (Self::Withdrawal, None) => Err("Withdrawals need an amount"),
+            (Self::Dispute, Some(_)) => Err("Disputes can't have an amount"),
+            (Self::Resolve, Some(_)) => Err("Resolves can't have an amount"),
+            (Self::Chargeback, Some(_)) => Err("Chargebacks can't have an amount"),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl fmt::Display for RecordType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/*
+Read like the type of an input record, with the record types of the run.
+*/
+impl<'de> Deserialize<'de> for RecordType {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        RecordTypes::current()
+            .parse(&name)
+            .map_err(de::Error::custom)
+    }
+}
+
+const BUILT_IN: [RecordType; 5] = [
+    RecordType::Deposit,
+    RecordType::Withdrawal,
+    RecordType::Dispute,
+    RecordType::Resolve,
+    RecordType::Chargeback,
+];
+
+/*
+The record types the input may have besides the built-in ones, and aliases of
+type names such as `withdraw` for `withdrawal`. Type names and aliases are read
+case-insensitively, and custom types are known by their lowercase name.
+*/
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RecordTypes {
+    aliases: BTreeMap<String, RecordType>,
+    custom: BTreeSet<String>,
+}
+
+static RECORD_TYPES: OnceLock<RecordTypes> = OnceLock::new();
+
+static BUILT_IN_ONLY: RecordTypes = RecordTypes {
+    aliases: BTreeMap::new(),
+    custom: BTreeSet::new(),
+};
+
+impl RecordTypes {
+    /*
+    Adds a custom type, for the input of a handler registered for it.
+    */
+    pub fn custom(&mut self, name: &str) -> Result<&mut Self, &'static str> {
+        let name = name.to_lowercase();
+        if name.is_empty() {
+            return Err("A record type needs a name");
+        }
+        if self.parse(&name).is_ok() {
+            return Err("The record type or alias is given more than once");
+        }
+        self.custom.insert(name);
+        Ok(self)
+    }
+
+    /*
+    Adds an alias of a built-in or custom type.
+    */
+    pub fn alias(&mut self, alias: &str, name: &str) -> Result<&mut Self, &'static str> {
+        let alias = alias.to_lowercase();
+        if alias.is_empty() {
+            return Err("An alias needs a name");
+        }
+        if self.parse(&alias).is_ok() {
+            return Err("The record type or alias is given more than once");
+        }
+        let record_type = self
+            .parse(name)
+            .map_err(|_| "An alias has to be for a known record type")?;
+        self.aliases.insert(alias, record_type);
+        Ok(self)
+    }
+
+    /*
+    Sets the record types for the rest of the run. Can only be done once,
+    before any input was read.
+    */
+    pub fn set(self) -> Result<(), &'static str> {
+        RECORD_TYPES
+            .set(self)
+            .map_err(|_| "The record types were already set")
+    }
+
+    /*
+    The record types of the run, used to read the type of every record.
+    */
+    pub fn current() -> &'static Self {
+        RECORD_TYPES.get().unwrap_or(&BUILT_IN_ONLY)
+    }
+
+    pub fn parse(&self, name: &str) -> Result<RecordType, &'static str> {
+        // no allocation for the built-in types, which can't be aliases
+        for record_type in BUILT_IN {
+            if name.eq_ignore_ascii_case(record_type.name()) {
+                return Ok(record_type);
+            }
+        }
+        let name = name.to_lowercase();
+        if let Some(record_type) = self.aliases.get(&name) {
+            return Ok(record_type.clone());
+        }
+        if self.custom.contains(&name) {
+            return Ok(RecordType::Custom(name));
+        }
+        Err("Unknown record type")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordType, RecordTypes};
+
+    #[test]
+    fn parse() {
+        let types = RecordTypes::default();
+        assert_eq!(types.parse("deposit"), Ok(RecordType::Deposit));
+        assert_eq!(types.parse("ChargeBack"), Ok(RecordType::Chargeback));
+        assert_eq!(types.parse("withdraw"), Err("Unknown record type"));
+        assert_eq!(types.parse("bonus"), Err("Unknown record type"));
+
+        let mut types = RecordTypes::default();
+        types
+            .custom("Bonus")
+            .unwrap()
+            .alias("withdraw", "withdrawal")
+            .unwrap()
+            .alias("CB", "chargeback")
+            .unwrap()
+            .alias("gift", "bonus")
+            .unwrap();
+        assert_eq!(types.parse("WITHDRAW"), Ok(RecordType::Withdrawal));
+        assert_eq!(types.parse("cb"), Ok(RecordType::Chargeback));
+        assert_eq!(
+            types.parse("bonus"),
+            Ok(RecordType::Custom("bonus".to_string()))
+        );
+        assert_eq!(
+            types.parse("Gift"),
+            Ok(RecordType::Custom("bonus".to_string()))
+        );
+
+        // names are only given once, aliases are for known types
+        assert!(types.alias("deposit", "withdrawal").is_err());
+        assert!(types.alias("cb", "dispute").is_err());
+        assert!(types.custom("withdraw").is_err());
+        assert!(types.alias("adjust", "adjustment").is_err());
+    }
+
+    #[test]
+    fn deserialize() {
+        assert_eq!(
+            serde_json::from_str::<RecordType>(r#""Deposit""#).unwrap(),
+            RecordType::Deposit
+        );
+        assert!(serde_json::from_str::<RecordType>(r#""bonus""#).is_err());
+        assert!(serde_json::from_str::<RecordType>("1").is_err());
+    }
+
+    #[test]
+    fn check_amount() {
+        let amount = Some("1");
+        assert_eq!(
+            RecordType::Deposit.check_amount(None),
+            Err("Deposits need an amount")
+        );
+        assert_eq!(RecordType::Withdrawal.check_amount(amount), Ok(()));
+        assert_eq!(
+            RecordType::Resolve.check_amount(amount),
+            Err("Resolves can't have an amount")
+        );
+        assert_eq!(RecordType::Dispute.check_amount(None), Ok(()));
+        assert_eq!(RecordType::named("bonus").check_amount(None), Ok(()));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::{
+    input::InputRecord,
+    output::{CsvRows, Output},
+    sources::Location,
+};
+
+/*
+Where rejected records go.
+Every rejection is logged to stderr. If a rejects file was given the record
+is also written there as CSV, together with the line and the reason.
+With several input files the rows start with the file the record came from.
+The rejects file only appears once `finish` is called, except with checkpoints.
+*/
+pub struct Rejects {
+    rows: CsvRows,
+}
+
+const HEADER: [&str; 7] = ["source", "line", "type", "client", "tx", "amount", "error"];
+const WRITE_ERROR: &str = "Failed to write rejects file";
+
+impl Default for Rejects {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Rejects {
+    pub fn new() -> Self {
+        Self {
+            rows: CsvRows::none(WRITE_ERROR),
+        }
+    }
+
+    pub fn open(filename: Option<&str>, sources: bool) -> Result<Self, &'static str> {
+        let rows = CsvRows::open(filename, &HEADER, sources, WRITE_ERROR)?;
+        Ok(Self { rows })
+    }
+
+    /*
+    The rejects file of a run with checkpoints, see `CsvRows::open_checkpointed`.
+    */
+    pub fn open_checkpointed(
+        filename: Option<&str>,
+        resume_at: Option<u64>,
+    ) -> Result<Self, &'static str> {
+        let rows = CsvRows::open_checkpointed(filename, &HEADER, resume_at, WRITE_ERROR)?;
+        Ok(Self { rows })
+    }
+
+    pub fn to_output(out: Output, sources: bool) -> Result<Self, &'static str> {
+        let rows = CsvRows::to_output(out, &HEADER, sources, WRITE_ERROR)?;
+        Ok(Self { rows })
+    }
+
+    /*
+    `record` is None if the line could not be parsed.
+    */
+    pub fn reject(
+        &mut self,
+        location: &Location,
+        record: Option<&InputRecord>,
+        error: &'static str,
+    ) -> Result<(), &'static str> {
+        eprintln!("{}: {}", location, error);
+        if !self.rows.is_open() {
+            return Ok(());
+        }
+
+        let source = location.source.as_deref().unwrap_or_default();
+        let row = match record {
+            Some(r) => [
+                source.to_string(),
+                location.line.to_string(),
+                r.record_type.to_string(),
+                r.client.to_string(),
+                r.transaction.to_string(),
+                r.amount.as_deref().unwrap_or_default().to_string(),
+                error.to_string(),
+            ],
+            None => [
+                source.to_string(),
+                location.line.to_string(),
+                String::new(),
+                String::new(),
+                String::new(),
+                String::new(),
+                error.to_string(),
+            ],
+        };
+
+        self.rows.write(&row)
+    }
+
+    /*
+    The length of the rejects file so far, None without one.
+    */
+    pub fn checkpoint(&mut self) -> Result<Option<u64>, &'static str> {
+        self.rows.checkpoint()
+    }
+
+    pub fn finish(self) -> Result<(), &'static str> {
+        self.rows.finish()
+    }
+}
@@ -0,0 +1,440 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    io::Write,
+    time::Instant,
+};
+
+use crate::{
+    cents::Money, client::ClientId, input::InputRecord, output::AtomicFile, policy::Policy,
+    process::Duplicate, record_type::RecordType, state::DEFAULT_TENANT,
+};
+
+/*
+How a run that got to the end of its input went.
+*/
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    // every record was applied
+    Clean,
+    // some records were rejected, the rest was applied
+    Rejections,
+}
+
+/*
+Summary of a run, written as JSON at the end of processing.
+Amounts are formatted the same way as the accounts output.
+A checkpoint saves the report too, see `resume`.
+*/
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Report {
+    records_read: u64,
+    records_unparsed: u64,
+    // side records emitted by a script, also counted as read
+    #[serde(skip_serializing_if = "is_zero")]
+    records_emitted: u64,
+    records: BTreeMap<String, RecordCounts>,
+    // the reasons are static, only a resumed report has owned ones
+    rejections: BTreeMap<Cow<'static, str>, u64>,
+    // amounts normalized per kind of change, only with --lenient-amounts
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    normalized: BTreeMap<Cow<'static, str>, u64>,
+    // only with --pending
+    #[serde(skip_serializing_if = "PendingCounts::is_empty")]
+    pending: PendingCounts,
+    // records that broke a fraud rule, by rule name
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    fraud_hits: BTreeMap<String, u64>,
+    // records tagged by a script, by tag
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    tags: BTreeMap<String, u64>,
+    // records applied although their after_apply hook failed
+    #[serde(skip_serializing_if = "is_zero")]
+    hook_failures: u64,
+    totals: Totals,
+    // clients of the DEFAULT_TENANT, the others are by tenant
+    newly_locked: BTreeSet<ClientId>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    newly_locked_by_tenant: BTreeMap<String, BTreeSet<ClientId>>,
+    // the effective business rules
+    #[serde(skip_deserializing)]
+    policy: Policy,
+    #[serde(skip_deserializing)]
+    duration_secs: f64,
+    #[serde(skip)]
+    started: Instant,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+struct RecordCounts {
+    read: u64,
+    applied: u64,
+    rejected: u64,
+    // reused transaction ids, skipped without changing anything
+    #[serde(default, skip_serializing_if = "is_zero")]
+    replayed: u64,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    skipped: u64,
+}
+
+/*
+Records that were parked until the transaction they refer to arrived, and how
+many of those it did or didn't.
+*/
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+struct PendingCounts {
+    parked: u64,
+    matched: u64,
+    unmatched: u64,
+}
+
+impl PendingCounts {
+    fn is_empty(&self) -> bool {
+        self.parked == 0
+    }
+}
+
+/*
+Sums over the whole run can get larger than any single balance.
+A total that overflowed is None and written as null.
+*/
+#[derive(Clone, Deserialize, Serialize)]
+struct Totals {
+    deposited: Option<Money>,
+    withdrawn: Option<Money>,
+    disputed: Option<Money>,
+    charged_back: Option<Money>,
+}
+
+impl Default for Totals {
+    fn default() -> Self {
+        Self {
+            deposited: Some(Money::ZERO),
+            withdrawn: Some(Money::ZERO),
+            disputed: Some(Money::ZERO),
+            charged_back: Some(Money::ZERO),
+        }
+    }
+}
+
+fn is_zero(count: &u64) -> bool {
+    *count == 0
+}
+
+fn add_to_total(total: &mut Option<Money>, amount: Money) {
+    *total = total.and_then(|t| t.checked_add(amount).ok());
+}
+
+impl Default for Report {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Self {
+            records_read: 0,
+            records_unparsed: 0,
+            records_emitted: 0,
+            records: BTreeMap::new(),
+            rejections: BTreeMap::new(),
+            normalized: BTreeMap::new(),
+            pending: PendingCounts::default(),
+            fraud_hits: BTreeMap::new(),
+            tags: BTreeMap::new(),
+            hook_failures: 0,
+            totals: Totals::default(),
+            newly_locked: BTreeSet::new(),
+            newly_locked_by_tenant: BTreeMap::new(),
+            policy: Policy::default(),
+            duration_secs: 0.0,
+            started: Instant::now(),
+        }
+    }
+
+    /*
+    A line that could not be parsed into an InputRecord.
+    It has no record type, so it is only counted as read and rejected.
+    */
+    pub fn unparsed(&mut self, error: &'static str) {
+        self.records_read += 1;
+        self.records_unparsed += 1;
+        *self.rejections.entry(Cow::Borrowed(error)).or_insert(0) += 1;
+    }
+
+    /*
+    A record that was successfully applied.
+    `amount` is the amount the record moved, as returned by `process_record`.
+    */
+    pub fn applied(&mut self, record: &InputRecord, amount: Money) {
+        self.records_read += 1;
+        let counts = self.counts(record.record_type.name());
+        counts.read += 1;
+        counts.applied += 1;
+
+        match record.record_type {
+            RecordType::Deposit => add_to_total(&mut self.totals.deposited, amount),
+            RecordType::Withdrawal => add_to_total(&mut self.totals.withdrawn, amount),
+            RecordType::Dispute => add_to_total(&mut self.totals.disputed, amount),
+            RecordType::Chargeback => {
+                add_to_total(&mut self.totals.charged_back, amount);
+                // a successful chargeback always locks the account
+                match &record.tenant {
+                    Some(tenant) if tenant != DEFAULT_TENANT => {
+                        self.newly_locked_by_tenant
+                            .entry(tenant.clone())
+                            .or_default()
+                            .insert(record.client);
+                    }
+                    _ => {
+                        self.newly_locked.insert(record.client);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /*
+    A deposit or withdrawal that reused a transaction id and was skipped, see
+    `process::Duplicate`. It is neither applied nor rejected.
+    */
+    pub fn duplicate(&mut self, record: &InputRecord, duplicate: Duplicate) {
+        self.records_read += 1;
+        let counts = self.counts(record.record_type.name());
+        counts.read += 1;
+        match duplicate {
+            Duplicate::Replay => counts.replayed += 1,
+            Duplicate::Skipped => counts.skipped += 1,
+        }
+    }
+
+    /*
+    Continues the counts of the run that saved a checkpoint. The policy and the
+    duration stay the ones of this run.
+    */
+    pub fn resume(&mut self, saved: Report) {
+        *self = Self {
+            policy: std::mem::take(&mut self.policy),
+            started: self.started,
+            ..saved
+        };
+    }
+
+    pub fn policy(&mut self, policy: &Policy) {
+        self.policy = policy.clone();
+    }
+
+    /*
+    An amount that the lenient parser had to normalize, see `lenient::normalize`.
+    */
+    pub fn normalized(&mut self, changes: &[&'static str]) {
+        for change in changes {
+            *self.normalized.entry(Cow::Borrowed(*change)).or_insert(0) += 1;
+        }
+    }
+
+    /*
+    A record that waits for its transaction and isn't applied or rejected yet.
+    */
+    pub fn parked(&mut self) {
+        self.pending.parked += 1;
+    }
+
+    /*
+    A parked record that is applied now, before being counted as applied or
+    rejected.
+    */
+    pub fn unparked(&mut self, matched: bool) {
+        if matched {
+            self.pending.matched += 1;
+        } else {
+            self.pending.unmatched += 1;
+        }
+    }
+
+    /*
+    A record that broke a fraud rule, whatever the rule's action.
+    */
+    pub fn fraud_hit(&mut self, rule: &str) {
+        match self.fraud_hits.get_mut(rule) {
+            Some(count) => *count += 1,
+            None => {
+                self.fraud_hits.insert(rule.to_string(), 1);
+            }
+        }
+    }
+
+    /*
+    A record that a script gave a tag.
+    */
+    pub fn tagged(&mut self, tag: &str) {
+        match self.tags.get_mut(tag) {
+            Some(count) => *count += 1,
+            None => {
+                self.tags.insert(tag.to_string(), 1);
+            }
+        }
+    }
+
+    /*
+    A record that was applied although its after_apply hook failed.
+    */
+    pub fn hook_failed(&mut self) {
+        self.hook_failures += 1;
+    }
+
+    /*
+    A side record emitted by a script, before being counted as applied or
+    rejected like a record read from the input.
+    */
+    pub fn emitted(&mut self) {
+        self.records_emitted += 1;
+    }
+
+    pub fn rejected(&mut self, record: &InputRecord, error: &'static str) {
+        self.records_read += 1;
+        let counts = self.counts(record.record_type.name());
+        counts.read += 1;
+        counts.rejected += 1;
+        *self.rejections.entry(Cow::Borrowed(error)).or_insert(0) += 1;
+    }
+
+    pub fn finish(&mut self) {
+        self.duration_secs = self.started.elapsed().as_secs_f64();
+    }
+
+    pub fn write_file(&self, filename: &str) -> Result<(), &'static str> {
+        let mut file = AtomicFile::create(filename).map_err(|_| "Failed to create report file")?;
+        self.write(&mut file)?;
+        file.commit().map_err(|_| "Failed to write report file")
+    }
+
+    pub fn outcome(&self) -> Outcome {
+        if self.rejections.is_empty() {
+            Outcome::Clean
+        } else {
+            Outcome::Rejections
+        }
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> Result<(), &'static str> {
+        serde_json::to_writer_pretty(&mut out, self).map_err(|_| "Failed to write report")?;
+        writeln!(out).map_err(|_| "Failed to write report")?;
+        out.flush().map_err(|_| "Failed to write report")
+    }
+
+    fn counts(&mut self, record_type: &str) -> &mut RecordCounts {
+        // avoids allocating the type name for every record
+        if !self.records.contains_key(record_type) {
+            self.records
+                .insert(record_type.to_string(), RecordCounts::default());
+        }
+        self.records.get_mut(record_type).unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{RecordCounts, Report};
+    use crate::{
+        cents::Money,
+        client::{client_id, ClientId},
+        input::InputRecord,
+        process::Duplicate,
+        record_type::RecordType,
+        transaction::tx_id,
+    };
+
+    fn record(record_type: &str, client: ClientId) -> InputRecord {
+        InputRecord {
+            record_type: RecordType::named(record_type),
+            client,
+            transaction: tx_id(1),
+            amount: None,
+            account: None,
+            tenant: None,
+            time: None,
+        }
+    }
+
+    #[test]
+    fn counts() {
+        let mut report = Report::new();
+
+        report.applied(&record("deposit", client_id(1)), Money::from_cents(1_0000));
+        report.applied(&record("deposit", client_id(1)), Money::from_cents(2_0000));
+        report.rejected(
+            &record("deposit", client_id(1)),
+            "Transaction id already exists",
+        );
+        report.rejected(&record("withdrawal", client_id(2)), "Client not found");
+        report.unparsed("error parsing input");
+        report.duplicate(&record("deposit", client_id(1)), Duplicate::Replay);
+        report.duplicate(&record("deposit", client_id(1)), Duplicate::Skipped);
+        report.duplicate(&record("deposit", client_id(1)), Duplicate::Skipped);
+
+        assert_eq!(report.records_read, 8);
+        assert_eq!(report.records_unparsed, 1);
+        assert_eq!(
+            report.records.get("deposit"),
+            Some(&RecordCounts {
+                read: 6,
+                applied: 2,
+                rejected: 1,
+                replayed: 1,
+                skipped: 2,
+            }),
+        );
+        assert_eq!(
+            report.records.get("withdrawal"),
+            Some(&RecordCounts {
+                read: 1,
+                rejected: 1,
+                ..RecordCounts::default()
+            }),
+        );
+        assert_eq!(
+            report.rejections.get("Transaction id already exists"),
+            Some(&1)
+        );
+        assert_eq!(report.rejections.get("error parsing input"), Some(&1));
+        assert_eq!(report.rejections.get("Account is locked"), None);
+    }
+
+    #[test]
+    fn totals_and_locked() {
+        let mut report = Report::new();
+
+        report.applied(&record("deposit", client_id(1)), Money::from_cents(1_5000));
+        report.applied(&record("withdrawal", client_id(1)), Money::from_cents(5000));
+        report.applied(&record("dispute", client_id(1)), Money::from_cents(1_0000));
+        report.applied(
+            &record("chargeback", client_id(1)),
+            Money::from_cents(1_0000),
+        );
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["totals"]["deposited"], "1.5000");
+        assert_eq!(json["totals"]["withdrawn"], "0.5000");
+        assert_eq!(json["totals"]["disputed"], "1.0000");
+        assert_eq!(json["totals"]["charged_back"], "1.0000");
+        assert_eq!(json["newly_locked"], serde_json::json!([client_id(1)]));
+    }
+
+    #[test]
+    fn total_overflow() {
+        let mut report = Report::new();
+
+        report.applied(&record("deposit", client_id(1)), Money::MAX);
+        report.applied(&record("withdrawal", client_id(1)), Money::MAX);
+        report.applied(&record("deposit", client_id(1)), Money::from_cents(1));
+
+        let json = serde_json::to_value(&report).unwrap();
+        assert_eq!(json["totals"]["deposited"], serde_json::Value::Null);
+        assert_eq!(json["totals"]["withdrawn"], Money::MAX.to_string());
+    }
+}
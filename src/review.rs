@@ -0,0 +1,124 @@
+use crate::{fraud::FraudRule, input::InputRecord, output::CsvRows, sources::Location};
+
+/*
+Where records that broke a fraud rule, were tagged by a script or failed its
+after_apply hook go, one row per rule or tag, for someone to review. Like
+rejections every hit is also logged to stderr, and the file only appears once
+`finish` is called, except with checkpoints.
+*/
+pub struct ReviewQueue {
+    rows: CsvRows,
+}
+
+const HEADER: [&str; 9] = [
+    "source", "line", "rule", "action", "tenant", "type", "client", "tx", "amount",
+];
+const WRITE_ERROR: &str = "Failed to write review queue";
+
+impl Default for ReviewQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ReviewQueue {
+    pub fn new() -> Self {
+        Self {
+            rows: CsvRows::none(WRITE_ERROR),
+        }
+    }
+
+    pub fn open(filename: Option<&str>, sources: bool) -> Result<Self, &'static str> {
+        let rows = CsvRows::open(filename, &HEADER, sources, WRITE_ERROR)?;
+        Ok(Self { rows })
+    }
+
+    /*
+    The review queue of a run with checkpoints, see `CsvRows::open_checkpointed`.
+    */
+    pub fn open_checkpointed(
+        filename: Option<&str>,
+        resume_at: Option<u64>,
+    ) -> Result<Self, &'static str> {
+        let rows = CsvRows::open_checkpointed(filename, &HEADER, resume_at, WRITE_ERROR)?;
+        Ok(Self { rows })
+    }
+
+    pub fn flag(
+        &mut self,
+        location: &Location,
+        record: &InputRecord,
+        rule: &FraudRule,
+    ) -> Result<(), &'static str> {
+        eprintln!(
+            "{}: fraud rule {} ({})",
+            location,
+            rule.name,
+            rule.action.name()
+        );
+        self.write(location, record, &rule.name, rule.action.name())
+    }
+
+    /*
+    A record that a script gave a tag, written with the tag as its rule.
+    */
+    pub fn tag(
+        &mut self,
+        location: &Location,
+        record: &InputRecord,
+        tag: &str,
+    ) -> Result<(), &'static str> {
+        eprintln!("{}: tagged {} by the script", location, tag);
+        self.write(location, record, tag, "tag")
+    }
+
+    /*
+    A record that was applied although a hook of the script failed for it,
+    written with the hook as its rule.
+    */
+    pub fn hook_failed(
+        &mut self,
+        location: &Location,
+        record: &InputRecord,
+        hook: &str,
+    ) -> Result<(), &'static str> {
+        eprintln!("{}: applied although {} failed", location, hook);
+        self.write(location, record, hook, "failed")
+    }
+
+    fn write(
+        &mut self,
+        location: &Location,
+        record: &InputRecord,
+        rule: &str,
+        action: &str,
+    ) -> Result<(), &'static str> {
+        if !self.rows.is_open() {
+            return Ok(());
+        }
+
+        let row = [
+            location.source.as_deref().unwrap_or_default().to_string(),
+            location.line.to_string(),
+            rule.to_string(),
+            action.to_string(),
+            record.tenant.clone().unwrap_or_default(),
+            record.record_type.to_string(),
+            record.client.to_string(),
+            record.transaction.to_string(),
+            record.amount.as_deref().unwrap_or_default().to_string(),
+        ];
+        self.rows.write(&row)
+    }
+
+    /*
+    The length of the review queue so far, None without one.
+    */
+    pub fn checkpoint(&mut self) -> Result<Option<u64>, &'static str> {
+        self.rows.checkpoint()
+    }
+
+    pub fn finish(self) -> Result<(), &'static str> {
+        self.rows.finish()
+    }
+}
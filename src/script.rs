@@ -0,0 +1,519 @@
+use rhai::{Array, Dynamic, Engine, EvalAltResult, Map, Scope, AST, INT};
+use std::{collections::HashSet, fmt, fs, sync::Mutex};
+
+use crate::{
+    cents::{Cents, Money},
+    client::{AccountData, ClientData, DEFAULT_ACCOUNT},
+    input::{InputRecord, RawInputRecord},
+    state::DEFAULT_TENANT,
+    transaction::{DepositData, DepositState},
+};
+
+pub const FAILED: &str = "A script hook failed";
+pub const VETOED: &str = "Vetoed by script";
+
+// distinct veto reasons kept for a run, later ones become VETOED
+const MAX_REASONS: usize = 1000;
+
+// limits of a single hook call, so a broken script can't hang the run
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 32;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_STRING_SIZE: usize = 64 * 1024;
+const MAX_ARRAY_SIZE: usize = 10_000;
+const MAX_MAP_SIZE: usize = 10_000;
+
+pub type ScriptError = Box<EvalAltResult>;
+
+/*
+A Rhai script with custom rules, see the README.
+The hooks get copies of the record, the client and the deposit the record
+refers to, so they can't change the engine state. A script has no access to
+files or the network, and its `print` and `debug` go to stderr.
+*/
+pub struct Script {
+    filename: String,
+    engine: Engine,
+    ast: AST,
+    // the number of arguments of each hook, None if the script doesn't have it
+    before_apply: Option<usize>,
+    after_apply: Option<usize>,
+    // veto reasons live for the rest of the run, like the errors they become,
+    // so there are at most MAX_REASONS of them
+    reasons: Mutex<HashSet<&'static str>>,
+}
+
+/*
+What a hook asks for besides applying the record.
+*/
+#[derive(Debug, Default)]
+pub struct HookResult {
+    // the record is rejected with this reason
+    pub veto: Option<&'static str>,
+    pub tags: Vec<String>,
+    // side records, applied right after the record
+    pub emit: Vec<InputRecord>,
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Script")
+            .field("filename", &self.filename)
+            .finish()
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.filename == other.filename
+    }
+}
+
+impl Script {
+    pub fn load(filename: &str) -> Result<Self, &'static str> {
+        let source = fs::read_to_string(filename).map_err(|_| "Failed to open script file")?;
+        Self::compile(filename, &source)
+    }
+
+    fn compile(filename: &str, source: &str) -> Result<Self, &'static str> {
+        let engine = engine();
+        let ast = engine.compile(source).map_err(|e| {
+            eprintln!("{}: {}", filename, e);
+            "Failed to parse script file"
+        })?;
+
+        let arity = |name: &str| -> Result<Option<usize>, &'static str> {
+            match ast.iter_functions().find(|f| f.name == name) {
+                Some(f) if f.params.len() == 2 || f.params.len() == 3 => Ok(Some(f.params.len())),
+                Some(_) => Err("Script hooks take a record, a client and optionally a deposit"),
+                None => Ok(None),
+            }
+        };
+        let before_apply = arity("before_apply")?;
+        let after_apply = arity("after_apply")?;
+        if before_apply.is_none() && after_apply.is_none() {
+            return Err("The script has no before_apply or after_apply hook");
+        }
+
+        // the top level only runs once, the hooks can't see its variables
+        engine.run_ast(&ast).map_err(|e| {
+            eprintln!("{}: {}", filename, e);
+            "Failed to run script file"
+        })?;
+
+        Ok(Self {
+            filename: filename.to_string(),
+            engine,
+            ast,
+            before_apply,
+            after_apply,
+            reasons: Mutex::new(HashSet::new()),
+        })
+    }
+
+    /*
+    Runs the before_apply hook, which may veto the record. `client` is None
+    for a client that doesn't exist yet and `deposit` is the deposit a
+    dispute, resolve or chargeback refers to.
+    */
+    pub fn before_apply(
+        &self,
+        record: &InputRecord,
+        client: Option<&ClientData>,
+        deposit: Option<&DepositData>,
+    ) -> Result<HookResult, ScriptError> {
+        match self.before_apply {
+            Some(arity) => self.call("before_apply", arity, record, client, deposit),
+            None => Ok(HookResult::default()),
+        }
+    }
+
+    /*
+    Runs the after_apply hook with the client after the record was applied.
+    */
+    pub fn after_apply(
+        &self,
+        record: &InputRecord,
+        client: Option<&ClientData>,
+        deposit: Option<&DepositData>,
+    ) -> Result<HookResult, ScriptError> {
+        let result = match self.after_apply {
+            Some(arity) => self.call("after_apply", arity, record, client, deposit)?,
+            None => return Ok(HookResult::default()),
+        };
+        if result.veto.is_some() {
+            return Err("after_apply can't veto a record that was already applied".into());
+        }
+        Ok(result)
+    }
+
+    fn call(
+        &self,
+        hook: &str,
+        arity: usize,
+        record: &InputRecord,
+        client: Option<&ClientData>,
+        deposit: Option<&DepositData>,
+    ) -> Result<HookResult, ScriptError> {
+        let mut args = vec![record_map(record)?, client_map(client)?];
+        if arity == 3 {
+            args.push(deposit_map(deposit)?);
+        }
+
+        let options = rhai::CallFnOptions::new().eval_ast(false);
+        let result: Dynamic =
+            self.engine
+                .call_fn_with_options(options, &mut Scope::new(), &self.ast, hook, args)?;
+        self.hook_result(record, result)
+    }
+
+    /*
+    A hook returns nothing, a veto reason, or a map with any of `veto`, `tags`
+    and `emit`.
+    */
+    fn hook_result(
+        &self,
+        record: &InputRecord,
+        result: Dynamic,
+    ) -> Result<HookResult, ScriptError> {
+        if result.is_unit() {
+            return Ok(HookResult::default());
+        }
+        if result.is_string() {
+            let reason = result.into_immutable_string()?;
+            return Ok(HookResult {
+                veto: Some(self.reason(&reason)),
+                ..HookResult::default()
+            });
+        }
+
+        let map = result
+            .try_cast::<Map>()
+            .ok_or("A hook returns nothing, a veto reason or a map")?;
+        let mut hook_result = HookResult::default();
+        for (key, value) in map {
+            match key.as_str() {
+                "veto" if value.is_unit() => {}
+                "veto" => hook_result.veto = Some(self.reason(&value.into_immutable_string()?)),
+                "tags" => {
+                    for tag in array(value, "tags")? {
+                        hook_result.tags.push(tag.into_string()?);
+                    }
+                }
+                "emit" => {
+                    for side in array(value, "emit")? {
+                        hook_result.emit.push(side_record(record, side)?);
+                    }
+                }
+                _ => return Err(format!("Unknown key {} in the result of a hook", key).into()),
+            }
+        }
+        Ok(hook_result)
+    }
+
+    fn reason(&self, reason: &str) -> &'static str {
+        let mut reasons = self.reasons.lock().unwrap_or_else(|e| e.into_inner());
+        match reasons.get(reason) {
+            Some(&r) => r,
+            None if reasons.len() >= MAX_REASONS => {
+                eprintln!("{}: vetoed: {}", self.filename, reason);
+                VETOED
+            }
+            None => {
+                let r: &'static str = Box::leak(reason.to_string().into_boxed_str());
+                reasons.insert(r);
+                r
+            }
+        }
+    }
+}
+
+/*
+A sandboxed engine with Money as a type of its own, so amounts are compared and
+added exactly.
+*/
+fn engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_EXPR_DEPTH)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_ARRAY_SIZE)
+        .set_max_map_size(MAX_MAP_SIZE)
+        .disable_symbol("eval")
+        // stdout is the accounts output
+        .on_print(|s| eprintln!("{}", s))
+        .on_debug(|s, _, pos| eprintln!("{:?}: {}", pos, s));
+
+    engine
+        .register_type_with_name::<Money>("Money")
+        .register_fn("money", |s: &str| -> Result<Money, ScriptError> {
+            s.parse().map_err(|e: &str| e.into())
+        })
+        .register_fn("to_string", |m: &mut Money| m.to_string())
+        .register_fn("to_debug", |m: &mut Money| m.to_string())
+        .register_fn("==", |a: Money, b: Money| a == b)
+        .register_fn("!=", |a: Money, b: Money| a != b)
+        .register_fn("<", |a: Money, b: Money| a < b)
+        .register_fn("<=", |a: Money, b: Money| a <= b)
+        .register_fn(">", |a: Money, b: Money| a > b)
+        .register_fn(">=", |a: Money, b: Money| a >= b)
+        .register_fn("+", |a: Money, b: Money| -> Result<Money, ScriptError> {
+            a.checked_add(b).map_err(|e| e.into())
+        })
+        .register_fn("-", |a: Money, b: Money| -> Result<Money, ScriptError> {
+            a.checked_sub(b).map_err(|e| e.into())
+        })
+        .register_fn("*", |a: Money, b: INT| -> Result<Money, ScriptError> {
+            a.as_cents()
+                .checked_mul(Cents::from(b))
+                .map(Money::from_cents)
+                .ok_or_else(|| "Amount overflow".into())
+        });
+
+    engine
+}
+
+/*
+The record, with its tenant and account filled in and the amount as Money, or
+() if it has none or it isn't a valid amount.
+*/
+fn record_map(record: &InputRecord) -> Result<Dynamic, ScriptError> {
+    let amount = record
+        .amount
+        .as_deref()
+        .and_then(|a| a.parse::<Money>().ok());
+    let mut map = Map::new();
+    map.insert("type".into(), record.record_type.name().into());
+    map.insert("client".into(), rhai::serde::to_dynamic(record.client)?);
+    map.insert("tx".into(), rhai::serde::to_dynamic(record.transaction)?);
+    map.insert("amount".into(), amount.map_or(Dynamic::UNIT, Dynamic::from));
+    let account = record.account.as_deref().unwrap_or(DEFAULT_ACCOUNT);
+    map.insert("account".into(), account.into());
+    let tenant = record.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+    map.insert("tenant".into(), tenant.into());
+    map.insert("time".into(), rhai::serde::to_dynamic(record.time)?);
+    Ok(map.into())
+}
+
+fn client_map(client: Option<&ClientData>) -> Result<Dynamic, ScriptError> {
+    let client = match client {
+        Some(c) => c,
+        None => return Ok(Dynamic::UNIT),
+    };
+
+    let accounts = client
+        .accounts()
+        .map(|(name, account)| Ok((name.into(), account_map(account)?)))
+        .collect::<Result<Map, ScriptError>>()?;
+    let mut map = Map::new();
+    map.insert("available".into(), Dynamic::from(client.get_available()?));
+    map.insert("held".into(), Dynamic::from(client.get_held()?));
+    map.insert("total".into(), Dynamic::from(client.get_total()?));
+    map.insert("locked".into(), client.is_locked().into());
+    map.insert("accounts".into(), accounts.into());
+    Ok(map.into())
+}
+
+fn account_map(account: &AccountData) -> Result<Dynamic, ScriptError> {
+    let mut map = Map::new();
+    map.insert("available".into(), Dynamic::from(account.get_available()));
+    map.insert("held".into(), Dynamic::from(account.get_held()));
+    map.insert("total".into(), Dynamic::from(account.get_total()?));
+    map.insert("locked".into(), account.is_locked().into());
+    Ok(map.into())
+}
+
+fn deposit_map(deposit: Option<&DepositData>) -> Result<Dynamic, ScriptError> {
+    let deposit = match deposit {
+        Some(d) => d,
+        None => return Ok(Dynamic::UNIT),
+    };
+
+    let state = match deposit.state {
+        DepositState::Ok => "ok",
+        DepositState::Dispute => "dispute",
+        DepositState::Resolved => "resolved",
+        DepositState::Chargeback => "chargeback",
+    };
+    let mut map = Map::new();
+    map.insert(
+        "client".into(),
+        rhai::serde::to_dynamic(deposit.get_client())?,
+    );
+    map.insert("account".into(), deposit.get_account().into());
+    map.insert("amount".into(), Dynamic::from(deposit.get_amount()));
+    map.insert("state".into(), state.into());
+    Ok(map.into())
+}
+
+fn array(value: Dynamic, key: &str) -> Result<Array, ScriptError> {
+    value
+        .try_cast::<Array>()
+        .ok_or_else(|| format!("{} has to be an array", key).into())
+}
+
+/*
+A record emitted by a hook. It has the fields of an input record, and is for
+the tenant of the record that emitted it unless it has a tenant of its own.
+*/
+fn side_record(record: &InputRecord, side: Dynamic) -> Result<InputRecord, ScriptError> {
+    let mut map = side
+        .try_cast::<Map>()
+        .ok_or("Emitted records have to be maps")?;
+    if let Some(amount) = map.get_mut("amount") {
+        if let Some(money) = amount.clone().try_cast::<Money>() {
+            *amount = money.to_string().into();
+        }
+    }
+
+    let side: RawInputRecord = rhai::serde::from_dynamic(&map.into())?;
+    let mut side = InputRecord::try_from(side)?;
+    if side.tenant.is_none() {
+        side.tenant = record.tenant.clone();
+    }
+    Ok(side)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Script, MAX_REASONS, VETOED};
+    use crate::{
+        amount::Amount,
+        cents::Money,
+        client::{client_id, ClientData},
+        input::InputRecord,
+        record_type::RecordType,
+        transaction::tx_id,
+    };
+
+    fn record(record_type: &str, amount: Option<&str>) -> InputRecord {
+        InputRecord {
+            record_type: RecordType::named(record_type),
+            client: client_id(1),
+            transaction: tx_id(1),
+            amount: amount.map(Amount::from),
+            account: None,
+            tenant: None,
+            time: None,
+        }
+    }
+
+    #[test]
+    fn hooks() {
+        let script = Script::compile(
+            "test",
+            r#"
+            fn before_apply(record, client) {
+                if record.type == "withdrawal" && record.amount > money("100") {
+                    return "Withdrawal over 100";
+                }
+                if client != () && client.locked {
+                    return #{ tags: ["locked"] };
+                }
+            }
+
+            fn after_apply(record, client, deposit) {
+                if record.type == "deposit" && client.available * 2 > money("10") {
+                    #{ tags: ["large"], emit: [#{ type: "withdrawal", client: record.client, tx: 99, amount: money("1") }] }
+                }
+            }
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            script
+                .before_apply(&record("withdrawal", Some("100.01")), None, None)
+                .unwrap()
+                .veto,
+            Some("Withdrawal over 100"),
+        );
+        let result = script
+            .before_apply(&record("withdrawal", Some("100")), None, None)
+            .unwrap();
+        assert!(result.veto.is_none() && result.tags.is_empty() && result.emit.is_empty());
+
+        let mut client = ClientData::new();
+        client.deposit("main", Money::from_cents(6_0000)).unwrap();
+        let result = script
+            .after_apply(&record("deposit", Some("6")), Some(&client), None)
+            .unwrap();
+        assert_eq!(result.tags, vec!["large".to_string()]);
+        assert_eq!(result.emit.len(), 1);
+        assert_eq!(result.emit[0].transaction, tx_id(99));
+        assert_eq!(result.emit[0].amount.as_deref(), Some("1.0000"));
+
+        client.lock();
+        let result = script
+            .before_apply(&record("deposit", Some("1")), Some(&client), None)
+            .unwrap();
+        assert_eq!(result.tags, vec!["locked".to_string()]);
+    }
+
+    #[test]
+    fn veto_reasons() {
+        let script = Script::compile(
+            "test",
+            "fn before_apply(record, client) { `Withdrawal of ${record.amount}` }",
+        )
+        .unwrap();
+        let veto = |amount: usize| {
+            script
+                .before_apply(&record("withdrawal", Some(&amount.to_string())), None, None)
+                .unwrap()
+                .veto
+        };
+
+        for amount in 0..MAX_REASONS {
+            assert_eq!(
+                veto(amount),
+                Some(&*format!("Withdrawal of {}.0000", amount))
+            );
+        }
+        // a known reason is still given, a new one isn't kept
+        assert_eq!(veto(0), Some("Withdrawal of 0.0000"));
+        assert_eq!(veto(MAX_REASONS), Some(VETOED));
+    }
+
+    #[test]
+    fn sandbox() {
+        // an endless loop runs out of operations instead of hanging the run
+        let script =
+            Script::compile("test", "fn before_apply(record, client) { loop {} }").unwrap();
+        assert!(script
+            .before_apply(&record("deposit", Some("1")), None, None)
+            .is_err());
+
+        // a hook can't change the record it was given
+        let script = Script::compile(
+            "test",
+            "fn before_apply(record, client) { record.amount = money(\"0\"); }",
+        )
+        .unwrap();
+        let deposit = record("deposit", Some("1"));
+        assert!(script.before_apply(&deposit, None, None).is_ok());
+        assert_eq!(deposit.amount.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(
+            Script::compile("test", "fn before_apply(record) {}"),
+            Err("Script hooks take a record, a client and optionally a deposit"),
+        );
+        assert_eq!(
+            Script::compile("test", "fn other(record, client) {}"),
+            Err("The script has no before_apply or after_apply hook"),
+        );
+        assert_eq!(
+            Script::compile("test", "fn before_apply(record, client) {"),
+            Err("Failed to parse script file"),
+        );
+        assert_eq!(
+            Script::compile("test", "eval(\"1\"); fn before_apply(record, client) {}"),
+            Err("Failed to parse script file"),
+        );
+    }
+}
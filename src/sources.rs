@@ -0,0 +1,334 @@
+use std::{cmp::Ordering, fmt, sync::Arc};
+
+use crate::input::{self, InputFormat, InputRecord, KeyedRecord, KeyedRecords};
+
+/*
+Where a record was read: the input file, if the run has more than one, and
+the line in it.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Location {
+    pub source: Option<Arc<str>>,
+    pub line: u32,
+}
+
+impl Location {
+    pub fn line(line: u32) -> Self {
+        Self { source: None, line }
+    }
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.source {
+            Some(source) => write!(f, "{} line {}", source, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+/*
+Records paired with where they were read, for error reporting.
+*/
+pub type LocatedRecords = Box<dyn Iterator<Item = (Location, Result<InputRecord, &'static str>)>>;
+
+/*
+The input files of a run. They are read one after the other, or with
+`merge_by` merged into a single stream in order of that column.
+*/
+#[derive(Clone, Debug, PartialEq)]
+pub struct Inputs {
+    filenames: Vec<String>,
+    format: InputFormat,
+    merge_by: Option<String>,
+}
+
+impl Inputs {
+    /*
+    Glob patterns such as `acquirer_*.csv` are expanded into the matching files,
+    in alphabetical order. Other filenames, including - for stdin, are kept as
+    they are.
+    */
+    pub fn new(
+        patterns: &[String],
+        format: InputFormat,
+        merge_by: Option<String>,
+    ) -> Result<Self, &'static str> {
+        let mut filenames = Vec::new();
+        for pattern in patterns {
+            if !pattern.contains(['*', '?', '[']) {
+                filenames.push(pattern.clone());
+                continue;
+            }
+
+            let mut matches = glob::glob(pattern)
+                .map_err(|_| "Invalid input file pattern")?
+                .map(|path| match path {
+                    Ok(p) => p
+                        .to_str()
+                        .map(str::to_string)
+                        .ok_or("Invalid input filename"),
+                    Err(_) => Err("Failed to read input file pattern"),
+                })
+                .collect::<Result<Vec<_>, _>>()?;
+            if matches.is_empty() {
+                return Err("No input file matches the pattern");
+            }
+            matches.sort();
+            filenames.extend(matches);
+        }
+
+        Ok(Self {
+            filenames,
+            format,
+            merge_by,
+        })
+    }
+
+    pub fn single(filename: &str, format: InputFormat) -> Self {
+        Self {
+            filenames: vec![filename.to_string()],
+            format,
+            merge_by: None,
+        }
+    }
+
+    /*
+    The input file, if there is exactly one and it isn't stdin.
+    */
+    pub fn single_file(&self) -> Option<&str> {
+        match self.filenames.as_slice() {
+            [filename] if filename != "-" => Some(filename),
+            _ => None,
+        }
+    }
+
+    /*
+    Whether records come from more than one file, so their location needs the
+    source file.
+    */
+    pub fn has_sources(&self) -> bool {
+        self.filenames.len() > 1
+    }
+
+    /*
+    Opens every input file, so a missing one is an error before anything was
+    applied.
+    */
+    pub fn read(&self) -> Result<LocatedRecords, &'static str> {
+        let mut sources = Vec::with_capacity(self.filenames.len());
+        for filename in &self.filenames {
+            let records = match &self.merge_by {
+                Some(column) => input::read_keyed_records(filename, self.format, column)?,
+                None => Box::new(
+                    input::read_records(filename, self.format)?
+                        .map(|(line, record)| (line, record, None)),
+                ),
+            };
+            let source = self.has_sources().then(|| Arc::from(source_name(filename)));
+            sources.push((source, records));
+        }
+
+        if self.merge_by.is_some() {
+            Ok(Box::new(Merged::new(sources)))
+        } else {
+            Ok(Box::new(sources.into_iter().flat_map(
+                |(source, records)| {
+                    records.map(move |(line, record, _)| {
+                        let location = Location {
+                            source: source.clone(),
+                            line,
+                        };
+                        (location, record)
+                    })
+                },
+            )))
+        }
+    }
+}
+
+fn source_name(filename: &str) -> &str {
+    if filename == "-" {
+        "stdin"
+    } else {
+        filename
+    }
+}
+
+/*
+A k-way merge of inputs that are each in order of the merge column.
+The next record is always the one with the smallest key among the next record
+of every input, and from the earliest input on a tie. Records without a key,
+e.g. because they could not be parsed, come right away.
+*/
+struct Merged {
+    sources: Vec<(Option<Arc<str>>, KeyedRecords)>,
+    heads: Vec<Option<KeyedRecord>>,
+}
+
+impl Merged {
+    fn new(mut sources: Vec<(Option<Arc<str>>, KeyedRecords)>) -> Self {
+        let heads = sources.iter_mut().map(|(_, r)| r.next()).collect();
+        Self { sources, heads }
+    }
+}
+
+impl Iterator for Merged {
+    type Item = (Location, Result<InputRecord, &'static str>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut next: Option<usize> = None;
+        for (i, head) in self.heads.iter().enumerate() {
+            let Some((_, _, key)) = head else { continue };
+            let smaller = match next.and_then(|n| self.heads[n].as_ref()) {
+                Some((_, _, next_key)) => compare_keys(key, next_key) == Ordering::Less,
+                None => true,
+            };
+            if smaller {
+                next = Some(i);
+            }
+        }
+
+        let i = next?;
+        let (line, record, _) = self.heads[i].take()?;
+        let (source, records) = &mut self.sources[i];
+        self.heads[i] = records.next();
+
+        let location = Location {
+            source: source.clone(),
+            line,
+        };
+        Some((location, record))
+    }
+}
+
+/*
+Keys that are both whole numbers, like sequence numbers, compare as numbers.
+Everything else, like ISO 8601 timestamps, compares as text.
+*/
+fn compare_keys(a: &Option<String>, b: &Option<String>) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => match (a.parse::<u64>(), b.parse::<u64>()) {
+            (Ok(a), Ok(b)) => a.cmp(&b),
+            _ => a.cmp(b),
+        },
+        _ => a.is_some().cmp(&b.is_some()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use super::{compare_keys, Inputs, Location};
+    use crate::input::InputFormat;
+
+    fn locations(inputs: &Inputs) -> Vec<String> {
+        inputs
+            .read()
+            .unwrap()
+            .map(|(location, _)| location.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn keys() {
+        let key = |k: &str| Some(k.to_string());
+
+        assert_eq!(compare_keys(&key("9"), &key("10")), Ordering::Less);
+        assert_eq!(
+            compare_keys(&key("2024-01-02T10:00:00Z"), &key("2024-01-02T09:00:00Z")),
+            Ordering::Greater
+        );
+        assert_eq!(compare_keys(&None, &key("1")), Ordering::Less);
+        assert_eq!(compare_keys(&key("1"), &key("1")), Ordering::Equal);
+    }
+
+    #[test]
+    fn concat() {
+        let inputs = Inputs::new(
+            &[
+                "test_data/merge_b.csv".to_string(),
+                "test_data/merge_a.csv".to_string(),
+            ],
+            InputFormat::Csv,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            locations(&inputs),
+            vec![
+                "test_data/merge_b.csv line 2",
+                "test_data/merge_b.csv line 3",
+                "test_data/merge_a.csv line 2",
+                "test_data/merge_a.csv line 3",
+                "test_data/merge_a.csv line 4",
+            ],
+        );
+    }
+
+    #[test]
+    fn merge_by() {
+        // the glob is in alphabetical order, the merge in order of seq
+        let inputs = Inputs::new(
+            &["test_data/merge_?.csv".to_string()],
+            InputFormat::Csv,
+            Some("seq".to_string()),
+        )
+        .unwrap();
+
+        assert_eq!(
+            locations(&inputs),
+            vec![
+                "test_data/merge_a.csv line 2",
+                "test_data/merge_b.csv line 2",
+                "test_data/merge_a.csv line 3",
+                "test_data/merge_b.csv line 3",
+                "test_data/merge_a.csv line 4",
+            ],
+        );
+
+        let inputs = Inputs::new(
+            &["test_data/merge_a.csv".to_string()],
+            InputFormat::Csv,
+            Some("timestamp".to_string()),
+        )
+        .unwrap();
+        assert!(inputs.read().is_err());
+    }
+
+    #[test]
+    fn errors() {
+        assert!(Inputs::new(
+            &["test_data/no_such_*.csv".to_string()],
+            InputFormat::Csv,
+            None
+        )
+        .is_err());
+
+        let inputs = Inputs::new(
+            &[
+                "test_data/merge_a.csv".to_string(),
+                "test_data/missing.csv".to_string(),
+            ],
+            InputFormat::Csv,
+            None,
+        )
+        .unwrap();
+        assert!(inputs.read().is_err());
+    }
+
+    #[test]
+    fn location() {
+        assert_eq!(Location::line(3).to_string(), "line 3");
+        assert_eq!(
+            Location {
+                source: Some("a.csv".into()),
+                line: 3
+            }
+            .to_string(),
+            "a.csv line 3"
+        );
+    }
+}
@@ -0,0 +1,442 @@
+use csv::{ReaderBuilder, Trim};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{BufReader, Write},
+};
+
+use crate::{
+    cents::{Money, Precision, DEFAULT_SCALE},
+    client::{ClientId, ClientsMap, DEFAULT_ACCOUNT, ROLL_UP},
+    fraud::FraudHistory,
+    output::AtomicFile,
+    pending::Pending,
+    transaction::TransactionsMap,
+};
+
+/*
+The tenant of records without one.
+*/
+pub const DEFAULT_TENANT: &str = "";
+
+/*
+Everything the engine knows after processing some input.
+Can be saved to and loaded from a JSON state file to continue processing later.
+The amounts in a state file are only meaningful at the scale they were saved at.
+*/
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(from = "StateFile")]
+pub struct State {
+    scale: u32,
+    pub tenants: BTreeMap<String, Ledger>,
+    // only during a run, everything pending is applied at the end of the input
+    #[serde(skip)]
+    pub pending: Pending,
+    // the fraud rules only look at records of the same run, a checkpoint has it
+    #[serde(skip)]
+    pub fraud: FraudHistory,
+}
+
+/*
+The clients and transactions of one tenant.
+Client and transaction ids only have to be unique within a tenant.
+*/
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct Ledger {
+    pub clients: ClientsMap,
+    pub transactions: TransactionsMap,
+}
+
+/*
+A state file as written now, or from before tenants existed, when the clients
+and transactions were at the top level. Those belong to the DEFAULT_TENANT.
+*/
+#[derive(Deserialize)]
+struct StateFile {
+    // state files from before --scale existed are at the default scale
+    #[serde(default = "default_scale")]
+    scale: u32,
+    #[serde(default)]
+    tenants: BTreeMap<String, Ledger>,
+    #[serde(default)]
+    clients: ClientsMap,
+    #[serde(default)]
+    transactions: TransactionsMap,
+}
+
+/*
+A row of an accounts file as written by `output::write_tenant_accounts`.
+*/
+#[derive(Deserialize)]
+struct AccountsFileRow {
+    #[serde(default)]
+    tenant: Option<String>,
+    client: ClientId,
+    #[serde(default)]
+    account: Option<String>,
+    available: Money,
+    held: Money,
+    total: Money,
+    locked: bool,
+}
+
+fn default_scale() -> u32 {
+    DEFAULT_SCALE
+}
+
+impl From<StateFile> for State {
+    fn from(file: StateFile) -> Self {
+        let mut tenants = file.tenants;
+        if !file.clients.is_empty() || !file.transactions.is_empty() {
+            tenants.insert(
+                DEFAULT_TENANT.to_string(),
+                Ledger {
+                    clients: file.clients,
+                    transactions: file.transactions,
+                },
+            );
+        }
+
+        Self {
+            scale: file.scale,
+            tenants,
+            pending: Pending::default(),
+            fraud: FraudHistory::default(),
+        }
+    }
+}
+
+impl Default for State {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl State {
+    pub fn new() -> Self {
+        Self {
+            scale: Precision::current().scale,
+            tenants: BTreeMap::new(),
+            pending: Pending::default(),
+            fraud: FraudHistory::default(),
+        }
+    }
+
+    pub fn ledger(&self, tenant: &str) -> Option<&Ledger> {
+        self.tenants.get(tenant)
+    }
+
+    /*
+    The ledger of a tenant, which is created if the tenant is new.
+    */
+    pub fn ledger_mut(&mut self, tenant: &str) -> &mut Ledger {
+        // avoids allocating the key for every record of a known tenant
+        if !self.tenants.contains_key(tenant) {
+            self.tenants.insert(tenant.to_string(), Ledger::default());
+        }
+        self.tenants.get_mut(tenant).unwrap()
+    }
+
+    /*
+    Whether any clients belong to a tenant other than the DEFAULT_TENANT.
+    */
+    pub fn has_tenants(&self) -> bool {
+        self.tenants
+            .iter()
+            .any(|(tenant, ledger)| tenant != DEFAULT_TENANT && !ledger.clients.is_empty())
+    }
+
+    pub fn load(filename: &str) -> Result<Self, &'static str> {
+        let file = File::open(filename).map_err(|_| "Failed to open state file")?;
+        let state: Self = serde_json::from_reader(BufReader::new(file))
+            .map_err(|_| "Failed to parse state file")?;
+
+        state.check_scale()?;
+        Ok(state)
+    }
+
+    /*
+    The amounts of a loaded state are only meaningful at the scale of the run.
+    */
+    pub fn check_scale(&self) -> Result<(), &'static str> {
+        if self.scale != Precision::current().scale {
+            return Err("The state file was saved with a different --scale");
+        }
+        Ok(())
+    }
+
+    /*
+    Starts from the balances in an accounts file written by an earlier run,
+    e.g. the day before. Only the balances are known, not the transactions.
+    A `tenant` column and the `account` column of --sub-accounts are optional.
+    Without sub-accounts the balances are the client's main account, and a
+    locked client is locked as a whole. With sub-accounts the roll-up row has
+    the lock of the whole client. What is wrong with an invalid file is logged
+    to stderr.
+    */
+    pub fn load_accounts(filename: &str) -> Result<Self, &'static str> {
+        let file = File::open(filename).map_err(|_| "Failed to open accounts file")?;
+        let reader = ReaderBuilder::new().trim(Trim::All).from_reader(file);
+
+        let mut state = Self::new();
+        // line 1 is the header, data starts at line 2
+        for (result, line) in reader.into_deserialize::<AccountsFileRow>().zip(2..) {
+            let error = match result {
+                Ok(row) => match state.open_account(row) {
+                    Ok(()) => continue,
+                    Err(error) => error,
+                },
+                Err(_) => "error parsing accounts file",
+            };
+            eprintln!("line {}: {}", line, error);
+            return Err("Invalid accounts file");
+        }
+
+        Ok(state)
+    }
+
+    fn open_account(&mut self, row: AccountsFileRow) -> Result<(), &'static str> {
+        if row.available.checked_add(row.held)? != row.total {
+            return Err("The total is not the sum of available and held");
+        }
+
+        let tenant = row.tenant.as_deref().unwrap_or(DEFAULT_TENANT);
+        let client = self
+            .ledger_mut(tenant)
+            .clients
+            .entry(row.client)
+            .or_default();
+
+        let account = match row.account.as_deref() {
+            // the client roll-up of --sub-accounts only has the client's lock
+            Some(ROLL_UP) => {
+                if row.locked {
+                    client.lock();
+                }
+                return Ok(());
+            }
+            Some("") | None => DEFAULT_ACCOUNT,
+            Some(a) => a,
+        };
+        // a client row is locked if the client is
+        let sub_account = row.account.is_some();
+        client.open_account(account, row.available, row.held, row.locked && sub_account)?;
+        if row.locked && !sub_account {
+            client.lock();
+        }
+        Ok(())
+    }
+
+    pub fn save(&self, filename: &str) -> Result<(), &'static str> {
+        let mut file = AtomicFile::create(filename).map_err(|_| "Failed to create state file")?;
+        self.write(&mut file)?;
+        file.commit().map_err(|_| "Failed to write state file")
+    }
+
+    pub fn write<W: Write>(&self, mut out: W) -> Result<(), &'static str> {
+        serde_json::to_writer(&mut out, self).map_err(|_| "Failed to write state")?;
+        out.flush().map_err(|_| "Failed to write state")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use super::{State, DEFAULT_TENANT};
+    use crate::{
+        cents::Money,
+        client::client_id,
+        input::InputRecord,
+        output::{self, OutputFormat},
+        process::process_record,
+        record_type::RecordType,
+        transaction::tx_id,
+    };
+
+    #[test]
+    fn round_trip() {
+        let mut state = State::new();
+        let ledger = state.ledger_mut(DEFAULT_TENANT);
+
+        let deposit = InputRecord {
+            record_type: RecordType::Deposit,
+            client: client_id(1),
+            transaction: tx_id(1),
+            amount: Some("1".into()),
+            account: None,
+            tenant: None,
+            time: None,
+        };
+
+        assert!(process_record(&deposit, &mut ledger.clients, &mut ledger.transactions).is_ok());
+
+        let dispute = InputRecord {
+            record_type: RecordType::Dispute,
+            client: client_id(1),
+            transaction: tx_id(1),
+            amount: None,
+            account: None,
+            tenant: None,
+            time: None,
+        };
+
+        assert!(process_record(&dispute, &mut ledger.clients, &mut ledger.transactions).is_ok());
+
+        let mut out = Vec::new();
+        assert!(state.write(&mut out).is_ok());
+        let mut state: State = serde_json::from_slice(&out).unwrap();
+
+        let ledger = state.ledger_mut(DEFAULT_TENANT);
+        let client = ledger.clients.get(&client_id(1)).unwrap();
+        assert_eq!(client.get_available(), Ok(Money::from_cents(0)));
+        assert_eq!(client.get_held(), Ok(Money::from_cents(1_0000)));
+
+        // the loaded deposit is still disputed and can be resolved
+        let resolve = InputRecord {
+            record_type: RecordType::Resolve,
+            client: client_id(1),
+            transaction: tx_id(1),
+            amount: None,
+            account: None,
+            tenant: None,
+            time: None,
+        };
+
+        assert!(process_record(&resolve, &mut ledger.clients, &mut ledger.transactions).is_ok());
+
+        let client = ledger.clients.get(&client_id(1)).unwrap();
+        assert_eq!(client.get_available(), Ok(Money::from_cents(1_0000)));
+        assert_eq!(client.get_held(), Ok(Money::from_cents(0)));
+    }
+
+    #[test]
+    fn state_before_tenants() {
+        let state: State = serde_json::from_str(
+            r#"{"clients":{"1":{"available":"1","held":"0","locked":false}},"transactions":{}}"#,
+        )
+        .unwrap();
+
+        let ledger = state.ledger(DEFAULT_TENANT).unwrap();
+        assert_eq!(
+            ledger.clients.get(&client_id(1)).unwrap().get_available(),
+            Ok(Money::from_cents(1_0000))
+        );
+        assert!(!state.has_tenants());
+
+        let mut out = Vec::new();
+        assert!(state.write(&mut out).is_ok());
+        assert!(String::from_utf8(out)
+            .unwrap()
+            .contains(r#""tenants":{"":{"clients""#));
+    }
+
+    #[test]
+    fn state_before_sub_accounts() {
+        let mut state: State = serde_json::from_str(
+            r#"{"clients":{"1":{"available":"1","held":"0","locked":true}},"transactions":{}}"#,
+        )
+        .unwrap();
+
+        // the client was locked as a whole, so it can't get a new sub-account
+        let ledger = state.ledger_mut(DEFAULT_TENANT);
+        let deposit = InputRecord {
+            record_type: RecordType::Deposit,
+            client: client_id(1),
+            transaction: tx_id(3),
+            amount: Some("7".into()),
+            account: Some("escrow".to_string()),
+            tenant: None,
+            time: None,
+        };
+        assert!(process_record(&deposit, &mut ledger.clients, &mut ledger.transactions).is_err());
+        assert!(ledger
+            .clients
+            .get(&client_id(1))
+            .unwrap()
+            .get_account("escrow")
+            .is_none());
+    }
+
+    #[test]
+    fn load_accounts() {
+        let state = State::load_accounts("test_data/opening_balances.csv").unwrap();
+
+        let clients = &state.ledger(DEFAULT_TENANT).unwrap().clients;
+        let client = clients.get(&client_id(1)).unwrap();
+        assert_eq!(client.get_available(), Ok(Money::from_cents(1_5000)));
+        assert_eq!(
+            client.get_account("main").unwrap().get_held(),
+            Money::from_cents(5000)
+        );
+        assert!(!client.is_locked());
+        assert!(clients.get(&client_id(2)).unwrap().is_client_locked());
+
+        // a sub-account locked on its own, and a client locked as a whole
+        let state = State::load_accounts("test_data/opening_balances_sub_accounts.csv").unwrap();
+        let clients = &state.ledger(DEFAULT_TENANT).unwrap().clients;
+        let client = clients.get(&client_id(1)).unwrap();
+        assert!(client.get_account("savings").unwrap().is_locked());
+        assert!(!client.is_client_locked());
+        assert!(clients.get(&client_id(2)).unwrap().is_client_locked());
+
+        assert!(State::load_accounts("test_data/opening_balances_invalid.csv").is_err());
+        assert!(State::load_accounts("test_data/missing.csv").is_err());
+    }
+
+    #[test]
+    fn accounts_round_trip() {
+        let mut state = State::new();
+        for (tenant, client, transaction, account, amount) in [
+            ("", 1, 1, "main", "1"),
+            ("", 1, 2, "savings", "2"),
+            ("", 2, 4, "savings", "4"),
+            ("acme", 1, 3, "main", "3"),
+        ] {
+            let ledger = state.ledger_mut(tenant);
+            let deposit = InputRecord {
+                record_type: RecordType::Deposit,
+                client: client_id(client),
+                transaction: tx_id(transaction),
+                amount: Some(amount.into()),
+                account: Some(account.to_string()),
+                tenant: None,
+                time: None,
+            };
+            assert!(
+                process_record(&deposit, &mut ledger.clients, &mut ledger.transactions).is_ok()
+            );
+        }
+        let clients = &mut state.ledger_mut(DEFAULT_TENANT).clients;
+        clients.get_mut(&client_id(2)).unwrap().lock();
+
+        for sub_accounts in [false, true] {
+            let write = |state: &State| {
+                let mut out = Vec::new();
+                output::write_tenant_accounts(
+                    state,
+                    sub_accounts,
+                    output::account_writer(OutputFormat::Csv, &mut out).as_mut(),
+                )
+                .unwrap();
+                out
+            };
+
+            let filename = env::temp_dir().join(format!(
+                "transaction_processor_accounts_round_trip_{}.csv",
+                sub_accounts
+            ));
+            let accounts = write(&state);
+            fs::write(&filename, &accounts).unwrap();
+
+            let loaded = State::load_accounts(filename.to_str().unwrap()).unwrap();
+            assert_eq!(write(&loaded), accounts);
+            // the whole client is still locked, so it can't get new sub-accounts
+            let clients = &loaded.ledger(DEFAULT_TENANT).unwrap().clients;
+            assert!(clients.get(&client_id(2)).unwrap().is_client_locked());
+
+            fs::remove_file(filename).unwrap();
+        }
+    }
+}
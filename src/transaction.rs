@@ -1,25 +1,82 @@
+use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-use crate::{cents::Cents, client::ClientId};
+use crate::{
+    cents::Money,
+    client::{ClientId, DEFAULT_ACCOUNT},
+    record_type::RecordType,
+};
 
+/*
+Transaction ids are u32 unless a wider id feature is enabled, see the README.
+*/
+#[cfg(feature = "string-ids")]
+pub type TransactionId = crate::ids::InternedId;
+#[cfg(all(feature = "tx-id-u64", not(feature = "string-ids")))]
+pub type TransactionId = u64;
+#[cfg(not(any(feature = "tx-id-u64", feature = "string-ids")))]
 pub type TransactionId = u32;
 pub type TransactionsMap = HashMap<TransactionId, TransactionData>;
 
+/*
+A transaction id in tests, whatever the id type.
+*/
+#[cfg(test)]
+pub fn tx_id(id: u32) -> TransactionId {
+    TransactionId::from(id)
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
 pub enum TransactionData {
     Deposit(DepositData),
     Withdrawal(WithdrawalData),
 }
 
+impl TransactionData {
+    /*
+    Whether a record with this transaction's id is an exact replay of it, e.g.
+    from a retried delivery: the same type, client, sub-account and amount.
+    */
+    pub fn is_replay(
+        &self,
+        record_type: &RecordType,
+        client: ClientId,
+        account: &str,
+        amount: Money,
+    ) -> bool {
+        let (t_type, t_client, t_account, t_amount) = match self {
+            Self::Deposit(d) => (RecordType::Deposit, d.client, d.account.as_str(), d.amount),
+            Self::Withdrawal(w) => (
+                RecordType::Withdrawal,
+                w.client,
+                w.account.as_str(),
+                w.amount,
+            ),
+        };
+        t_type == *record_type && t_client == client && t_account == account && t_amount == amount
+    }
+}
+
+#[derive(Clone, Deserialize, Serialize)]
 pub struct DepositData {
     client: ClientId,
-    amount: Cents,
+    // state files from before sub-accounts only have the default account
+    #[serde(default = "default_account")]
+    account: String,
+    amount: Money,
     pub state: DepositState,
 }
 
+fn default_account() -> String {
+    DEFAULT_ACCOUNT.to_string()
+}
+
 impl DepositData {
-    pub fn new(client: ClientId, amount: Cents) -> Self {
+    pub fn new(client: ClientId, account: &str, amount: Money) -> Self {
         Self {
             client,
+            account: account.to_string(),
             amount,
             state: DepositState::Ok,
         }
@@ -29,44 +86,77 @@ impl DepositData {
         self.client
     }
 
-    pub fn get_amount(&self) -> Cents {
+    pub fn get_account(&self) -> &str {
+        &self.account
+    }
+
+    pub fn get_amount(&self) -> Money {
         self.amount
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
 pub enum DepositState {
     Ok,
     Dispute,
+    // was disputed and resolved, state files from before this are Ok
+    Resolved,
     Chargeback,
 }
 
+#[derive(Clone, Deserialize, Serialize)]
 pub struct WithdrawalData {
-    _client: ClientId,
-    _amount: Cents,
+    client: ClientId,
+    #[serde(default = "default_account")]
+    account: String,
+    amount: Money,
 }
 
 impl WithdrawalData {
-    pub fn new(client: ClientId, amount: Cents) -> Self {
+    pub fn new(client: ClientId, account: &str, amount: Money) -> Self {
         Self {
-            _client: client,
-            _amount: amount,
+            client,
+            account: account.to_string(),
+            amount,
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{DepositData, DepositState};
+    use super::{DepositData, DepositState, TransactionData, WithdrawalData};
+    use crate::{cents::Money, client::client_id, record_type::RecordType};
 
     #[test]
     fn depost_data() {
-        let client = 1;
-        let amount = 2;
-        let depost = DepositData::new(client, amount);
+        let client = client_id(1);
+        let amount = Money::from_cents(2);
+        let depost = DepositData::new(client, "main", amount);
 
         assert_eq!(depost.get_client(), client);
+        assert_eq!(depost.get_account(), "main");
         assert_eq!(depost.get_amount(), amount);
         assert_eq!(depost.state, DepositState::Ok);
     }
+
+    #[test]
+    fn replay() {
+        let amount = Money::from_cents(2);
+        let deposit = TransactionData::Deposit(DepositData::new(client_id(1), "main", amount));
+        let withdrawal =
+            TransactionData::Withdrawal(WithdrawalData::new(client_id(1), "main", amount));
+
+        assert!(deposit.is_replay(&RecordType::Deposit, client_id(1), "main", amount));
+        assert!(withdrawal.is_replay(&RecordType::Withdrawal, client_id(1), "main", amount));
+
+        assert!(!deposit.is_replay(&RecordType::Withdrawal, client_id(1), "main", amount));
+        assert!(!deposit.is_replay(&RecordType::Deposit, client_id(2), "main", amount));
+        assert!(!deposit.is_replay(&RecordType::Deposit, client_id(1), "savings", amount));
+        assert!(!deposit.is_replay(
+            &RecordType::Deposit,
+            client_id(1),
+            "main",
+            Money::from_cents(3)
+        ));
+    }
 }